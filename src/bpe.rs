@@ -1,214 +1,1091 @@
-// BPE
+// BPE, little-endian on PS2 and big-endian on Xbox 360 / PS3
 // [magic_num: u32][reserved: u32][compressed_len: u32][decompressed_len: u32]
 // block
 // [encoding_info: ..][block_len: u16][block_data: ..]
 
 extern crate threadpool;
 
-use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
-use std::fs::{create_dir_all, File};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::fmt;
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::channel;
+use std::thread;
 
-use crate::{create_file_to_write, read_exact};
+use crate::list::Listing;
+use crate::{
+    check_bounds, check_magic_num, create_dir_all, create_file_to_write, has_magic_num, open_file,
+    read_exact, read_file, write_file, Endian, Error, PackOptions, Result, UnpackOptions,
+};
 
 const MAGIC_NUM: &[u8; 4] = b"BPE ";
 const RESERVED: &[u8; 4] = b"\x00\x01\x00\x00";
 const MAX_BLOCK_SIZE: usize = 4096;
+/// Other encoders cut larger blocks, but a block can always be stored as it is, so its
+/// data never needs more than its u16 length field can hold.
+const MAX_DECODED_BLOCK_SIZE: usize = u16::MAX as usize;
 const MAX_NORMAL_BYTE_NUM: usize = 200;
+/// Below this many decompressed bytes, starting threads costs more than it saves.
+const MIN_PARALLEL_DECODE_LEN: usize = 256 * 1024;
 const MIN_OCCURRENCE: usize = 3;
+const SEARCH_BLOCK_SIZES: [usize; 3] = [MAX_BLOCK_SIZE, MAX_BLOCK_SIZE / 2, MAX_BLOCK_SIZE / 4];
+const SEARCH_BYTE_NUMS: [usize; 14] = [
+    64, 80, 96, 112, 128, 144, 160, 176, 192, 200, 208, 224, 240, 256,
+];
 
-pub fn detect_format<P: AsRef<Path>>(path: P) -> bool {
-    let mut file = File::open(path).unwrap();
-    let buf = read_exact!(&mut file, 4);
-    &buf == MAGIC_NUM
+pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<bool> {
+    has_magic_num(path, MAGIC_NUM)
 }
 
-pub fn pack(src_path: PathBuf, dst_path: PathBuf) {
-    let file = File::open(src_path).unwrap();
-    let file_len = u32::try_from(file.metadata().unwrap().len()).unwrap();
-    let mut reader = BufReader::new(file);
+pub fn detect(data: &[u8]) -> bool {
+    data.starts_with(MAGIC_NUM)
+}
 
-    if let Some(p) = dst_path.parent() {
-        create_dir_all(p).unwrap();
+/// Returns the byte order of the BPE stream `data`, detected from its header.
+pub fn detect_endian(data: &[u8]) -> Option<Endian> {
+    if !detect(data) || data.len() < 16 {
+        return None;
     }
-    let file = create_file_to_write(dst_path);
-    let mut writer = BufWriter::new(file);
+    let mut reserved = [0u8; 4];
+    reserved.copy_from_slice(&data[4..8]);
+    let mut compressed_len = [0u8; 4];
+    compressed_len.copy_from_slice(&data[8..12]);
+    Some(header_endian(reserved, compressed_len))
+}
+
+/// The byte order of a header: the reserved word is 0x100 in every known file,
+/// otherwise the compressed length is used.
+#[inline]
+fn header_endian(reserved: [u8; 4], compressed_len: [u8; 4]) -> Endian {
+    if reserved == *RESERVED {
+        Endian::Little
+    } else if reserved == Endian::Big.words_from_le(RESERVED) {
+        Endian::Big
+    } else {
+        Endian::guess(compressed_len)
+    }
+}
+
+/// Returns the length of the BPE stream at the start of `data`, which may be followed
+/// by padding.
+pub fn exact_len(data: &[u8]) -> Option<usize> {
+    let endian = detect_endian(data)?;
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&data[8..12]);
+    let len = endian.u32_from_bytes(buf) as usize + 16;
+    if len > data.len() {
+        return None;
+    }
+    Some(len)
+}
 
-    writer.write_all(MAGIC_NUM).unwrap();
-    writer.write_all(RESERVED).unwrap();
-    writer.write_all(b"\x00\x00\x00\x00").unwrap(); // re-write compressed_len later
-    writer.write_all(&file_len.to_le_bytes()).unwrap();
+/// Compresses `data` into a complete little-endian BPE stream, header included.
+///
+/// Fails with [`Error::TooLarge`] if `data` or the compressed stream is 4 GiB or
+/// larger, since the header can't record such lengths.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    compress_with_endian(data, Endian::Little)
+}
+
+/// Like [`compress`], writing the header and block lengths in the given byte order.
+pub fn compress_with_endian(data: &[u8], endian: Endian) -> Result<Vec<u8>> {
+    compress_with(data, endian, Level::Normal)
+}
+
+/// Like [`compress`], in the given byte order and compression level.
+pub fn compress_with(data: &[u8], endian: Endian, level: Level) -> Result<Vec<u8>> {
+    compress_with_choices(data, endian, level, Choices::default())
+}
+
+/// Like [`compress_with`], choosing pairs and substitute bytes as `choices` says.
+pub fn compress_with_choices(
+    data: &[u8],
+    endian: Endian,
+    level: Level,
+    choices: Choices,
+) -> Result<Vec<u8>> {
+    let decompressed_len = u32::try_from(data.len()).map_err(|_| Error::TooLarge)?;
+
+    let mut result = vec![0u8; 16];
+    match level {
+        Level::Normal => compress_blocks(data, endian, choices, &mut result),
+        Level::Max => {
+            // choosing cuts block by block can lose overall, so keep the normal result
+            // when it is smaller
+            let mut normal = vec![];
+            compress_blocks(data, endian, choices, &mut normal);
+            let mut searched = vec![];
+            compress_blocks_searching(data, endian, choices, &mut searched);
+            result.extend(if searched.len() < normal.len() {
+                searched
+            } else {
+                normal
+            });
+        }
+    }
+    write_header(&mut result, endian, decompressed_len)?;
+    Ok(result)
+}
+
+/// Compresses `data` the way `analysis` found the stream it came from was written:
+/// blocks cut at the same lengths, the same pairs substituted by the same bytes in the
+/// same order. This rewrites streams that none of the [`Choices`] reproduce, as long
+/// as `data` is as long as they decompress to.
+pub fn compress_replaying(data: &[u8], analysis: &Analysis) -> Result<Vec<u8>> {
+    let decompressed_len = u32::try_from(data.len()).map_err(|_| Error::TooLarge)?;
+    let analyzed_len: u64 = analysis.blocks.iter().map(|b| b.decompressed_len).sum();
+    if analyzed_len != data.len() as u64 {
+        return Err(Error::Unsupported(format!(
+            "the analysis covers {:#x} bytes, not {:#x}",
+            analyzed_len,
+            data.len()
+        )));
+    }
+
+    let mut result = vec![0u8; 16];
+    let mut start = 0;
+    for block in &analysis.blocks {
+        let end = start + block.decompressed_len as usize;
+        let compressed = compress_block_replaying(
+            data[start..end].to_vec(),
+            &block.substitutions,
+            analysis.endian,
+        );
+        result.extend(compressed);
+        start = end;
+    }
+    write_header(&mut result, analysis.endian, decompressed_len)?;
+    Ok(result)
+}
 
+/// Fills in the 16 bytes reserved for the header at the start of `result`.
+fn write_header(result: &mut [u8], endian: Endian, decompressed_len: u32) -> Result<()> {
+    let compressed_len = u32::try_from(result.len() - 16).map_err(|_| Error::TooLarge)?;
+    result[..4].copy_from_slice(MAGIC_NUM);
+    result[4..8].copy_from_slice(&endian.words_from_le(RESERVED));
+    result[8..12].copy_from_slice(&endian.u32_to_bytes(compressed_len));
+    result[12..16].copy_from_slice(&endian.u32_to_bytes(decompressed_len));
+    Ok(())
+}
+
+/// How hard the compressor looks for good block boundaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Level {
+    /// Cuts a block at 4096 bytes or before its 201st distinct byte, like the original tool.
+    #[default]
+    Normal,
+    /// Tries several block lengths and distinct byte limits for every block, keeping the
+    /// one that compresses best. Much slower, and never larger than `Normal`.
+    Max,
+}
+
+impl Level {
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Normal => "normal",
+            Level::Max => "max",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "normal" => Some(Level::Normal),
+            "max" => Some(Level::Max),
+            _ => None,
+        }
+    }
+}
+
+/// How the encoder picks the pair to substitute among equally frequent ones, and the
+/// byte standing for it among the bytes a block doesn't use.
+///
+/// These are the only choices the encoder offers. A stream whose blocks were written
+/// some other way can still be rewritten from its [`Analysis`], see
+/// [`compress_replaying`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Choices {
+    pub tie_break: TieBreak,
+    pub substitute: SubstituteOrder,
+}
+
+impl Choices {
+    /// Every combination, the default first.
+    pub const ALL: [Choices; 4] = [
+        Choices {
+            tie_break: TieBreak::Greatest,
+            substitute: SubstituteOrder::Lowest,
+        },
+        Choices {
+            tie_break: TieBreak::Greatest,
+            substitute: SubstituteOrder::Highest,
+        },
+        Choices {
+            tie_break: TieBreak::Least,
+            substitute: SubstituteOrder::Lowest,
+        },
+        Choices {
+            tie_break: TieBreak::Least,
+            substitute: SubstituteOrder::Highest,
+        },
+    ];
+}
+
+impl fmt::Display for Choices {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "--ties {} --substitutes {}",
+            self.tie_break.name(),
+            self.substitute.name()
+        )
+    }
+}
+
+/// Which of the most frequent pairs is substituted first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TieBreak {
+    /// The pair with the greater first byte, then second byte.
+    #[default]
+    Greatest,
+    /// The pair with the lesser first byte, then second byte.
+    Least,
+}
+
+impl TieBreak {
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            TieBreak::Greatest => "greatest",
+            TieBreak::Least => "least",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "greatest" => Some(TieBreak::Greatest),
+            "least" => Some(TieBreak::Least),
+            _ => None,
+        }
+    }
+}
+
+/// Which unused byte stands for the next substituted pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SubstituteOrder {
+    /// From 0x00 upwards.
+    #[default]
+    Lowest,
+    /// From 0xff downwards.
+    Highest,
+}
+
+impl SubstituteOrder {
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            SubstituteOrder::Lowest => "lowest",
+            SubstituteOrder::Highest => "highest",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lowest" => Some(SubstituteOrder::Lowest),
+            "highest" => Some(SubstituteOrder::Highest),
+            _ => None,
+        }
+    }
+}
+
+fn compress_blocks(data: &[u8], endian: Endian, choices: Choices, result: &mut Vec<u8>) {
     let pool = threadpool::ThreadPool::default();
     let mut rx_list = vec![];
-    loop {
-        match read_block_to_compress(&mut reader) {
-            None => break,
-            Some((block, used_bytes)) => {
-                let (tx, rx) = channel::<Vec<u8>>();
-                rx_list.push(rx);
-                pool.execute(move || {
-                    let compressed = compress_block(block, &used_bytes);
-                    tx.send(compressed).unwrap();
-                });
-            }
-        }
+    let mut start = 0;
+    while start < data.len() {
+        let (len, used_bytes) = block_extent(&data[start..], MAX_BLOCK_SIZE, MAX_NORMAL_BYTE_NUM);
+        let block = data[start..start + len].to_vec();
+        start += len;
+
+        let (tx, rx) = channel::<Vec<u8>>();
+        rx_list.push(rx);
+        pool.execute(move || {
+            let compressed = compress_block(block, &used_bytes, endian, choices);
+            let _ = tx.send(compressed);
+        });
     }
-    let mut compressed_len = 0;
+
     for rx in rx_list {
-        let compressed = rx.recv().unwrap();
-        writer.write_all(&compressed).unwrap();
-        compressed_len += compressed.len();
+        let compressed = rx.recv().expect("compression worker panicked");
+        result.extend(compressed);
     }
-    let compressed_len = u32::try_from(compressed_len).unwrap();
-    writer.seek(SeekFrom::Start(8)).unwrap();
-    writer.write_all(&compressed_len.to_le_bytes()).unwrap();
 }
 
-fn read_block_to_compress<R: Read + Seek>(reader: &mut R) -> Option<(Vec<u8>, HashSet<u8>)> {
-    let mut vec = vec![];
-    let mut used_bytes = HashSet::new();
-    loop {
-        let mut buf = [0u8];
-        let read_num = reader.read(&mut buf).unwrap();
-        if read_num == 0 {
-            return if vec.is_empty() {
-                None
-            } else {
-                Some((vec, used_bytes))
-            };
+/// Compresses `data` block by block, choosing each block's end among the cuts given by
+/// `SEARCH_BLOCK_SIZES` and `SEARCH_BYTE_NUMS` by the best compression ratio.
+fn compress_blocks_searching(data: &[u8], endian: Endian, choices: Choices, result: &mut Vec<u8>) {
+    let mut start = 0;
+    while start < data.len() {
+        let mut tried_lens = HashSet::new();
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for max_len in SEARCH_BLOCK_SIZES {
+            for max_byte_num in SEARCH_BYTE_NUMS {
+                let (len, used_bytes) = block_extent(&data[start..], max_len, max_byte_num);
+                // the same cut always compresses the same way
+                if !tried_lens.insert(len) {
+                    continue;
+                }
+                let block = data[start..start + len].to_vec();
+                let compressed = compress_block(block, &used_bytes, endian, choices);
+                let is_better = match &best {
+                    // compare compressed.len() / len with best_compressed.len() / best_len
+                    Some((best_len, best_compressed)) => {
+                        compressed.len() * best_len < best_compressed.len() * len
+                    }
+                    None => true,
+                };
+                if is_better {
+                    best = Some((len, compressed));
+                }
+            }
         }
+        let (len, compressed) = best.expect("a block is never empty");
+        result.extend(compressed);
+        start += len;
+    }
+}
 
-        if used_bytes.len() == MAX_NORMAL_BYTE_NUM && !used_bytes.contains(&buf[0]) {
-            reader.seek(SeekFrom::Current(-1)).unwrap();
+/// Returns the length of the block starting `data`, cut at `max_len` bytes or before
+/// it would use more than `max_byte_num` distinct bytes, along with the bytes used.
+fn block_extent(data: &[u8], max_len: usize, max_byte_num: usize) -> (usize, HashSet<u8>) {
+    let mut used_bytes = HashSet::new();
+    let mut len = 0;
+    for byte in data.iter().take(max_len) {
+        if used_bytes.len() == max_byte_num && !used_bytes.contains(byte) {
             break;
         }
+        used_bytes.insert(*byte);
+        len += 1;
+    }
+    (len, used_bytes)
+}
 
-        vec.push(buf[0]);
-        used_bytes.insert(buf[0]);
-        if vec.len() >= MAX_BLOCK_SIZE {
-            break;
+/// Decompresses a complete BPE stream, header included.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    decompress_with(data, None, false)
+}
+
+/// Like [`decompress`], in the given byte order or the detected one, and rejecting
+/// streams that don't decompress to the length in the header if `strict` is set.
+///
+/// The blocks are found by a first pass over their substitution tables and lengths,
+/// which also gives the decompressed length before anything is allocated. Each block is
+/// then expanded straight into its part of the output, in parallel for large streams.
+pub fn decompress_with(data: &[u8], endian: Option<Endian>, strict: bool) -> Result<Vec<u8>> {
+    let decoder = BpeDecoder::with_endian(data, endian)?;
+    let endian = decoder.endian();
+    let compressed_len = decoder.compressed_len() as u64;
+    let decompressed_len = decoder.decompressed_len() as u64;
+    check_bounds(16, compressed_len, data.len() as u64)?;
+    let data = &data[16..16 + compressed_len as usize];
+
+    let blocks = index_blocks(data, endian)?;
+    let max_len = blocks.len() as u64 * MAX_DECODED_BLOCK_SIZE as u64;
+    if decompressed_len > max_len {
+        return Err(Error::Malformed(format!(
+            "header claims {:#x} decompressed bytes, more than {} blocks can hold",
+            decompressed_len,
+            blocks.len()
+        )));
+    }
+    let len: usize = blocks.iter().map(|b| b.decompressed_len).sum();
+    if strict && len as u64 != decompressed_len {
+        return Err(Error::Malformed(format!(
+            "data decompresses to {:#x} bytes instead of {:#x}",
+            len, decompressed_len
+        )));
+    }
+
+    // short data is padded with zeroes, extra data is kept
+    let mut result = vec![0u8; len.max(decompressed_len as usize)];
+    decode_blocks(&blocks, data, &mut result[..len]);
+    Ok(result)
+}
+
+/// A block of compressed data found by [`index_blocks`].
+struct IndexedBlock {
+    /// Where the block starts in the compressed data.
+    start: usize,
+    pairs: [Option<[u8; 2]>; 256],
+    /// The substituted bytes, in the order [`order_substitutions`] gives.
+    order: Vec<u8>,
+    /// Where the block's data is in the compressed data.
+    data: Range<usize>,
+    decompressed_len: usize,
+}
+
+/// Finds every block in the compressed data `data` and how long it decompresses to,
+/// reading only their substitution tables, lengths and data.
+fn index_blocks(data: &[u8], endian: Endian) -> Result<Vec<IndexedBlock>> {
+    let compressed_len = data.len() as u64;
+    let mut reader = CountingReader {
+        inner: data,
+        count: 0,
+    };
+    let mut vec = vec![];
+    while reader.count < compressed_len {
+        let start = reader.count;
+        let block = index_one_block(&mut reader, endian, compressed_len)
+            .map_err(|e| block_error(vec.len() as _, 16 + start, e))?;
+        vec.push(block);
+    }
+    Ok(vec)
+}
+
+/// Reads one block's substitution table and length, and sums up what its data expands to.
+fn index_one_block(
+    reader: &mut CountingReader<&[u8]>,
+    endian: Endian,
+    compressed_len: u64,
+) -> Result<IndexedBlock> {
+    let start = reader.count as usize;
+    // straight from the slice, which reads much faster than through the counter
+    let table_len = reader.inner.len();
+    let pairs = read_substitution_info(&mut reader.inner)?;
+    reader.count += (table_len - reader.inner.len()) as u64;
+    let (order, lens) = order_substitutions(&pairs)?;
+
+    let len = read_block_len(reader, endian, compressed_len)? as usize;
+    let (data, rest) = reader.inner.split_at(len);
+    let data_start = reader.count as usize;
+    reader.inner = rest;
+    reader.count += len as u64;
+
+    let decompressed_len: usize = data.iter().map(|&b| lens[b as usize]).sum();
+    if decompressed_len > MAX_DECODED_BLOCK_SIZE {
+        return Err(Error::Malformed(format!(
+            "block decompresses to more than {:#x} bytes",
+            MAX_DECODED_BLOCK_SIZE
+        )));
+    }
+    Ok(IndexedBlock {
+        start,
+        pairs,
+        order,
+        data: data_start..data_start + len,
+        decompressed_len,
+    })
+}
+
+/// Decodes the indexed blocks of `data` into `out` one after another, splitting them
+/// between threads if there is enough output to be worth it.
+///
+/// Indexing has already checked everything that could go wrong.
+fn decode_blocks(blocks: &[IndexedBlock], data: &[u8], out: &mut [u8]) {
+    let thread_num = if out.len() < MIN_PARALLEL_DECODE_LEN {
+        1
+    } else {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    };
+    if thread_num == 1 {
+        return decode_block_run(blocks, data, out);
+    }
+
+    let run_len = blocks.len().div_ceil(thread_num);
+    thread::scope(|scope| {
+        let mut rest = out;
+        for run in blocks.chunks(run_len) {
+            let len = run.iter().map(|b| b.decompressed_len).sum();
+            let (run_out, tail) = mem::take(&mut rest).split_at_mut(len);
+            rest = tail;
+            scope.spawn(move || decode_block_run(run, data, run_out));
         }
+    });
+}
+
+/// Decodes consecutive indexed blocks of `data` into `out`.
+fn decode_block_run(blocks: &[IndexedBlock], data: &[u8], out: &mut [u8]) {
+    let mut rest = out;
+    for block in blocks {
+        let (block_out, tail) = mem::take(&mut rest).split_at_mut(block.decompressed_len);
+        rest = tail;
+        decode_block(block, data, block_out);
     }
-    Some((vec, used_bytes))
 }
 
-fn compress_block(mut block: Vec<u8>, used_bytes: &HashSet<u8>) -> Vec<u8> {
-    let mut substitutable_bytes = VecDeque::new();
-    for b in 0..=u8::MAX {
-        if !used_bytes.contains(&b) {
-            substitutable_bytes.push_back(b);
+/// Decodes an indexed block of `data` into `out`, which is as long as the block
+/// decompresses to.
+fn decode_block(block: &IndexedBlock, data: &[u8], out: &mut [u8]) {
+    let expansions = expand_in_order(&block.pairs, &block.order);
+    let mut pos = 0;
+    for &b in &data[block.data.clone()] {
+        let expansion = expansions.get(b);
+        out[pos..pos + expansion.len()].copy_from_slice(expansion);
+        pos += expansion.len();
+    }
+}
+
+/// Buffers everything written to it and writes the compressed stream on [`finish`].
+///
+/// Nothing reaches the inner writer before [`finish`]: an encoder dropped without it
+/// loses everything written to it, which debug builds report by panicking.
+///
+/// [`finish`]: BpeEncoder::finish
+pub struct BpeEncoder<W: Write> {
+    /// Taken by [`finish`](Self::finish).
+    writer: Option<W>,
+    endian: Endian,
+    level: Level,
+    choices: Choices,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BpeEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_endian(writer, Endian::Little)
+    }
+
+    pub fn with_endian(writer: W, endian: Endian) -> Self {
+        Self {
+            writer: Some(writer),
+            endian,
+            level: Level::Normal,
+            choices: Choices::default(),
+            buf: vec![],
         }
     }
-    let mut count_map: HashMap<[u8; 2], usize> = HashMap::new();
-    for slice in block.windows(2) {
-        let pair = [slice[0], slice[1]];
-        match count_map.get(&pair) {
-            Some(count) => {
-                let count = *count;
-                count_map.insert(pair, count + 1);
+
+    /// Sets the compression level, [`Level::Normal`] by default.
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Sets how pairs and substitute bytes are picked, [`Choices::default`] by default.
+    pub fn with_choices(mut self, choices: Choices) -> Self {
+        self.choices = choices;
+        self
+    }
+
+    /// Compresses the buffered data, writes it out and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        let mut writer = self.writer.take().expect("only finish takes the writer");
+        let compressed = compress_with_choices(&self.buf, self.endian, self.level, self.choices)?;
+        writer.write_all(&compressed)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Drop for BpeEncoder<W> {
+    fn drop(&mut self) {
+        debug_assert!(
+            self.writer.is_none() || thread::panicking(),
+            "BpeEncoder dropped without calling finish, its data is lost"
+        );
+    }
+}
+
+impl<W: Write> Write for BpeEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompresses a BPE stream block by block as it is read.
+///
+/// Every block is checked against the header and the substitution table it carries,
+/// so that corrupted input fails with [`Error::BadBlock`] instead of panicking or
+/// expanding without bound.
+pub struct BpeDecoder<R: Read> {
+    reader: CountingReader<R>,
+    endian: Endian,
+    compressed_len: u64,
+    decompressed_len: u64,
+    strict: bool,
+    total_write_num: u64,
+    block_index: u64,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> BpeDecoder<R> {
+    /// Reads the header from `reader`, detecting its byte order.
+    pub fn new(reader: R) -> Result<Self> {
+        Self::with_endian(reader, None)
+    }
+
+    /// Reads the header from `reader` in the given byte order, or the detected one.
+    pub fn with_endian(mut reader: R, endian: Option<Endian>) -> Result<Self> {
+        let buf = read_exact!(reader, 4);
+        check_magic_num(buf, MAGIC_NUM)?;
+
+        let reserved = read_exact!(reader, 4);
+
+        let buf = read_exact!(reader, 4);
+        let endian = endian.unwrap_or_else(|| header_endian(reserved, buf));
+        let compressed_len = endian.u32_from_bytes(buf);
+
+        let buf = read_exact!(reader, 4);
+        let decompressed_len = endian.u32_from_bytes(buf);
+
+        Ok(Self {
+            reader: CountingReader {
+                inner: reader,
+                count: 0,
+            },
+            endian,
+            compressed_len: compressed_len as _,
+            decompressed_len: decompressed_len as _,
+            strict: false,
+            total_write_num: 0,
+            block_index: 0,
+            block: vec![],
+            pos: 0,
+        })
+    }
+
+    /// Rejects streams that don't decompress to exactly the length in the header. By
+    /// default, missing data at the end is filled with zeroes and extra data is kept.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    pub fn compressed_len(&self) -> u32 {
+        self.compressed_len as _
+    }
+
+    pub fn decompressed_len(&self) -> u32 {
+        self.decompressed_len as _
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.inner
+    }
+
+    /// Decodes the next block into the internal buffer; returns false at the end of stream.
+    fn fill_block(&mut self) -> Result<bool> {
+        self.block.clear();
+        self.pos = 0;
+        if self.reader.count < self.compressed_len {
+            let offset = 16 + self.reader.count;
+            unpack_one_block(
+                &mut self.reader,
+                &mut self.block,
+                self.endian,
+                self.compressed_len,
+            )
+            .map_err(|e| block_error(self.block_index, offset, e))?;
+            if self.strict && self.total_write_num + self.block.len() as u64 > self.decompressed_len
+            {
+                let e = Error::Malformed(format!(
+                    "data decompresses to more than {:#x} bytes",
+                    self.decompressed_len
+                ));
+                return Err(block_error(self.block_index, offset, e));
             }
-            None => {
-                count_map.insert(pair, 1);
+            self.block_index += 1;
+        } else if self.total_write_num < self.decompressed_len {
+            if self.strict {
+                return Err(Error::Malformed(format!(
+                    "data decompresses to {:#x} bytes instead of {:#x}",
+                    self.total_write_num, self.decompressed_len
+                )));
             }
+            let zero_num = (self.decompressed_len - self.total_write_num).min(MAX_BLOCK_SIZE as _);
+            self.block.resize(zero_num as _, 0);
+        } else {
+            return Ok(false);
         }
+        self.total_write_num += self.block.len() as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for BpeDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            if !self.fill_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Attributes an error to the block `index` starting at `offset`, keeping I/O errors as
+/// they are.
+fn block_error(index: u64, offset: u64, e: Error) -> Error {
+    let reason = match e {
+        Error::Io { .. } => return e,
+        Error::Malformed(reason) => reason,
+        e => e.to_string(),
+    };
+    Error::BadBlock {
+        index,
+        offset,
+        reason,
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// What a BPE stream tells about the encoder that wrote it, block by block.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub endian: Endian,
+    pub blocks: Vec<BlockAnalysis>,
+}
+
+impl Analysis {
+    /// The choices for which the encoder writes every block byte for byte, empty if the
+    /// stream can't be reproduced.
+    pub fn matching_choices(&self) -> Vec<Choices> {
+        Choices::ALL
+            .iter()
+            .copied()
+            .filter(|c| self.blocks.iter().all(|b| b.matching_choices.contains(c)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockAnalysis {
+    /// Where the block starts in the stream, header included.
+    pub offset: u64,
+    /// The length of the block, substitution table included.
+    pub len: u64,
+    /// Where the block's data starts in the decompressed data.
+    pub decompressed_offset: u64,
+    pub decompressed_len: u64,
+    /// Whether the block ends where [`Level::Normal`] cuts it.
+    pub normal_cut: bool,
+    /// The substitutions of the block, in the order the encoder chose them.
+    pub substitutions: Vec<Substitution>,
+    /// The choices for which the encoder writes this block byte for byte.
+    pub matching_choices: Vec<Choices>,
+    /// Whether [`compress_replaying`] writes this block byte for byte.
+    pub replayed: bool,
+}
+
+impl fmt::Display for BlockAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block at {:#x}, {:#x} bytes: data {:#x}..{:#x}",
+            self.offset,
+            self.len,
+            self.decompressed_offset,
+            self.decompressed_offset + self.decompressed_len
+        )?;
+        if !self.normal_cut {
+            write!(f, ", not cut like --level normal")?;
+        }
+        match self.matching_choices.first() {
+            Some(choices) => write!(f, ", matches {}", choices)?,
+            None => write!(f, ", matches no choices")?,
+        }
+        if !self.replayed {
+            write!(f, ", not replayed")?;
+        }
+        Ok(())
+    }
+}
+
+/// A pair substituted in a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Substitution {
+    pub byte: u8,
+    pub pair: [u8; 2],
+    /// How often the pair occurred when it was substituted.
+    pub count: usize,
+    /// Whether no other pair occurred more often at that point.
+    pub most_frequent: bool,
+    /// The other pairs that occurred just as often.
+    pub ties: Vec<[u8; 2]>,
+}
+
+impl fmt::Display for Substitution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#04x} <- {:02x} {:02x}, {} times",
+            self.byte, self.pair[0], self.pair[1], self.count
+        )?;
+        if !self.most_frequent {
+            write!(f, ", not the most frequent pair")?;
+        }
+        if !self.ties.is_empty() {
+            let ties: Vec<_> = self
+                .ties
+                .iter()
+                .map(|p| format!("{:02x} {:02x}", p[0], p[1]))
+                .collect();
+            write!(f, ", tied with {}", ties.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the BPE stream `data` and works out, for every block, the order in which its
+/// pairs were substituted and which of the [`Choices::ALL`] make the encoder reproduce
+/// it, if any. [`compress_replaying`] follows the cuts and order found instead.
+pub fn analyze(data: &[u8]) -> Result<Analysis> {
+    let decoder = BpeDecoder::new(data)?;
+    let endian = decoder.endian();
+    let compressed_len = decoder.compressed_len() as u64;
+    check_bounds(16, compressed_len, data.len() as u64)?;
+    let compressed = &data[16..16 + compressed_len as usize];
+
+    let mut decoded = vec![];
+    for indexed in index_blocks(compressed, endian)? {
+        let mut block = vec![0; indexed.decompressed_len];
+        decode_block(&indexed, compressed, &mut block);
+        let raw = &compressed[indexed.start..indexed.data.end];
+        decoded.push((indexed.start, raw, indexed.pairs, block));
+    }
+
+    let decompressed: Vec<u8> = decoded.iter().flat_map(|d| d.3.iter().copied()).collect();
+    let mut blocks = vec![];
+    let mut decompressed_offset = 0;
+    for (start, raw, pairs, block) in decoded {
+        let (normal_len, _) = block_extent(
+            &decompressed[decompressed_offset..],
+            MAX_BLOCK_SIZE,
+            MAX_NORMAL_BYTE_NUM,
+        );
+        let used_bytes: HashSet<u8> = block.iter().copied().collect();
+        let matching_choices: Vec<Choices> = Choices::ALL
+            .iter()
+            .copied()
+            .filter(|c| compress_block(block.clone(), &used_bytes, endian, *c) == raw)
+            .collect();
+        let tie_break = matching_choices
+            .first()
+            .map(|c| c.tie_break)
+            .unwrap_or_default();
+        let substitutions = replay_substitutions(&block, &pairs, tie_break);
+        let replayed = compress_block_replaying(block.clone(), &substitutions, endian) == raw;
+        blocks.push(BlockAnalysis {
+            offset: 16 + start as u64,
+            len: raw.len() as _,
+            decompressed_offset: decompressed_offset as _,
+            decompressed_len: block.len() as _,
+            normal_cut: normal_len == block.len(),
+            substitutions,
+            matching_choices,
+            replayed,
+        });
+        decompressed_offset += block.len();
+    }
+    Ok(Analysis { endian, blocks })
+}
+
+/// Substitutes the pairs of a block's table into its decompressed data the way the
+/// encoder would, taking the most frequent of them each time among those whose bytes
+/// are already there.
+fn replay_substitutions(
+    block: &[u8],
+    pairs: &[Option<[u8; 2]>; 256],
+    tie_break: TieBreak,
+) -> Vec<Substitution> {
+    let mut block = block.to_vec();
+    let mut pair_table = PairTable::new();
+    for slice in block.windows(2) {
+        pair_table.increment([slice[0], slice[1]]);
+    }
+    let mut remaining: Vec<(u8, [u8; 2])> = (0..=u8::MAX)
+        .filter_map(|b| pairs[b as usize].map(|pair| (b, pair)))
+        .collect();
+
+    let mut result = vec![];
+    while !remaining.is_empty() {
+        let key = |&(_, pair): &(u8, [u8; 2])| {
+            let rank = match tie_break {
+                TieBreak::Greatest => u16::from_be_bytes(pair),
+                TieBreak::Least => u16::MAX - u16::from_be_bytes(pair),
+            };
+            (pair_table.count(pair), rank)
+        };
+        // a pair holding a byte that still stands for another pair can't occur yet
+        let is_pending = |b: u8| remaining.iter().any(|(byte, _)| *byte == b);
+        let position = (0..remaining.len())
+            .filter(|&i| !remaining[i].1.iter().any(|b| is_pending(*b)))
+            .max_by_key(|&i| key(&remaining[i]))
+            .expect("the decoder rejects substitution cycles");
+        let (byte, pair) = remaining.remove(position);
+
+        let count = pair_table.count(pair);
+        let mut most_frequent_pairs: Vec<[u8; 2]> = pair_table
+            .most_frequent_pairs(1)
+            .iter()
+            .map(|index| index.to_be_bytes())
+            .collect();
+        let max_count = most_frequent_pairs
+            .first()
+            .map_or(0, |p| pair_table.count(*p));
+        let ties = if count == max_count {
+            most_frequent_pairs.retain(|p| *p != pair);
+            most_frequent_pairs.sort_unstable();
+            most_frequent_pairs
+        } else {
+            vec![]
+        };
+        result.push(Substitution {
+            byte,
+            pair,
+            count,
+            most_frequent: count >= max_count,
+            ties,
+        });
+        if !block.is_empty() {
+            substitute_pair(&mut block, pair, byte, &mut pair_table);
+        }
+    }
+    result
+}
+
+/// Names the field of the BPE stream `data` that `offset` falls in.
+pub fn field_at(_data: &[u8], offset: u64) -> Option<String> {
+    let field = match offset {
+        0..=3 => "magic number",
+        4..=7 => "reserved",
+        8..=11 => "compressed length",
+        12..=15 => "decompressed length",
+        _ => "compressed data",
+    };
+    Some(field.to_string())
+}
+
+/// Compresses the file `src_path`, or standard input if it is `-`, into `dst_path`.
+pub fn pack(src_path: PathBuf, dst_path: PathBuf, options: &PackOptions) -> Result<()> {
+    let data = if src_path == Path::new("-") {
+        let mut data = vec![];
+        io::stdin().lock().read_to_end(&mut data)?;
+        data
+    } else {
+        read_file(&src_path)?
+    };
+
+    if let Some(p) = dst_path.parent() {
+        create_dir_all(p)?;
+    }
+    let file = create_file_to_write(&dst_path)?;
+    let mut writer = BufWriter::new(file);
+    pack_data(&data, &mut writer, options)?;
+    writer.flush().map_err(|e| Error::io(&dst_path, e))
+}
+
+/// Compresses everything read from `reader`, which doesn't need to be seekable, into
+/// `writer`.
+pub fn pack_stream<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &PackOptions,
+) -> Result<()> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    pack_data(&data, writer, options)
+}
+
+fn pack_data<W: Write>(data: &[u8], writer: &mut W, options: &PackOptions) -> Result<()> {
+    let endian = options.endian.unwrap_or_default();
+    writer.write_all(&compress_with_choices(
+        data,
+        endian,
+        options.level,
+        options.choices,
+    )?)?;
+    Ok(())
+}
+
+fn compress_block(
+    mut block: Vec<u8>,
+    used_bytes: &HashSet<u8>,
+    endian: Endian,
+    choices: Choices,
+) -> Vec<u8> {
+    let mut substitutable_bytes: VecDeque<u8> =
+        (0..=u8::MAX).filter(|b| !used_bytes.contains(b)).collect();
+    let mut pair_table = PairTable::new();
+    for slice in block.windows(2) {
+        pair_table.increment([slice[0], slice[1]]);
     }
 
     let mut substitution_map = HashMap::new();
     loop {
-        let substituted_byte = match substitutable_bytes.pop_front() {
+        let substituted_byte = match choices.substitute {
+            SubstituteOrder::Lowest => substitutable_bytes.pop_front(),
+            SubstituteOrder::Highest => substitutable_bytes.pop_back(),
+        };
+        let substituted_byte = match substituted_byte {
             Some(b) => b,
             None => break,
         };
-        let pair = match count_map
-            .iter()
-            .filter(|(_, count)| **count >= MIN_OCCURRENCE)
-            .max_by(|(pair1, count1), (pair2, count2)| {
-                let result = count1.cmp(count2);
-                if result != Ordering::Equal {
-                    result
-                } else {
-                    let result = pair1[0].cmp(&pair2[0]);
-                    if result != Ordering::Equal {
-                        result
-                    } else {
-                        pair1[1].cmp(&pair2[1])
-                    }
-                }
-            }) {
-            Some((pair, _)) => [pair[0], pair[1]],
+        let pair = match pair_table.most_frequent(MIN_OCCURRENCE, choices.tie_break) {
+            Some(pair) => pair,
             None => break,
         };
         substitution_map.insert(substituted_byte, pair);
+        substitute_pair(&mut block, pair, substituted_byte, &mut pair_table);
+    }
+    write_block(&substitution_map, block, endian)
+}
 
-        let len = block.len();
-        let mut w = 0; // index to write
-        let mut r = 0; // index to read
-        while r < len - 1 {
-            if block[r] == pair[0] && block[r + 1] == pair[1] {
-                if w > 0 {
-                    let p = [block[w - 1], block[r]];
-                    if let Some(count) = count_map.get(&p) {
-                        let count = *count;
-                        if count > 1 {
-                            count_map.insert(p, count - 1);
-                        } else {
-                            count_map.remove(&p);
-                        }
-                    }
-                    let p = [block[w - 1], substituted_byte];
-                    match count_map.get(&p) {
-                        Some(count) => {
-                            let count = *count;
-                            count_map.insert(p, count + 1);
-                        }
-                        None => {
-                            count_map.insert(p, 1);
-                        }
-                    }
-                }
-                if r < len - 2 {
-                    let p = [block[r + 1], block[r + 2]];
-                    if let Some(count) = count_map.get(&p) {
-                        let count = *count;
-                        if count > 1 {
-                            count_map.insert(p, count - 1);
-                        } else {
-                            count_map.remove(&p);
-                        }
-                    }
-                    let p = [substituted_byte, block[r + 2]];
-                    match count_map.get(&p) {
-                        Some(count) => {
-                            let count = *count;
-                            count_map.insert(p, count + 1);
-                        }
-                        None => {
-                            count_map.insert(p, 1);
-                        }
-                    }
-                }
-                block[w] = substituted_byte;
-                w += 1;
-                r += 2;
-            } else {
-                block[w] = block[r];
-                w += 1;
-                r += 1;
-            }
-        }
-        if r == len - 1 {
-            block[w] = block[r];
-            w += 1;
+/// Compresses a block substituting `substitutions` in the order given.
+fn compress_block_replaying(
+    mut block: Vec<u8>,
+    substitutions: &[Substitution],
+    endian: Endian,
+) -> Vec<u8> {
+    let mut pair_table = PairTable::new();
+    for slice in block.windows(2) {
+        pair_table.increment([slice[0], slice[1]]);
+    }
+    let mut substitution_map = HashMap::new();
+    for substitution in substitutions {
+        substitution_map.insert(substitution.byte, substitution.pair);
+        if !block.is_empty() {
+            substitute_pair(
+                &mut block,
+                substitution.pair,
+                substitution.byte,
+                &mut pair_table,
+            );
         }
-        block.truncate(w);
-        count_map.remove(&pair);
     }
+    write_block(&substitution_map, block, endian)
+}
 
+/// Writes a compressed block: the substitution table, then the length and the data.
+fn write_block(substitution_map: &HashMap<u8, [u8; 2]>, block: Vec<u8>, endian: Endian) -> Vec<u8> {
     let mut result = vec![];
 
     // write encoding info
@@ -221,7 +1098,7 @@ fn compress_block(mut block: Vec<u8>, used_bytes: &HashSet<u8>) -> Vec<u8> {
                     Some(b) => b,
                     None => {
                         // [byte, 255] are substituted
-                        write_substituted_range(&mut result, byte, u8::MAX, &substitution_map);
+                        write_substituted_range(&mut result, byte, u8::MAX, substitution_map);
                         break 'out;
                     }
                 };
@@ -234,7 +1111,7 @@ fn compress_block(mut block: Vec<u8>, used_bytes: &HashSet<u8>) -> Vec<u8> {
                 i += 1;
             }
             // [byte, byte+i-1] are substituted
-            write_substituted_range(&mut result, byte, byte + i - 1, &substitution_map);
+            write_substituted_range(&mut result, byte, byte + i - 1, substitution_map);
             byte += i;
         } else {
             loop {
@@ -242,7 +1119,7 @@ fn compress_block(mut block: Vec<u8>, used_bytes: &HashSet<u8>) -> Vec<u8> {
                     Some(b) => b,
                     None => {
                         // [byte, 255] are not substituted
-                        write_not_substituted_range(&mut result, byte, u8::MAX, &substitution_map);
+                        write_not_substituted_range(&mut result, byte, u8::MAX, substitution_map);
                         break 'out;
                     }
                 };
@@ -255,7 +1132,7 @@ fn compress_block(mut block: Vec<u8>, used_bytes: &HashSet<u8>) -> Vec<u8> {
                 i += 1;
             }
             // [byte, byte+i-1] are not substituted
-            write_not_substituted_range(&mut result, byte, byte + i - 1, &substitution_map);
+            write_not_substituted_range(&mut result, byte, byte + i - 1, substitution_map);
             byte = match u8::checked_add(byte, i + 1) {
                 Some(b) => b,
                 None => break 'out, // done
@@ -264,12 +1141,147 @@ fn compress_block(mut block: Vec<u8>, used_bytes: &HashSet<u8>) -> Vec<u8> {
     }
 
     // write compressed data's len
-    let len = u16::try_from(block.len()).unwrap();
-    result.extend_from_slice(&len.to_le_bytes());
+    let len = block.len() as u16; // a block never exceeds MAX_BLOCK_SIZE
+    result.extend_from_slice(&endian.u16_to_bytes(len));
     result.extend(block);
     result
 }
 
+/// Replaces every occurrence of `pair` in `block` with `substituted_byte`, from left to
+/// right, updating the pair counts.
+fn substitute_pair(
+    block: &mut Vec<u8>,
+    pair: [u8; 2],
+    substituted_byte: u8,
+    pair_table: &mut PairTable,
+) {
+    // counts are updated as the original encoder did, so that the same pairs win
+    let len = block.len();
+    let mut w = 0; // index to write
+    let mut r = 0; // index to read
+    while r < len - 1 {
+        if block[r] == pair[0] && block[r + 1] == pair[1] {
+            if w > 0 {
+                pair_table.decrement([block[w - 1], block[r]]);
+                pair_table.increment([block[w - 1], substituted_byte]);
+            }
+            if r < len - 2 {
+                pair_table.decrement([block[r + 1], block[r + 2]]);
+                pair_table.increment([substituted_byte, block[r + 2]]);
+            }
+            block[w] = substituted_byte;
+            w += 1;
+            r += 2;
+        } else {
+            block[w] = block[r];
+            w += 1;
+            r += 1;
+        }
+    }
+    if r == len - 1 {
+        block[w] = block[r];
+        w += 1;
+    }
+    block.truncate(w);
+    pair_table.clear(pair);
+}
+
+/// Counts of every byte pair in a block, bucketed by count so that the most frequent
+/// pair is found without scanning all of them.
+struct PairTable {
+    counts: Vec<u16>,
+    /// Index of each counted pair in its bucket.
+    positions: Vec<u16>,
+    /// Pairs, as `first << 8 | second`, by count.
+    buckets: Vec<Vec<u16>>,
+    /// No bucket above this one holds any pair.
+    max_count: usize,
+}
+
+impl PairTable {
+    fn new() -> Self {
+        Self {
+            counts: vec![0; 0x10000],
+            positions: vec![0; 0x10000],
+            buckets: vec![],
+            max_count: 0,
+        }
+    }
+
+    #[inline]
+    fn increment(&mut self, pair: [u8; 2]) {
+        let index = u16::from_be_bytes(pair);
+        let count = self.counts[index as usize] as usize;
+        self.move_to_bucket(index, count, count + 1);
+        self.max_count = self.max_count.max(count + 1);
+    }
+
+    /// Decrements the count of `pair`, unless it is already zero.
+    #[inline]
+    fn decrement(&mut self, pair: [u8; 2]) {
+        let index = u16::from_be_bytes(pair);
+        let count = self.counts[index as usize] as usize;
+        if count > 0 {
+            self.move_to_bucket(index, count, count - 1);
+        }
+    }
+
+    #[inline]
+    fn clear(&mut self, pair: [u8; 2]) {
+        let index = u16::from_be_bytes(pair);
+        let count = self.counts[index as usize] as usize;
+        self.move_to_bucket(index, count, 0);
+    }
+
+    /// Returns the pair counted most often, at least `min_count` times; ties are broken
+    /// by `tie_break`.
+    fn most_frequent(&mut self, min_count: usize, tie_break: TieBreak) -> Option<[u8; 2]> {
+        let pairs = self.most_frequent_pairs(min_count);
+        let index = match tie_break {
+            TieBreak::Greatest => pairs.iter().max(),
+            TieBreak::Least => pairs.iter().min(),
+        };
+        index.map(|index| index.to_be_bytes())
+    }
+
+    /// Returns all the pairs counted most often, at least `min_count` times.
+    fn most_frequent_pairs(&mut self, min_count: usize) -> &[u16] {
+        while self.max_count >= min_count.max(1) && self.buckets[self.max_count].is_empty() {
+            self.max_count -= 1;
+        }
+        if self.max_count < min_count.max(1) {
+            return &[];
+        }
+        &self.buckets[self.max_count]
+    }
+
+    #[inline]
+    fn count(&self, pair: [u8; 2]) -> usize {
+        self.counts[u16::from_be_bytes(pair) as usize] as usize
+    }
+
+    #[inline]
+    fn move_to_bucket(&mut self, index: u16, from: usize, to: usize) {
+        if from > 0 {
+            let bucket = &mut self.buckets[from];
+            let position = self.positions[index as usize] as usize;
+            bucket.swap_remove(position);
+            if let Some(moved) = bucket.get(position) {
+                self.positions[*moved as usize] = position as u16;
+            }
+        }
+        if to > 0 {
+            if to >= self.buckets.len() {
+                self.buckets.resize(to + 1, vec![]);
+            }
+            let bucket = &mut self.buckets[to];
+            self.positions[index as usize] = bucket.len() as u16;
+            bucket.push(index);
+        }
+        self.counts[index as usize] = to as u16;
+    }
+}
+
 #[inline]
 fn write_substituted_range(
     buffer: &mut Vec<u8>,
@@ -280,8 +1292,7 @@ fn write_substituted_range(
     let len = to - from;
     buffer.push(len);
     for b in from..=to {
-        let pair = substitution_map.get(&b).unwrap();
-        buffer.extend_from_slice(pair);
+        buffer.extend_from_slice(&substitution_map[&b]);
     }
 }
 
@@ -306,69 +1317,100 @@ fn write_not_substituted_range(
     }
 }
 
-pub fn unpack(src_path: PathBuf, dst_path: PathBuf) {
-    let file = File::open(src_path).unwrap();
-    let file_len = file.metadata().unwrap().len();
-    let mut reader = BufReader::new(file);
-
-    let buf = read_exact!(&mut reader, 4);
-    assert_eq!(&buf, MAGIC_NUM);
-
-    reader.seek(SeekFrom::Current(4)).unwrap();
-
-    let buf = read_exact!(&mut reader, 4);
-    let compressed_len = u32::from_le_bytes(buf);
-    assert!(compressed_len > 0 && (compressed_len + 16) as u64 == file_len);
+/// Lists the header of the BPE file `src_path`; it has no entries.
+pub fn list(src_path: &Path, options: &UnpackOptions) -> Result<Listing> {
+    let file = open_file(src_path)?;
+    list_from(file, options.endian)
+}
 
-    let buf = read_exact!(&mut reader, 4);
-    let decompressed_len = u32::from_le_bytes(buf);
+/// Like [`list`], reading the header from `reader` in the given byte order, or the
+/// detected one.
+pub fn list_from<R: Read>(reader: R, endian: Option<Endian>) -> Result<Listing> {
+    let decoder = BpeDecoder::with_endian(reader, endian)?;
+    let mut listing = Listing::new("bpe", decoder.endian());
+    listing.header = vec![
+        (
+            "compressed_len".to_string(),
+            decoder.compressed_len() as u64,
+        ),
+        (
+            "decompressed_len".to_string(),
+            decoder.decompressed_len() as u64,
+        ),
+    ];
+    Ok(listing)
+}
 
-    let file = create_file_to_write(dst_path);
-    let mut writer = BufWriter::new(file);
+pub fn unpack(src_path: PathBuf, dst_path: PathBuf, options: &UnpackOptions) -> Result<()> {
+    let data = read_file(&src_path)?;
+    let decoder = BpeDecoder::with_endian(&data[..], options.endian)?;
 
-    let mut total_read_num = 0;
-    let mut total_write_num = 0;
-    while total_read_num < compressed_len {
-        let read_pos0 = reader.seek(SeekFrom::Current(0)).unwrap();
-        let write_pos0 = writer.seek(SeekFrom::Current(0)).unwrap();
+    let compressed_len = decoder.compressed_len();
+    if compressed_len == 0 || compressed_len as u64 + 16 != data.len() as u64 {
+        return Err(Error::Malformed(format!(
+            "compressed length {:#x} doesn't match file size {:#x}",
+            compressed_len,
+            data.len()
+        )));
+    }
 
-        unpack_one_block(&mut reader, &mut writer);
+    let decompressed = decompress_with(&data, options.endian, options.strict)?;
+    write_file(&dst_path, &decompressed)
+}
 
-        let read_pos1 = reader.seek(SeekFrom::Current(0)).unwrap();
-        let write_pos1 = writer.seek(SeekFrom::Current(0)).unwrap();
+/// Decodes one block into `block`, reading no further than `compressed_len` bytes into
+/// the compressed data.
+fn unpack_one_block<R: Read>(
+    reader: &mut CountingReader<R>,
+    block: &mut Vec<u8>,
+    endian: Endian,
+    compressed_len: u64,
+) -> Result<()> {
+    let pairs = read_substitution_info(reader)?;
+    let expansions = expand_substitutions(&pairs)?;
 
-        total_read_num += u32::try_from(read_pos1 - read_pos0).unwrap();
-        total_write_num += u32::try_from(write_pos1 - write_pos0).unwrap();
-    }
-    if total_write_num < decompressed_len {
-        for _ in 0..(decompressed_len - total_write_num) {
-            writer.write_all(b"\x00").unwrap();
+    let len = read_block_len(reader, endian, compressed_len)?;
+    let mut data = vec![0u8; len as _];
+    reader.read_exact(&mut data)?;
+    for b in data {
+        let expansion = expansions.get(b);
+        if block.len() + expansion.len() > MAX_DECODED_BLOCK_SIZE {
+            return Err(Error::Malformed(format!(
+                "block decompresses to more than {:#x} bytes",
+                MAX_DECODED_BLOCK_SIZE
+            )));
         }
+        block.extend_from_slice(expansion);
     }
+    Ok(())
 }
 
-fn unpack_one_block<R: Read, W: Write>(reader: &mut R, writer: &mut W) {
-    let encoding_map = read_substitution_info(reader);
-
+/// Reads the length of a block's data, which must end within `compressed_len` bytes
+/// into the compressed data.
+fn read_block_len<R: Read>(
+    reader: &mut CountingReader<R>,
+    endian: Endian,
+    compressed_len: u64,
+) -> Result<u64> {
     let buf = read_exact!(reader, 2);
-    let len = u16::from_le_bytes(buf);
-
-    for _ in 0..len {
-        let buf = read_exact!(reader, 1);
-        match encoding_map.get(&buf[0]) {
-            None => writer.write_all(&buf).unwrap(),
-            Some(vec) => writer.write_all(&vec).unwrap(),
-        }
+    let len = endian.u16_from_bytes(buf) as u64;
+    let rem = compressed_len.saturating_sub(reader.count);
+    if reader.count > compressed_len || len > rem {
+        return Err(Error::Malformed(format!(
+            "block data of {:#x} bytes exceeds the {:#x} bytes left",
+            len, rem
+        )));
     }
+    Ok(len)
 }
 
-fn read_substitution_info<R: Read>(reader: &mut R) -> HashMap<u8, Vec<u8>> {
-    let mut substituted_bytes = vec![];
-    let mut substitution_map = HashMap::new();
+/// Reads the substitution table of a block: the pair each byte stands for, if any.
+fn read_substitution_info<R: Read>(reader: &mut R) -> Result<[Option<[u8; 2]>; 256]> {
+    let mut pairs = [None; 256];
     let mut byte = 0;
     'out: loop {
         let mut buf = [0u8; 2];
-        reader.read_exact(&mut buf[..1]).unwrap();
+        reader.read_exact(&mut buf[..1])?;
         let mut substituted_byte_num = 1;
         if buf[0] >= 0x80 {
             // [byte, byte+i] are not substituted, (byte+i+1) may be substituted
@@ -382,11 +1424,10 @@ fn read_substitution_info<R: Read>(reader: &mut R) -> HashMap<u8, Vec<u8>> {
             substituted_byte_num = buf[0] + 1;
         }
         for _ in 0..substituted_byte_num {
-            reader.read_exact(&mut buf[..1]).unwrap();
+            reader.read_exact(&mut buf[..1])?;
             if byte != buf[0] {
-                reader.read_exact(&mut buf[1..]).unwrap();
-                substituted_bytes.push(byte);
-                substitution_map.insert(byte, Vec::from(&buf[..]));
+                reader.read_exact(&mut buf[1..])?;
+                pairs[byte as usize] = Some(buf);
             }
             byte = match u8::checked_add(byte, 1) {
                 Some(b) => b,
@@ -394,25 +1435,548 @@ fn read_substitution_info<R: Read>(reader: &mut R) -> HashMap<u8, Vec<u8>> {
             }
         }
     }
+    Ok(pairs)
+}
+
+/// What every byte of a block finally stands for, all in one buffer.
+struct Expansions {
+    bytes: Vec<u8>,
+    /// The start and length of each byte's expansion in `bytes`.
+    spans: [(u32, u32); 256],
+}
+
+impl Expansions {
+    fn get(&self, byte: u8) -> &[u8] {
+        let (start, len) = self.spans[byte as usize];
+        &self.bytes[start as usize..(start + len) as usize]
+    }
+}
+
+/// Expands every byte into the bytes it finally stands for.
+fn expand_substitutions(pairs: &[Option<[u8; 2]>; 256]) -> Result<Expansions> {
+    let (order, _) = order_substitutions(pairs)?;
+    Ok(expand_in_order(pairs, &order))
+}
+
+/// Expands every byte, taking the substituted bytes in `order`, which must come from
+/// [`order_substitutions`].
+fn expand_in_order(pairs: &[Option<[u8; 2]>; 256], order: &[u8]) -> Expansions {
+    let mut bytes: Vec<u8> = (0..=u8::MAX).collect();
+    let mut spans = [(0, 1); 256];
+    for (byte, span) in spans.iter_mut().enumerate() {
+        span.0 = byte as u32;
+    }
+    for &byte in order {
+        let pair = pairs[byte as usize].expect("only substituted bytes are ordered");
+        let start = bytes.len();
+        for b in pair {
+            let (b_start, b_len) = spans[b as usize];
+            bytes.extend_from_within(b_start as usize..(b_start + b_len) as usize);
+        }
+        spans[byte as usize] = (start as u32, (bytes.len() - start) as u32);
+    }
+    Expansions { bytes, spans }
+}
+
+/// Orders the substituted bytes so that each comes after the substituted bytes its pair
+/// refers to, and finds how long every byte expands, without recursion.
+///
+/// A pair may refer to other substituted bytes but never, even indirectly, to its own
+/// byte, and no byte may expand to more than a whole block.
+fn order_substitutions(pairs: &[Option<[u8; 2]>; 256]) -> Result<(Vec<u8>, [usize; 256])> {
+    const PENDING: u8 = 0;
+    const EXPANDING: u8 = 1;
+    const DONE: u8 = 2;
 
-    for b in &substituted_bytes {
-        flat_substitution_map(&mut substitution_map, *b);
+    let mut order = vec![];
+    let mut lens = [1; 256];
+    let mut states = [DONE; 256];
+    for (state, pair) in states.iter_mut().zip(pairs) {
+        if pair.is_some() {
+            *state = PENDING;
+        }
     }
-    substitution_map
+
+    let mut stack = vec![];
+    for first in (0..=u8::MAX).filter(|&b| pairs[b as usize].is_some()) {
+        stack.push(first);
+        while let Some(&byte) = stack.last() {
+            let pair = match pairs[byte as usize] {
+                Some(pair) => pair,
+                None => {
+                    stack.pop();
+                    continue;
+                }
+            };
+            match states[byte as usize] {
+                PENDING => {
+                    // the bytes being expanded are exactly those on the stack
+                    states[byte as usize] = EXPANDING;
+                    for b in pair {
+                        match states[b as usize] {
+                            PENDING => stack.push(b),
+                            EXPANDING => {
+                                return Err(Error::Malformed(format!(
+                                    "substitution of byte {:#04x} refers back to itself",
+                                    b
+                                )))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                EXPANDING => {
+                    let len = lens[pair[0] as usize] + lens[pair[1] as usize];
+                    if len > MAX_DECODED_BLOCK_SIZE {
+                        return Err(Error::Malformed(format!(
+                            "substitution of byte {:#04x} expands to more than {:#x} bytes",
+                            byte, MAX_DECODED_BLOCK_SIZE
+                        )));
+                    }
+                    lens[byte as usize] = len;
+                    order.push(byte);
+                    states[byte as usize] = DONE;
+                    stack.pop();
+                }
+                _ => {
+                    stack.pop();
+                }
+            }
+        }
+    }
+    Ok((order, lens))
 }
 
-fn flat_substitution_map(substitution_map: &mut HashMap<u8, Vec<u8>>, byte: u8) -> Vec<u8> {
-    let vec;
-    match substitution_map.get(&byte) {
-        Some(v) => {
-            vec = v.clone();
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::*;
+
+    /// Deterministic pseudo-random bytes, xorshift.
+    fn random_bytes(len: usize, seed: u32, modulus: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                (x % modulus) as u8
+            })
+            .collect()
+    }
+
+    /// The encoder as it was before the bucketed pair table, kept as a reference.
+    fn reference_compress_block(mut block: Vec<u8>, used_bytes: &HashSet<u8>) -> Vec<u8> {
+        let mut substitutable_bytes: VecDeque<u8> =
+            (0..=u8::MAX).filter(|b| !used_bytes.contains(b)).collect();
+        let mut count_map: HashMap<[u8; 2], usize> = HashMap::new();
+        for slice in block.windows(2) {
+            *count_map.entry([slice[0], slice[1]]).or_default() += 1;
         }
-        None => return vec![byte],
+        let decrement = |count_map: &mut HashMap<[u8; 2], usize>, p: [u8; 2]| {
+            if let Some(count) = count_map.get_mut(&p) {
+                *count -= 1;
+                if *count == 0 {
+                    count_map.remove(&p);
+                }
+            }
+        };
+
+        let mut substitution_map = HashMap::new();
+        while let Some(substituted_byte) = substitutable_bytes.pop_front() {
+            let pair = match count_map
+                .iter()
+                .filter(|(_, count)| **count >= MIN_OCCURRENCE)
+                .max_by(
+                    |(pair1, count1), (pair2, count2)| match count1.cmp(count2) {
+                        Ordering::Equal => pair1.cmp(pair2),
+                        result => result,
+                    },
+                ) {
+                Some((pair, _)) => *pair,
+                None => break,
+            };
+            substitution_map.insert(substituted_byte, pair);
+
+            let len = block.len();
+            let mut w = 0;
+            let mut r = 0;
+            while r < len - 1 {
+                if block[r] == pair[0] && block[r + 1] == pair[1] {
+                    if w > 0 {
+                        decrement(&mut count_map, [block[w - 1], block[r]]);
+                        *count_map
+                            .entry([block[w - 1], substituted_byte])
+                            .or_default() += 1;
+                    }
+                    if r < len - 2 {
+                        decrement(&mut count_map, [block[r + 1], block[r + 2]]);
+                        *count_map
+                            .entry([substituted_byte, block[r + 2]])
+                            .or_default() += 1;
+                    }
+                    block[w] = substituted_byte;
+                    w += 1;
+                    r += 2;
+                } else {
+                    block[w] = block[r];
+                    w += 1;
+                    r += 1;
+                }
+            }
+            if r == len - 1 {
+                block[w] = block[r];
+                w += 1;
+            }
+            block.truncate(w);
+            count_map.remove(&pair);
+        }
+        write_block(&substitution_map, block, Endian::Little)
     }
-    let mut result = vec![];
-    for b in vec {
-        result.extend(flat_substitution_map(substitution_map, b));
+
+    /// Compresses `data` block by block with both encoders, returning the block lengths.
+    fn assert_same_as_reference(data: &[u8]) -> Vec<usize> {
+        let mut lens = vec![];
+        let mut reference = vec![];
+        let mut start = 0;
+        while start < data.len() {
+            let (len, used_bytes) =
+                block_extent(&data[start..], MAX_BLOCK_SIZE, MAX_NORMAL_BYTE_NUM);
+            let block = data[start..start + len].to_vec();
+            let expected = reference_compress_block(block.clone(), &used_bytes);
+            let found = compress_block(block, &used_bytes, Endian::Little, Choices::default());
+            assert_eq!(found, expected, "block at {:#x}", start);
+            reference.extend(expected);
+            lens.push(len);
+            start += len;
+        }
+        assert_eq!(compress(data).unwrap()[16..], reference[..]);
+        assert_eq!(decompress(&compress(data).unwrap()).unwrap(), data);
+        lens
+    }
+
+    #[test]
+    fn same_output_as_reference_encoder_on_random_data() {
+        assert_same_as_reference(&random_bytes(20000, 1, 256));
+        assert_same_as_reference(&random_bytes(20000, 2, 16));
+        assert_same_as_reference(&random_bytes(20000, 3, 3));
+    }
+
+    #[test]
+    fn same_output_as_reference_encoder_on_repetitive_data() {
+        assert_same_as_reference(&[0; 10000]);
+        assert_same_as_reference(&b"ab".repeat(5000));
+        assert_same_as_reference(&b"the quick brown fox jumps over the lazy dog. ".repeat(300));
+        assert_same_as_reference(&[7]);
+        assert_same_as_reference(&[1, 2]);
+    }
+
+    #[test]
+    fn same_output_as_reference_encoder_at_block_limits() {
+        // every byte value: blocks are cut before their 201st distinct byte
+        let all_bytes: Vec<u8> = (0..=u8::MAX).cycle().take(256 * 40).collect();
+        let lens = assert_same_as_reference(&all_bytes);
+        assert_eq!(lens[0], MAX_NORMAL_BYTE_NUM);
+
+        // exactly 200 distinct bytes, then 4096 bytes of few distinct ones
+        let mut data: Vec<u8> = (0..MAX_NORMAL_BYTE_NUM as u8).collect();
+        data.extend(random_bytes(
+            MAX_BLOCK_SIZE - data.len(),
+            4,
+            MAX_NORMAL_BYTE_NUM as _,
+        ));
+        data.extend(random_bytes(MAX_BLOCK_SIZE * 2 + 1, 5, 8));
+        let lens = assert_same_as_reference(&data);
+        assert_eq!(lens, [MAX_BLOCK_SIZE, MAX_BLOCK_SIZE, MAX_BLOCK_SIZE, 1]);
+    }
+
+    /// A substitution table giving `pairs`, every other byte standing for itself.
+    fn substitution_table(pairs: &[(u8, [u8; 2])]) -> Vec<u8> {
+        let mut table = vec![];
+        for half in [0u8, 0x80] {
+            table.push(0x7f);
+            for byte in half..=half + 0x7f {
+                match pairs.iter().find(|(b, _)| *b == byte) {
+                    Some((_, pair)) => table.extend_from_slice(pair),
+                    None => table.push(byte),
+                }
+            }
+        }
+        table
+    }
+
+    /// A little-endian stream of one block, `len` overriding the block length field.
+    fn stream(
+        pairs: &[(u8, [u8; 2])],
+        data: &[u8],
+        len: Option<u16>,
+        decompressed_len: u32,
+    ) -> Vec<u8> {
+        let mut block = substitution_table(pairs);
+        block.extend_from_slice(&len.unwrap_or(data.len() as u16).to_le_bytes());
+        block.extend_from_slice(data);
+        let mut result = MAGIC_NUM.to_vec();
+        result.extend_from_slice(RESERVED);
+        result.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        result.extend_from_slice(&decompressed_len.to_le_bytes());
+        result.extend(block);
+        result
+    }
+
+    fn assert_bad_block(data: &[u8], reason: &str) {
+        for strict in [false, true] {
+            match decompress_with(data, None, strict) {
+                Err(Error::BadBlock {
+                    index: 0,
+                    offset: 16,
+                    reason: r,
+                }) => {
+                    assert!(r.contains(reason), "{:?} doesn't contain {:?}", r, reason)
+                }
+                result => panic!("expected a bad block, got {:?}", result.map(|v| v.len())),
+            }
+            let mut decoder = BpeDecoder::new(data).unwrap().with_strict(strict);
+            assert!(decoder.read_to_end(&mut vec![]).is_err());
+        }
+    }
+
+    #[test]
+    fn decoder_rejects_substitution_cycles() {
+        assert_bad_block(
+            &stream(&[(0, [1, 2]), (1, [0, 3])], &[0], None, 3),
+            "refers back",
+        );
+        assert_bad_block(&stream(&[(0, [5, 0])], &[0], None, 2), "refers back");
+    }
+
+    #[test]
+    fn decoder_rejects_block_data_out_of_bounds() {
+        assert_bad_block(&stream(&[], &[1, 2], Some(10), 2), "exceeds");
+        let mut truncated = stream(&[], &[1, 2], None, 2);
+        truncated.truncate(16 + 100);
+        assert!(decompress(&truncated).is_err());
+    }
+
+    #[test]
+    fn decoder_rejects_overflowing_expansions() {
+        // byte 0 doubles 17 times
+        let pairs: Vec<_> = (0..17).map(|b| (b, [b + 1, b + 1])).collect();
+        assert_bad_block(&stream(&pairs, &[0], None, 1 << 17), "expands to more than");
+        // each byte fits but the block doesn't
+        let pairs: Vec<_> = (0..15).map(|b| (b, [b + 1, b + 1])).collect();
+        assert_bad_block(
+            &stream(&pairs, &[0, 0], None, 1 << 16),
+            "decompresses to more than",
+        );
+    }
+
+    #[test]
+    fn decoder_accepts_blocks_over_4096_bytes() {
+        // 5000 bytes, as cut by other encoders
+        let pairs: Vec<_> = (0..12).map(|b| (b, [b + 1, b + 1])).collect();
+        let mut data = vec![0];
+        data.extend_from_slice(&[13; 904]);
+        let stream = stream(&pairs, &data, None, 5000);
+        let mut expected = vec![12; 4096];
+        expected.extend_from_slice(&[13; 904]);
+        assert_eq!(decompress_with(&stream, None, true).unwrap(), expected);
+
+        let mut decoded = vec![];
+        let mut decoder = BpeDecoder::new(&stream[..]).unwrap().with_strict(true);
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn decoder_rejects_lengths_the_blocks_cannot_hold() {
+        let data = stream(&[], &[1, 2, 3], None, 0xf000_0000);
+        for strict in [false, true] {
+            match decompress_with(&data, None, strict) {
+                Err(Error::Malformed(reason)) => assert!(reason.contains("header claims")),
+                result => panic!("expected a bad header, got {:?}", result.map(|v| v.len())),
+            }
+        }
+
+        // a short stream within what its blocks can hold is still padded
+        let data = stream(&[], &[1, 2, 3], None, 5);
+        assert_eq!(
+            decompress_with(&data, None, false).unwrap(),
+            [1, 2, 3, 0, 0]
+        );
+        assert!(decompress_with(&data, None, true).is_err());
+    }
+
+    #[test]
+    fn decoder_round_trips_streams_large_enough_for_threads() {
+        let mut data = random_bytes(MIN_PARALLEL_DECODE_LEN, 7, 256);
+        data.extend_from_slice(&b"abcabd".repeat(MIN_PARALLEL_DECODE_LEN / 6));
+        let compressed = compress(&data).unwrap();
+        assert_eq!(decompress_with(&compressed, None, true).unwrap(), data);
+    }
+    #[test]
+    fn analyze_finds_the_choices_and_order_of_the_encoder() {
+        let data = b"the quick brown fox jumps over the lazy dog. ".repeat(200);
+        for choices in Choices::ALL {
+            let compressed =
+                compress_with_choices(&data, Endian::Big, Level::Normal, choices).unwrap();
+            let analysis = analyze(&compressed).unwrap();
+            assert_eq!(analysis.endian, Endian::Big);
+            assert!(analysis.matching_choices().contains(&choices));
+
+            let mut offset = 16;
+            let mut decompressed_offset = 0;
+            for block in &analysis.blocks {
+                assert_eq!(block.offset, offset);
+                assert_eq!(block.decompressed_offset, decompressed_offset);
+                assert!(block.normal_cut && block.replayed);
+                let first = &block.substitutions[0];
+                assert!(first.most_frequent);
+                assert_eq!(
+                    first.byte,
+                    match choices.substitute {
+                        SubstituteOrder::Lowest => 0x00,
+                        SubstituteOrder::Highest => 0xff,
+                    }
+                );
+                offset += block.len;
+                decompressed_offset += block.decompressed_len;
+            }
+            assert_eq!(offset, compressed.len() as u64);
+            assert_eq!(decompressed_offset, data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn compress_replaying_rewrites_streams_no_choices_reproduce() {
+        // cut after 41 bytes, where --level normal wouldn't, and substituted by bytes
+        // neither the lowest nor the highest unused
+        let blocks: [(&[u8], u8); 2] = [(b"ab", 0x80), (b"cd", 0x90)];
+        let mut data = vec![];
+        let mut stream = vec![0u8; 16];
+        for (i, (pair, byte)) in blocks.iter().enumerate() {
+            let mut block = pair.repeat(20 + i * 10);
+            block.push(b'c');
+            data.extend_from_slice(&block);
+            let mut substituted = vec![*byte; 20 + i * 10];
+            substituted.push(b'c');
+            let map = [(*byte, [pair[0], pair[1]])].iter().copied().collect();
+            stream.extend(write_block(&map, substituted, Endian::Little));
+        }
+        write_header(&mut stream, Endian::Little, data.len() as u32).unwrap();
+        assert_eq!(decompress_with(&stream, None, true).unwrap(), data);
+
+        let analysis = analyze(&stream).unwrap();
+        assert!(analysis.matching_choices().is_empty());
+        for choices in Choices::ALL {
+            assert_ne!(
+                compress_with_choices(&data, Endian::Little, Level::Normal, choices).unwrap(),
+                stream
+            );
+        }
+        assert!(!analysis.blocks[0].normal_cut);
+        assert!(analysis.blocks.iter().all(|b| b.replayed));
+        assert_eq!(compress_replaying(&data, &analysis).unwrap(), stream);
+
+        assert!(matches!(
+            compress_replaying(&data[1..], &analysis),
+            Err(Error::Unsupported(_))
+        ));
+    }
+    #[test]
+    fn encoder_writes_the_stream_on_finish() {
+        let data = b"abcabd".repeat(100);
+        let mut encoder = BpeEncoder::with_endian(vec![], Endian::Big);
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(
+            compressed,
+            compress_with_endian(&data, Endian::Big).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "without calling finish")]
+    fn encoder_dropped_without_finish_panics() {
+        let mut encoder = BpeEncoder::new(vec![]);
+        encoder.write_all(b"lost").unwrap();
+    }
+    #[test]
+    fn max_level_decodes_and_is_never_larger_than_normal() {
+        // runs of text, of few and of every byte value, so that cuts matter
+        let mut data = vec![];
+        for seed in 1..5 {
+            data.extend(b"the quick brown fox jumps over the lazy dog. ".repeat(40));
+            data.extend(random_bytes(3000, seed, 256));
+            data.extend(random_bytes(5000, seed + 10, 12));
+        }
+        for input in [&data[..], &data[..1000], &[7], &[]] {
+            for endian in [Endian::Little, Endian::Big] {
+                let normal = compress_with(input, endian, Level::Normal).unwrap();
+                let max = compress_with(input, endian, Level::Max).unwrap();
+                assert!(
+                    max.len() <= normal.len(),
+                    "{} > {}",
+                    max.len(),
+                    normal.len()
+                );
+                assert_eq!(decompress_with(&max, Some(endian), true).unwrap(), input);
+
+                let mut decoded = vec![];
+                let mut decoder = BpeDecoder::new(&max[..]).unwrap().with_strict(true);
+                decoder.read_to_end(&mut decoded).unwrap();
+                assert_eq!(decoded, input);
+            }
+        }
+        let normal = compress(&data).unwrap();
+        let max = compress_with(&data, Endian::Little, Level::Max).unwrap();
+        assert!(
+            max.len() < normal.len(),
+            "the search finds better cuts here"
+        );
+    }
+
+    #[test]
+    fn header_endian_prefers_the_reserved_word() {
+        let mut reserved = [0u8; 4];
+        reserved.copy_from_slice(&Endian::Big.words_from_le(RESERVED));
+        assert_eq!(header_endian(*RESERVED, [0, 0, 0, 1]), Endian::Little);
+        assert_eq!(header_endian(reserved, [1, 0, 0, 0]), Endian::Big);
+        assert_eq!(header_endian([0; 4], [0, 0, 0, 1]), Endian::Big);
+        assert_eq!(header_endian([0; 4], [1, 0, 0, 0]), Endian::Little);
+    }
+
+    #[test]
+    fn big_endian_unpack_and_pack_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-bpe-be", std::process::id()));
+        let src_path = dir.join("be.bpe");
+        let unpacked_path = dir.join("be");
+        let packed_path = dir.join("packed.bpe");
+        let original = b"big-endian big-endian big-endian".repeat(20);
+        let data = compress_with_endian(&original, Endian::Big).unwrap();
+        assert_eq!(detect_endian(&data), Some(Endian::Big));
+        assert_eq!(decompress(&data).unwrap(), original);
+
+        crate::create_dir_all(&dir).unwrap();
+        crate::write_file(&src_path, &data).unwrap();
+        unpack(
+            src_path.clone(),
+            unpacked_path.clone(),
+            &UnpackOptions::default(),
+        )
+        .unwrap();
+        // a lone BPE file keeps no byte order, unlike a node of an unpacked tree
+        let options = PackOptions {
+            endian: Some(Endian::Big),
+            ..PackOptions::default()
+        };
+        pack(unpacked_path.clone(), packed_path.clone(), &options).unwrap();
+        let unpacked = read_file(&unpacked_path).unwrap();
+        let packed = read_file(&packed_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(unpacked, original);
+        assert_eq!(packed, data);
     }
-    substitution_map.insert(byte, result.clone());
-    result
 }
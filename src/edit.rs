@@ -0,0 +1,200 @@
+// Changes to entries of existing archives. Replacing is made on the archive bytes so
+// that whatever isn't changed stays as it was, unknown fields and padding included;
+// adding and removing entries rewrite the archive that holds them.
+//
+// Selectors are the ones of `extract`; a BPE stream on the way is decompressed and
+// compressed again in its own byte order once the entry inside has changed. It is
+// re-encoded at the normal level with the default choices, whatever encoder wrote it,
+// so its blocks may differ from the original's even around unchanged data.
+
+use std::path::Path;
+
+use crate::extract::find_entry;
+use crate::format::{ContainerFormat, Registry};
+use crate::{bpe, check_bounds, read_file, replace_file, Error, Result, UnpackOptions};
+
+/// Replaces the data of the entry `selector` in the archive `src_path` with `new_data`.
+pub fn replace(
+    registry: &Registry,
+    src_path: &Path,
+    selector: &str,
+    new_data: &[u8],
+    options: &UnpackOptions,
+) -> Result<()> {
+    edit(
+        registry,
+        src_path,
+        selector,
+        options,
+        &mut |format, data, name, options| {
+            let listing = format.list_data(data, options)?;
+            let entry = find_entry(&listing, name)?;
+            format.replace_entry(data, entry.index, new_data, options)
+        },
+    )
+}
+
+/// Adds `new_data` to the archive `src_path` as the entry `selector`, whose last
+/// component is the new entry's name and the rest the selector of the archive to add
+/// it to. `divider` names the EPAC section to add it to.
+pub fn add(
+    registry: &Registry,
+    src_path: &Path,
+    selector: &str,
+    new_data: &[u8],
+    divider: Option<&str>,
+    options: &UnpackOptions,
+) -> Result<()> {
+    edit(
+        registry,
+        src_path,
+        selector,
+        options,
+        &mut |format, data, name, options| {
+            format.add_entry(data, name, new_data.to_vec(), divider, options)
+        },
+    )
+}
+
+/// Removes the entry `selector` from the archive `src_path`.
+pub fn remove(
+    registry: &Registry,
+    src_path: &Path,
+    selector: &str,
+    options: &UnpackOptions,
+) -> Result<()> {
+    edit(
+        registry,
+        src_path,
+        selector,
+        options,
+        &mut |format, data, name, options| {
+            let listing = format.list_data(data, options)?;
+            let entry = find_entry(&listing, name)?;
+            format.remove_entry(data, entry.index, options)
+        },
+    )
+}
+
+/// Calls `f` with the archive holding the last component of `selector` and that
+/// component, then writes every archive on the way back to `src_path`, replacing it
+/// only once the new file is complete.
+fn edit(
+    registry: &Registry,
+    src_path: &Path,
+    selector: &str,
+    options: &UnpackOptions,
+    f: &mut EditFn,
+) -> Result<()> {
+    let components: Vec<&str> = selector.split('/').filter(|c| !c.is_empty()).collect();
+    if components.is_empty() {
+        return Err(Error::InvalidName(selector.to_string()));
+    }
+    let mut data = read_file(src_path)?;
+    edit_in(registry, &mut data, &components, options, f)?;
+    replace_file(src_path, &data)
+}
+
+type EditFn<'a> =
+    dyn FnMut(&dyn ContainerFormat, &mut Vec<u8>, &str, &UnpackOptions) -> Result<()> + 'a;
+
+fn edit_in(
+    registry: &Registry,
+    data: &mut Vec<u8>,
+    components: &[&str],
+    options: &UnpackOptions,
+    f: &mut EditFn,
+) -> Result<()> {
+    // nested data is read in its own byte order
+    let nested_options = UnpackOptions {
+        endian: None,
+        ..options.clone()
+    };
+    if bpe::detect(data) {
+        let endian = options
+            .endian
+            .or_else(|| bpe::detect_endian(data))
+            .unwrap_or_default();
+        let mut decompressed = bpe::decompress_with(data, Some(endian), options.strict)?;
+        edit_in(registry, &mut decompressed, components, &nested_options, f)?;
+        *data = bpe::compress_with_endian(&decompressed, endian)?;
+        return Ok(());
+    }
+
+    let format = registry.detect(data).ok_or(Error::UnknownFormat)?;
+    if components.len() == 1 {
+        return f(format, data, components[0], options);
+    }
+
+    let listing = format.list_data(data, options)?;
+    let entry = find_entry(&listing, components[0])?;
+    check_bounds(entry.offset, entry.len, data.len() as u64)?;
+    let mut entry_data = data[entry.offset as usize..(entry.offset + entry.len) as usize].to_vec();
+    let padded_len = entry_data.len();
+    format.trim_entry(&mut entry_data);
+    let is_trimmed = entry_data.len() < padded_len;
+    edit_in(
+        registry,
+        &mut entry_data,
+        &components[1..],
+        &nested_options,
+        f,
+    )?;
+    // padding still holding the new data is kept, so the table keeps the entry's length
+    if is_trimmed && entry_data.len() <= padded_len {
+        entry_data.resize(padded_len, 0);
+    }
+    format.replace_entry(data, entry.index, &entry_data, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epac::{EpacArchive, EpacEntry, EpacSection};
+    use crate::pach::PachArchive;
+    use crate::write_file;
+
+    #[test]
+    fn nested_edits_keep_padding_the_data_still_fits_in() {
+        let path = std::env::temp_dir().join(format!(
+            "rr-mod-tool-test-{}-edit-padded.epac",
+            std::process::id()
+        ));
+        let mut pach = PachArchive::new();
+        pach.entries = vec![(0, vec![1; 8]), (1, vec![2; 8])];
+        let mut pach_data = vec![];
+        pach.write_to(&mut pach_data).unwrap();
+        let mut entry = EpacEntry::new("PACH", pach_data).unwrap();
+        entry.padded_len = Some(0x200);
+        let mut archive = EpacArchive::new();
+        archive.sections.push(EpacSection {
+            divider: None,
+            entries: vec![entry],
+        });
+        let mut original = vec![];
+        archive.write_to(&mut original).unwrap();
+        write_file(&path, &original).unwrap();
+
+        let registry = Registry::default();
+        let options = UnpackOptions::default();
+        replace(&registry, &path, "PACH/1", &[3; 8], &options).unwrap();
+        let same_size = read_file(&path).unwrap();
+        replace(&registry, &path, "PACH/1", &[4; 0x300], &options).unwrap();
+        let grown = read_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // only the nested entry's bytes change
+        let changed: Vec<_> = (0..original.len())
+            .filter(|&i| original[i] != same_size[i])
+            .collect();
+        assert_eq!(same_size.len(), original.len());
+        assert_eq!(changed.len(), 8);
+        let entry_len = |data: &[u8]| {
+            let archive = EpacArchive::read_from(&mut std::io::Cursor::new(data)).unwrap();
+            archive.sections[0].entries[0].data.len()
+        };
+        assert_eq!(entry_len(&same_size), 0x200);
+        // grown past its padding, the entry is only padded to the next 256 bytes
+        assert_eq!(entry_len(&grown), 0x400);
+    }
+}
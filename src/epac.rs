@@ -1,12 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::fs::{create_dir_all, File};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
-use crate::{create_file_to_write, read_exact, write_padding_zeroes, FileInfo, PackedFileInfo};
+use crate::list::{ListDivider, ListEntry, ListSection, Listing};
+use crate::manifest::{self, bytes_to_string, string_to_bytes, Table, Value};
+use crate::{
+    bpe, check_bounds, check_magic_num, check_output_filename, create_dir_all,
+    create_file_to_write, has_magic_num, is_after, open_file, pach, read_exact, read_file,
+    shift_offset, splice_packed_file, tex, write_file, write_padding_zeroes, Endian, Error,
+    PackOptions, PackedFileInfo, Result, UnpackOptions,
+};
 
-// EPAC (align=0x800)
+// EPAC (align=0x800), little-endian on PS2 and big-endian on Xbox 360 / PS3
 // header: len=0x4000
 // [magic_num: u32][?: u32][size: u32][reserved(?): u32]
 // entry info (from 0x800): [E???][?: u32][offset_of_next_file: u32]
@@ -15,306 +21,1310 @@ use crate::{create_file_to_write, read_exact, write_padding_zeroes, FileInfo, Pa
 // footer: len=0x800
 
 const MAGIC_NUM: &[u8; 4] = b"EPAC";
-const ALIGN_SIZE: usize = 2048;
+const ALIGN_SIZE: u64 = 2048;
 const RESERVED: &[u8; 4] = b"\x07\x00\x00\x00";
+const ENTRY_INFO_OFFSET: u64 = 0x800;
+const DATA_OFFSET: u64 = 0x4000;
+/// The entry table, with dividers and the zero row ending it, must end before the data.
+const MAX_TABLE_ROW_NUM: usize = ((DATA_OFFSET - ENTRY_INFO_OFFSET) / 12) as usize - 1;
 
-const FOOTER1: &[u8; 16] = b"EOP5/1.10\x00\x00\x00\x00\x00\x00\x00";
+const ENTRY_MANIFEST: &str = "__entry__.toml";
+const LEGACY_ENTRY_LIST: &str = "__entry__";
+const ENTRY_MANIFEST_COMMENT: &str = "EPAC entry list written by `rr-mod-tool -u`.
+`endian` is the byte order of the archive, \"little\" or \"big\", and unknown fields
+are words in that order. Entries are packed in the order listed, each
+section's entries following its divider; `file` names the file holding an entry.
+`padded_len` is the length in the original table when the file has been trimmed.
+`header_padding`, `table_padding` and `footer_padding`, when present, hold bytes
+that are zero in every known archive, one char per byte.";
 
-struct DividerInfo {
-    name: [u8; 4],
-    divider_unknown_field: [u8; 4],
+const FOOTER_TAG: &[u8; 16] = b"EOP5/1.10\x00\x00\x00\x00\x00\x00\x00";
+
+/// An EPAC archive held in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpacArchive {
+    pub endian: Endian,
+    /// The header word following the magic number.
+    pub header_unknown_field: [u8; 4],
+    /// The last word of the 16-byte header.
+    pub reserved: [u8; 4],
+    /// The zero-padded version string at the start of the footer.
+    pub footer_tag: [u8; 16],
+    /// The word at the start of the footer's second half.
+    pub footer_unknown_field: [u8; 4],
+    /// Header bytes 16..0x800, without trailing zeroes; empty in every known file.
+    pub header_padding: Vec<u8>,
+    /// The bytes from the zero row ending the entry table up to 0x4000, without
+    /// trailing zeroes. Cut short if the table grows into them.
+    pub table_padding: Vec<u8>,
+    /// The footer bytes after the tag and after the unknown word, one run after the
+    /// other and without trailing zeroes.
+    pub footer_padding: Vec<u8>,
+    pub sections: Vec<EpacSection>,
 }
 
-enum EntryInfo {
-    Divider(DividerInfo),
-    PackedFile(PackedFileInfo),
-    File(FileInfo),
+/// A divider together with the entries listed after it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EpacSection {
+    /// `None` for entries listed before the first divider.
+    pub divider: Option<EpacDivider>,
+    pub entries: Vec<EpacEntry>,
 }
 
-pub fn detect_format<P: AsRef<Path>>(path: P) -> bool {
-    let mut file = File::open(path).unwrap();
-    let buf = read_exact!(&mut file, 4);
-    &buf == MAGIC_NUM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpacDivider {
+    pub name: [u8; 4],
+    pub unknown_field: [u8; 4],
 }
 
-pub fn pack(src_path: PathBuf, dst_path: PathBuf) {
-    let mut header_unknown_field = [0u8; 4];
-    let mut footer_unknown_field = [0u8; 4];
-    let mut entry_info_list = vec![];
-    {
-        let file = File::open(src_path.join("__entry__")).unwrap();
-        let mut reader = BufReader::new(file);
-        reader.read_exact(&mut header_unknown_field).unwrap();
-        reader.read_exact(&mut footer_unknown_field).unwrap();
-
-        let mut buf = vec![];
-        reader.read_to_end(&mut buf).unwrap();
-        let len = buf.len() / 4;
-        assert_eq!(buf.len() % 4, 0);
-
-        let mut sn_map: HashMap<String, u32> = HashMap::new(); // to handle multiple file of same name
-        let mut i = 0;
-        while i < len {
-            let start = i * 4;
-            let end = (i + 1) * 4;
-            let mut name = [0u8; 4];
-            name.clone_from_slice(&buf[start..end]);
-            if &name[..] == [0, 0, 0, 0] {
-                assert!(i + 2 < len);
-                let mut divider_name = [0u8; 4];
-                divider_name.clone_from_slice(&buf[((i + 1) * 4)..((i + 2) * 4)]);
-                let mut divider_unknown_field = [0u8; 4];
-                divider_unknown_field.clone_from_slice(&buf[((i + 2) * 4)..((i + 3) * 4)]);
-                entry_info_list.push(EntryInfo::Divider(DividerInfo {
-                    name: divider_name,
-                    divider_unknown_field,
-                }));
-                i += 3;
-            } else {
-                let raw_name = String::from_utf8_lossy(&name).to_string();
-                let name = raw_name.trim();
-                let path = match sn_map.get_mut(name) {
-                    None => {
-                        sn_map.insert(name.to_string(), 0);
-                        src_path.join(&name)
-                    }
-                    Some(value) => {
-                        *value += 1;
-                        src_path.join(format!("{}.{}", name, value))
-                    }
-                };
-                let len = File::open(path).unwrap().metadata().unwrap().len();
-                let padding_zero_num = {
-                    let rem = len % ALIGN_SIZE as u64;
-                    if rem == 0 {
-                        0
-                    } else {
-                        ALIGN_SIZE as u64 - rem
-                    }
-                };
-                entry_info_list.push(EntryInfo::File(FileInfo {
-                    path: PathBuf::from(raw_name), // will be fixed after writing entry info
-                    len,
-                    padding_zero_num,
-                }));
-                i += 1;
-            }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpacEntry {
+    /// Entry name, may be padded with spaces.
+    pub name: [u8; 4],
+    pub data: Vec<u8>,
+    /// The length recorded in the entry table, a multiple of 256.
+    ///
+    /// Kept when the data has been trimmed to its exact length so that the table is
+    /// written back unchanged; ignored if the data has grown beyond it.
+    pub padded_len: Option<u32>,
+}
+
+impl Default for EpacArchive {
+    fn default() -> Self {
+        Self {
+            endian: Endian::Little,
+            header_unknown_field: [0; 4],
+            reserved: *RESERVED,
+            footer_tag: *FOOTER_TAG,
+            footer_unknown_field: [0; 4],
+            header_padding: vec![],
+            table_padding: vec![],
+            footer_padding: vec![],
+            sections: vec![],
         }
     }
-    assert!(!entry_info_list.is_empty());
+}
 
-    let file = create_file_to_write(dst_path);
-    let mut writer = BufWriter::new(file);
-    writer.write_all(MAGIC_NUM).unwrap();
-    writer.write_all(&header_unknown_field).unwrap();
-
-    let mut size = 0;
-    for info in &entry_info_list {
-        if let EntryInfo::File(info) = info {
-            size = u32::checked_add(
-                size,
-                u32::try_from(info.len + info.padding_zero_num).unwrap(),
-            )
-            .unwrap();
+impl EpacArchive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty archive in the given byte order, with the usual reserved word.
+    pub fn with_endian(endian: Endian) -> Self {
+        Self {
+            endian,
+            reserved: endian.words_from_le(RESERVED),
+            ..Self::default()
+        }
+    }
+
+    /// Reads an archive, detecting its byte order from the header.
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        Self::read_from_endian(reader, None)
+    }
+
+    /// Like [`read_from`](Self::read_from), in the given byte order or the detected one.
+    pub fn read_from_endian<R: Read + Seek>(
+        reader: &mut R,
+        endian: Option<Endian>,
+    ) -> Result<Self> {
+        let (mut archive, file_info_list) = read_table(reader, endian)?;
+
+        let size = reader.seek(SeekFrom::End(0))?;
+        let entries = archive.sections.iter_mut().flat_map(|s| &mut s.entries);
+        for (entry, info) in entries.zip(&file_info_list) {
+            check_bounds(info.offset, info.len, size)?;
+            reader.seek(SeekFrom::Start(info.offset))?;
+            entry.data = vec![0u8; info.len as _];
+            reader.read_exact(&mut entry.data)?;
         }
+        Ok(archive)
     }
-    writer.write_all(&size.to_le_bytes()).unwrap();
-    writer.write_all(RESERVED).unwrap();
 
-    // write 0 until 0x800;
-    write_padding_zeroes(&mut writer, 0x800 - 16);
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.entries().next().is_none() {
+            return Err(Error::Empty);
+        }
+        if self.row_num() > MAX_TABLE_ROW_NUM {
+            return Err(Error::TooLarge);
+        }
+
+        writer.write_all(MAGIC_NUM)?;
+        writer.write_all(&self.header_unknown_field)?;
+
+        let mut size = 0u32;
+        for entry in self.entries() {
+            size = u32::try_from(block_num(entry) * ALIGN_SIZE)
+                .ok()
+                .and_then(|len| size.checked_add(len))
+                .ok_or(Error::TooLarge)?;
+        }
+        let endian = self.endian;
+        writer.write_all(&endian.u32_to_bytes(size))?;
+        writer.write_all(&self.reserved)?;
 
-    // write entry info
-    let mut sn_map: HashMap<String, u32> = HashMap::new(); // to handle multiple file of same name
-    let mut offset_of_2k_block = 0u32;
-    for info in &mut entry_info_list {
-        match info {
-            EntryInfo::Divider(info) => {
-                writer.write_all(&info.name).unwrap();
-                writer.write_all(&info.divider_unknown_field).unwrap();
-                let offset = offset_of_2k_block;
-                writer.write_all(&offset.to_le_bytes()).unwrap();
+        // write header padding until 0x800;
+        write_padded(
+            writer,
+            &self.header_padding,
+            ENTRY_INFO_OFFSET as usize - 16,
+        )?;
+
+        // write entry info
+        let mut pos = ENTRY_INFO_OFFSET;
+        let mut offset_of_2k_block = 0u32;
+        for section in &self.sections {
+            if let Some(divider) = &section.divider {
+                check_divider(divider, offset_of_2k_block as _, endian)?;
+                writer.write_all(&divider.name)?;
+                writer.write_all(&divider.unknown_field)?;
+                writer.write_all(&endian.u32_to_bytes(offset_of_2k_block))?;
+                pos += 12;
             }
-            EntryInfo::File(info) => {
-                let raw_name = info.path.to_string_lossy();
-                let raw_name_bytes = raw_name.as_bytes();
-                writer.write_all(raw_name_bytes).unwrap();
-
-                let filename = raw_name.trim().to_string();
-                info.path = match sn_map.get_mut(&filename) {
-                    None => {
-                        sn_map.insert(filename.clone(), 0);
-                        src_path.join(filename)
-                    }
-                    Some(value) => {
-                        *value += 1;
-                        src_path.join(format!("{}.{}", filename, value))
-                    }
-                };
-
-                let offset = offset_of_2k_block;
-                writer.write_all(&offset.to_le_bytes()).unwrap();
-
-                assert_eq!((info.len + info.padding_zero_num) % 2048, 0);
-                let rem = info.len % 256;
-                let mut len = u32::try_from(info.len / 256).unwrap();
-                if rem > 0 {
-                    len += 1;
-                }
-                writer.write_all(&len.to_le_bytes()).unwrap();
+            for entry in &section.entries {
+                writer.write_all(&entry.name)?;
+                writer.write_all(&endian.u32_to_bytes(offset_of_2k_block))?;
 
-                offset_of_2k_block +=
-                    u32::try_from((info.len + info.padding_zero_num) / 2048).unwrap();
+                let len = u32::try_from(table_len(entry) / 256).map_err(|_| Error::TooLarge)?;
+                writer.write_all(&endian.u32_to_bytes(len))?;
+                pos += 12;
+
+                offset_of_2k_block = u32::try_from(block_num(entry))
+                    .ok()
+                    .and_then(|len| offset_of_2k_block.checked_add(len))
+                    .ok_or(Error::TooLarge)?;
             }
-            _ => unreachable!(),
         }
+
+        // write table padding until 0x4000, starting with the zero row;
+        if self.table_padding.iter().take(4).any(|b| *b != 0) {
+            return Err(Error::Malformed(
+                "table padding must start with a zero word".to_string(),
+            ));
+        }
+        let room = (DATA_OFFSET - pos) as usize;
+        let table_padding = &self.table_padding[..self.table_padding.len().min(room)];
+        write_padded(writer, table_padding, room)?;
+
+        // write data
+        for entry in self.entries() {
+            writer.write_all(&entry.data)?;
+            let zero_num = block_num(entry) * ALIGN_SIZE - entry.data.len() as u64;
+            write_padding_zeroes(writer, zero_num as _)?;
+        }
+
+        // write footer
+        let (first, second) = self
+            .footer_padding
+            .split_at(self.footer_padding.len().min(0x400 - 16));
+        writer.write_all(&self.footer_tag)?;
+        write_padded(writer, first, 0x400 - 16)?;
+        writer.write_all(&self.footer_unknown_field)?;
+        write_padded(writer, second, 0x400 - 4)?;
+        Ok(())
     }
 
-    // write 0 until 0x4000;
-    let pos = writer.seek(SeekFrom::Current(0)).unwrap();
-    write_padding_zeroes(&mut writer, (0x4000 - pos) as _);
+    /// Iterates over all entries in table order.
+    pub fn entries(&self) -> impl Iterator<Item = &EpacEntry> {
+        self.sections.iter().flat_map(|s| &s.entries)
+    }
 
-    // write data
-    for info in entry_info_list {
-        if let EntryInfo::File(info) = info {
-            let mut file = File::open(info.path).unwrap();
-            let mut vec = vec![];
-            file.read_to_end(&mut vec).unwrap();
-            writer.write_all(&vec).unwrap();
-            write_padding_zeroes(&mut writer, info.padding_zero_num as _);
+    /// Appends an entry to the section of the divider named `divider`, or to the
+    /// entries before the first divider. Fails if the entry table would reach 0x4000.
+    pub fn add_entry(&mut self, divider: Option<&[u8; 4]>, entry: EpacEntry) -> Result<()> {
+        if self.row_num() + 1 > MAX_TABLE_ROW_NUM {
+            return Err(Error::TooLarge);
         }
+        let section = match divider {
+            Some(name) => self
+                .sections
+                .iter_mut()
+                .find(|s| s.divider.as_ref().is_some_and(|d| d.name == *name))
+                .ok_or_else(|| Error::NotFound(bytes_to_string(name)))?,
+            None => {
+                if self.sections.first().is_none_or(|s| s.divider.is_some()) {
+                    self.sections.insert(0, EpacSection::default());
+                }
+                &mut self.sections[0]
+            }
+        };
+        section.entries.push(entry);
+        Ok(())
     }
 
-    // write footer
-    writer.write_all(FOOTER1).unwrap();
-    for _ in 16..0x400 {
-        writer.write_all(b"\x00").unwrap();
+    /// The number of rows in the entry table, dividers included.
+    fn row_num(&self) -> usize {
+        self.sections
+            .iter()
+            .map(|s| s.divider.is_some() as usize + s.entries.len())
+            .sum()
     }
-    writer.write_all(&footer_unknown_field).unwrap();
-    for _ in 4..0x400 {
-        writer.write_all(b"\x00").unwrap();
+
+    /// Removes the entry at `index` in table order, dividers not counted.
+    pub fn remove_entry(&mut self, index: usize) -> Option<EpacEntry> {
+        let mut index = index;
+        for section in &mut self.sections {
+            if index < section.entries.len() {
+                return Some(section.entries.remove(index));
+            }
+            index -= section.entries.len();
+        }
+        None
     }
 }
 
-pub fn unpack(src_path: PathBuf, dst_path: PathBuf) {
-    let file = File::open(src_path).unwrap();
-    let mut reader = BufReader::new(file);
+impl EpacEntry {
+    /// Creates an entry from its name of exactly 4 bytes, padding spaces included.
+    pub fn new(name: &str, data: Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            name: name_from_str(name)?,
+            data,
+            padded_len: None,
+        })
+    }
 
-    let buf = read_exact!(&mut reader, 4);
-    assert_eq!(&buf, MAGIC_NUM);
+    /// The entry name with the space padding removed.
+    pub fn name_str(&self) -> String {
+        String::from_utf8_lossy(&self.name).trim().to_string()
+    }
+}
 
-    let buf = read_exact!(&mut reader, 4);
-    let header_unknown_field = u32::from_le_bytes(buf);
+/// Checks that the divider still reads as one at the block offset `offset_of_2k_block`:
+/// the table tells dividers from entries by an unknown field other than that offset.
+fn check_divider(divider: &EpacDivider, offset_of_2k_block: u64, endian: Endian) -> Result<()> {
+    if endian.u32_from_bytes(divider.unknown_field) as u64 == offset_of_2k_block {
+        return Err(Error::Unsupported(format!(
+            "divider {:?} would read as an entry, its unknown field being its block offset {:#x}",
+            String::from_utf8_lossy(&divider.name),
+            offset_of_2k_block
+        )));
+    }
+    Ok(())
+}
 
-    reader.seek(SeekFrom::End(-0x400)).unwrap();
-    let buf = read_exact!(&mut reader, 4);
-    let footer_unknown_field = u32::from_le_bytes(buf);
+/// The length written to the entry table, in bytes.
+#[inline]
+fn table_len(entry: &EpacEntry) -> u64 {
+    let len = entry.data.len() as u64;
+    match entry.padded_len {
+        Some(padded_len) if padded_len as u64 >= len => (padded_len as u64).div_ceil(256) * 256,
+        _ => len.div_ceil(256) * 256,
+    }
+}
+
+/// The number of 2 KiB blocks taken by the entry's data.
+#[inline]
+fn block_num(entry: &EpacEntry) -> u64 {
+    table_len(entry).div_ceil(ALIGN_SIZE)
+}
+
+/// The byte order of a header: the reserved word is 7 in every known file, otherwise
+/// the data size is used.
+#[inline]
+fn header_endian(data_size: [u8; 4], reserved: [u8; 4]) -> Endian {
+    if reserved == *RESERVED {
+        Endian::Little
+    } else if reserved == Endian::Big.words_from_le(RESERVED) {
+        Endian::Big
+    } else {
+        Endian::guess(data_size)
+    }
+}
+
+/// Returns the byte order of the EPAC archive `data`, detected from its header.
+pub fn detect_endian(data: &[u8]) -> Option<Endian> {
+    if !detect(data) || data.len() < 16 {
+        return None;
+    }
+    let mut data_size = [0u8; 4];
+    data_size.copy_from_slice(&data[8..12]);
+    let mut reserved = [0u8; 4];
+    reserved.copy_from_slice(&data[12..16]);
+    Some(header_endian(data_size, reserved))
+}
+
+/// Guesses the exact length of entry data padded to 256 bytes, by looking for a BPE,
+/// PACH or TEX file at its start.
+pub fn exact_len(data: &[u8]) -> Option<usize> {
+    bpe::exact_len(data)
+        .or_else(|| pach::exact_len(data))
+        .or_else(|| tex::exact_len(data))
+}
+
+/// Trims entry data to its exact length when it can be found and only zeroes follow;
+/// returns whether it was trimmed.
+pub fn trim_entry(data: &mut Vec<u8>) -> bool {
+    match exact_len(data) {
+        Some(len) if data[len..].iter().all(|b| *b == 0) => {
+            data.truncate(len);
+            true
+        }
+        _ => false,
+    }
+}
 
-    reader.seek(SeekFrom::Start(0x800)).unwrap();
-    let mut offset_of_2k_block = 0;
-    let mut entry_info_list = vec![];
+/// Reads the header and entry table in the given byte order, or the one detected from
+/// the header.
+fn read_table<R: Read + Seek>(
+    reader: &mut R,
+    endian: Option<Endian>,
+) -> Result<(EpacArchive, Vec<PackedFileInfo>)> {
+    let buf = read_exact!(reader, 4);
+    check_magic_num(buf, MAGIC_NUM)?;
+
+    let header_unknown_field = read_exact!(reader, 4);
+    let data_size = read_exact!(reader, 4);
+    let reserved = read_exact!(reader, 4);
+    let endian = endian.unwrap_or_else(|| header_endian(data_size, reserved));
+
+    let size = reader.seek(SeekFrom::End(0))?;
+    if size < DATA_OFFSET + 0x800 {
+        return Err(Error::Truncated);
+    }
+    reader.seek(SeekFrom::Start(16))?;
+    let mut header_padding = vec![0u8; ENTRY_INFO_OFFSET as usize - 16];
+    reader.read_exact(&mut header_padding)?;
+
+    reader.seek(SeekFrom::End(-0x800))?;
+    let mut footer = vec![0u8; 0x800];
+    reader.read_exact(&mut footer)?;
+    let mut footer_tag = [0u8; 16];
+    footer_tag.copy_from_slice(&footer[..16]);
+    let mut footer_unknown_field = [0u8; 4];
+    footer_unknown_field.copy_from_slice(&footer[0x400..0x404]);
+    let mut footer_padding = footer[16..0x400].to_vec();
+    footer_padding.extend_from_slice(&footer[0x404..]);
+
+    let mut archive = EpacArchive {
+        endian,
+        header_unknown_field,
+        reserved,
+        footer_tag,
+        footer_unknown_field,
+        header_padding: trim_trailing_zeroes(header_padding),
+        table_padding: vec![],
+        footer_padding: trim_trailing_zeroes(footer_padding),
+        sections: vec![],
+    };
+    let mut file_info_list = vec![];
+
+    reader.seek(SeekFrom::Start(ENTRY_INFO_OFFSET))?;
+    let mut pos = ENTRY_INFO_OFFSET;
+    let mut offset_of_2k_block = 0u64;
     loop {
-        let buf = read_exact!(&mut reader, 12);
-        if &buf[..4] == [0, 0, 0, 0] {
+        if pos + 12 > DATA_OFFSET {
+            return Err(Error::Malformed(
+                "entry table isn't terminated before 0x4000".to_string(),
+            ));
+        }
+        let buf = read_exact!(reader, 12);
+        pos += 12;
+        if buf[..4] == [0, 0, 0, 0] {
+            let mut table_padding = buf.to_vec();
+            table_padding.resize((DATA_OFFSET - pos + 12) as _, 0);
+            reader.read_exact(&mut table_padding[12..])?;
+            archive.table_padding = trim_trailing_zeroes(table_padding);
             break;
         }
 
+        let mut name = [0u8; 4];
+        name.clone_from_slice(&buf[..4]);
+
         let mut maybe_offset = [0u8; 4];
         maybe_offset.clone_from_slice(&buf[4..8]);
-        let maybe_offset = u32::from_le_bytes(maybe_offset);
+        let maybe_offset = endian.u32_from_bytes(maybe_offset) as u64;
         if maybe_offset != offset_of_2k_block {
-            let mut name = [0u8; 4];
-            name.clone_from_slice(&buf[..4]);
-
-            let mut divider_unknown_field = [0u8; 4];
-            divider_unknown_field.clone_from_slice(&buf[4..8]);
+            let mut unknown_field = [0u8; 4];
+            unknown_field.clone_from_slice(&buf[4..8]);
 
-            entry_info_list.push(EntryInfo::Divider(DividerInfo {
-                name,
-                divider_unknown_field,
-            }));
+            archive.sections.push(EpacSection {
+                divider: Some(EpacDivider {
+                    name,
+                    unknown_field,
+                }),
+                entries: vec![],
+            });
         } else {
-            let mut file_no = [0u8; 4];
-            file_no.clone_from_slice(&buf[..4]);
-
             let offset = maybe_offset;
-            let abs_offset = offset * 2048 + 0x4000;
+            let abs_offset = offset * ALIGN_SIZE + DATA_OFFSET;
 
             let mut len = [0u8; 4];
             len.clone_from_slice(&buf[8..]);
-            let len = u32::from_le_bytes(len);
+            let len = endian.u32_from_bytes(len) as u64;
             let abs_len = len * 256;
 
-            entry_info_list.push(EntryInfo::PackedFile(PackedFileInfo {
-                filename: String::from_utf8_lossy(&file_no).to_string(),
-                offset: abs_offset as _,
-                len: abs_len as _,
-            }));
-
-            offset_of_2k_block += abs_len / 2048;
-            if abs_len % 2048 != 0 {
-                offset_of_2k_block += 1;
+            if archive.sections.is_empty() {
+                archive.sections.push(EpacSection::default());
             }
+            let section = archive.sections.last_mut().unwrap();
+            section.entries.push(EpacEntry {
+                name,
+                data: vec![],
+                padded_len: None,
+            });
+            file_info_list.push(PackedFileInfo {
+                filename: String::from_utf8_lossy(&name).to_string(),
+                offset: abs_offset,
+                len: abs_len,
+            });
+
+            offset_of_2k_block += abs_len.div_ceil(ALIGN_SIZE);
         }
     }
 
-    assert!(!entry_info_list.is_empty());
-    create_dir_all(&dst_path).unwrap();
+    if file_info_list.is_empty() {
+        return Err(Error::Empty);
+    }
+    Ok((archive, file_info_list))
+}
 
-    // write entry info
-    {
-        let path = dst_path.join("__entry__");
-        let file = create_file_to_write(path);
-        let mut writer = BufWriter::new(file);
-        writer
-            .write_all(&header_unknown_field.to_le_bytes())
-            .unwrap();
-        writer
-            .write_all(&footer_unknown_field.to_le_bytes())
-            .unwrap();
-        for info in &entry_info_list {
-            match info {
-                EntryInfo::Divider(info) => {
-                    writer.write_all(&[0, 0, 0, 0]).unwrap();
-                    writer.write_all(&info.name).unwrap();
-                    writer.write_all(&info.divider_unknown_field).unwrap();
+pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<bool> {
+    has_magic_num(path, MAGIC_NUM)
+}
+
+pub fn detect(data: &[u8]) -> bool {
+    data.starts_with(MAGIC_NUM)
+}
+
+/// Maps entry names to file names, suffixing `.1`, `.2`, .. to repeated names.
+#[derive(Default)]
+struct FilenameMap {
+    sn_map: HashMap<String, u32>, // to handle multiple file of same name
+    used: HashSet<String>,
+}
+
+impl FilenameMap {
+    fn next(&mut self, name: &str) -> String {
+        // name may contain spaces (0x20)
+        let name = name.trim();
+        loop {
+            let filename = match self.sn_map.get_mut(name) {
+                None => {
+                    self.sn_map.insert(name.to_string(), 0);
+                    name.to_string()
                 }
-                EntryInfo::PackedFile(info) => {
-                    let name = info.filename.as_bytes();
-                    assert_eq!(name.len(), 4);
-                    writer.write_all(name).unwrap();
+                Some(value) => {
+                    *value += 1;
+                    format!("{}.{}", name, value)
                 }
-                _ => unreachable!(),
+            };
+            // an entry named e.g. "A.1" takes the name of the second "A"
+            if self.used.insert(filename.clone()) {
+                return filename;
             }
         }
     }
 
-    // extract files
-    let mut sn_map: HashMap<String, u32> = HashMap::new(); // to handle multiple file of same name
-    for info in entry_info_list {
-        if let EntryInfo::PackedFile(info) = info {
-            reader.seek(SeekFrom::Start(info.offset as u64)).unwrap();
+    /// Records a file name given explicitly, which no other entry may use.
+    fn insert(&mut self, filename: &str) -> Result<()> {
+        if !self.used.insert(filename.to_string()) {
+            return Err(Error::Duplicate(filename.to_string()));
+        }
+        Ok(())
+    }
+}
 
-            // name may contain spaces (0x20)
-            let filename = info.filename.trim();
-            let output_path = match sn_map.get_mut(filename) {
-                None => {
-                    sn_map.insert(filename.to_string(), 0);
-                    dst_path.join(filename)
+/// Names the field of the EPAC archive `data` that `offset` falls in.
+pub fn field_at(data: &[u8], offset: u64) -> Option<String> {
+    let size = data.len() as u64;
+    let field = match offset {
+        0..=3 => "magic number",
+        4..=7 => "header unknown field",
+        8..=11 => "data size",
+        12..=15 => "reserved",
+        16..=0x7ff => "header padding",
+        _ => "",
+    };
+    if !field.is_empty() {
+        return Some(field.to_string());
+    }
+    if size >= DATA_OFFSET + 0x800 && offset >= size - 0x800 {
+        let field = match offset - (size - 0x800) {
+            0..=15 => "footer tag",
+            0x400..=0x403 => "footer unknown field",
+            _ => "footer padding",
+        };
+        return Some(field.to_string());
+    }
+
+    let (archive, file_info_list) = read_table(&mut Cursor::new(data), None).ok()?;
+    if offset < DATA_OFFSET {
+        let mut rows = vec![];
+        for section in &archive.sections {
+            if let Some(divider) = &section.divider {
+                rows.push(format!("divider {:?}", bytes_to_string(&divider.name)));
+            }
+            for entry in &section.entries {
+                rows.push(format!("entry {:?}", bytes_to_string(&entry.name)));
+            }
+        }
+        let row = ((offset - ENTRY_INFO_OFFSET) / 12) as usize;
+        return match rows.get(row) {
+            Some(name) => Some(format!("entry table row {}, {}", row, name)),
+            None => Some("entry table padding".to_string()),
+        };
+    }
+    for info in &file_info_list {
+        if offset >= info.offset && offset < info.offset + info.len {
+            return Some(format!("data of {}", info.filename));
+        }
+    }
+    Some("data padding".to_string())
+}
+
+pub fn pack(src_path: PathBuf, dst_path: PathBuf, options: &PackOptions) -> Result<()> {
+    let manifest_path = src_path.join(ENTRY_MANIFEST);
+    let (mut archive, filename_list) = if manifest_path.exists() {
+        read_entry_manifest(&manifest_path, options.endian)?
+    } else {
+        read_legacy_entry_list(&src_path.join(LEGACY_ENTRY_LIST), options.endian)?
+    };
+    let entries = archive.sections.iter_mut().flat_map(|s| &mut s.entries);
+    for (entry, filename) in entries.zip(&filename_list) {
+        entry.data = read_file(src_path.join(filename))?;
+    }
+    if options.drop_empty {
+        for section in &mut archive.sections {
+            section.entries.retain(|entry| !entry.data.is_empty());
+        }
+    }
+
+    if let Some(dst_dir) = dst_path.parent() {
+        create_dir_all(dst_dir)?;
+    }
+    let file = create_file_to_write(&dst_path)?;
+    let mut writer = BufWriter::new(file);
+    archive.write_to(&mut writer)?;
+    writer.flush().map_err(|e| Error::io(&dst_path, e))
+}
+
+/// Lists the dividers and entries of the archive `src_path` without reading their data.
+/// Entries are named as `unpack` names their files.
+pub fn list(src_path: &Path, options: &UnpackOptions) -> Result<Listing> {
+    let file = open_file(src_path)?;
+    list_from(&mut BufReader::new(file), options.endian)
+}
+
+/// Like [`list`], reading the archive from `reader` in the given byte order, or the
+/// detected one.
+pub fn list_from<R: Read + Seek>(reader: &mut R, endian: Option<Endian>) -> Result<Listing> {
+    let (archive, file_info_list) = read_table(reader, endian)?;
+    let endian = archive.endian;
+
+    let mut filename_map = FilenameMap::default();
+    let mut file_info_list = file_info_list.into_iter().enumerate();
+    let mut listing = Listing::new("epac", endian);
+    listing.header = vec![
+        (
+            "header_unknown_field".to_string(),
+            endian.u32_from_bytes(archive.header_unknown_field) as u64,
+        ),
+        (
+            "reserved".to_string(),
+            endian.u32_from_bytes(archive.reserved) as u64,
+        ),
+        (
+            "footer_unknown_field".to_string(),
+            endian.u32_from_bytes(archive.footer_unknown_field) as u64,
+        ),
+    ];
+    for section in &archive.sections {
+        let mut list_section = ListSection {
+            divider: section.divider.as_ref().map(|divider| ListDivider {
+                name: bytes_to_string(&divider.name),
+                unknown_field: endian.u32_from_bytes(divider.unknown_field),
+            }),
+            entries: vec![],
+        };
+        for (i, mut info) in file_info_list.by_ref().take(section.entries.len()) {
+            info.filename = filename_map.next(&info.filename);
+            let padding = info.len.div_ceil(ALIGN_SIZE) * ALIGN_SIZE - info.len;
+            list_section
+                .entries
+                .push(ListEntry::from_info(i, &info, padding));
+        }
+        listing.sections.push(list_section);
+    }
+    Ok(listing)
+}
+
+/// Replaces the data of entry `index` in the archive `data`, read in the given byte
+/// order or the detected one. The entries after it keep their bytes and move by whole
+/// 2 KiB blocks; in the table only its length, the block offsets after it and the
+/// header's data size change.
+pub fn replace_entry(
+    data: &mut Vec<u8>,
+    index: usize,
+    new_data: &[u8],
+    endian: Option<Endian>,
+) -> Result<()> {
+    let (archive, file_info_list) = read_table(&mut Cursor::new(&data[..]), endian)?;
+    let endian = archive.endian;
+    let info = file_info_list
+        .get(index)
+        .ok_or_else(|| Error::NotFound(format!("#{}", index)))?;
+    let new_entry = EpacEntry {
+        name: [0; 4],
+        data: new_data.to_vec(),
+        padded_len: None,
+    };
+    let len = u32::try_from(table_len(&new_entry) / 256).map_err(|_| Error::TooLarge)?;
+    // the old data is followed by zeroes up to its 2 KiB block
+    let old_len = info.len.div_ceil(ALIGN_SIZE) * ALIGN_SIZE;
+    let delta = splice_packed_file(data, info.offset, old_len, new_data, ALIGN_SIZE)?;
+    let block_delta = delta / ALIGN_SIZE as i64;
+
+    let mut row = ENTRY_INFO_OFFSET as usize;
+    let mut i = 0;
+    let mut size = 0u64;
+    for section in &archive.sections {
+        if let Some(divider) = &section.divider {
+            // the divider holds the block offset of the entry following it, or the end
+            let moves = match file_info_list.get(i) {
+                Some(next) => is_after(i, next.offset, index, info.offset),
+                None => true,
+            };
+            if moves {
+                let mut buf = [0u8; 4];
+                buf.copy_from_slice(&data[row + 8..row + 12]);
+                let offset = shift_offset(endian.u32_from_bytes(buf) as u64, block_delta)?;
+                check_divider(divider, offset as _, endian)?;
+                data[row + 8..row + 12].copy_from_slice(&endian.u32_to_bytes(offset));
+            }
+            row += 12;
+        }
+        for _ in &section.entries {
+            let other = &file_info_list[i];
+            if i == index {
+                data[row + 8..row + 12].copy_from_slice(&endian.u32_to_bytes(len));
+                size += block_num(&new_entry) * ALIGN_SIZE;
+            } else {
+                if is_after(i, other.offset, index, info.offset) {
+                    let offset = (other.offset - DATA_OFFSET) / ALIGN_SIZE;
+                    let offset = shift_offset(offset, block_delta)?;
+                    data[row + 4..row + 8].copy_from_slice(&endian.u32_to_bytes(offset));
                 }
-                Some(value) => {
-                    *value += 1;
-                    dst_path.join(format!("{}.{}", filename, value))
+                size += other.len.div_ceil(ALIGN_SIZE) * ALIGN_SIZE;
+            }
+            row += 12;
+            i += 1;
+        }
+    }
+    let size = u32::try_from(size).map_err(|_| Error::TooLarge)?;
+    data[8..12].copy_from_slice(&endian.u32_to_bytes(size));
+    Ok(())
+}
+
+/// Adds the entry `name` to the archive held in `data`, read in the given byte order or
+/// the detected one, at the end of the section of the divider `divider`, or of the
+/// entries before the first divider.
+pub fn add_entry(
+    data: &mut Vec<u8>,
+    name: &str,
+    entry_data: Vec<u8>,
+    divider: Option<&str>,
+    endian: Option<Endian>,
+) -> Result<()> {
+    let mut archive = EpacArchive::read_from_endian(&mut Cursor::new(&data[..]), endian)?;
+    let divider = divider.map(name_from_str).transpose()?;
+    archive.add_entry(divider.as_ref(), EpacEntry::new(name, entry_data)?)?;
+    data.clear();
+    archive.write_to(data)
+}
+
+/// Removes entry `index` from the archive held in `data`, read in the given byte order
+/// or the detected one; its divider stays.
+pub fn remove_entry(data: &mut Vec<u8>, index: usize, endian: Option<Endian>) -> Result<()> {
+    let mut archive = EpacArchive::read_from_endian(&mut Cursor::new(&data[..]), endian)?;
+    archive
+        .remove_entry(index)
+        .ok_or_else(|| Error::NotFound(format!("#{}", index)))?;
+    data.clear();
+    archive.write_to(data)
+}
+
+pub fn unpack(src_path: PathBuf, dst_path: PathBuf, options: &UnpackOptions) -> Result<()> {
+    let file = open_file(&src_path)?;
+    let mut reader = BufReader::new(file);
+    let (mut archive, mut file_info_list) = read_table(&mut reader, options.endian)?;
+
+    let mut filename_map = FilenameMap::default();
+    for info in &mut file_info_list {
+        info.filename = filename_map.next(&info.filename);
+    }
+
+    create_dir_all(&dst_path)?;
+
+    // extract files, trimmed to their exact length when it can be found
+    let size = reader.seek(SeekFrom::End(0))?;
+    let entries = archive.sections.iter_mut().flat_map(|s| &mut s.entries);
+    for (entry, info) in entries.zip(&file_info_list) {
+        check_output_filename(&info.filename)?;
+        check_bounds(info.offset, info.len, size)?;
+        reader.seek(SeekFrom::Start(info.offset))?;
+        let mut data = vec![0u8; info.len as _];
+        reader.read_exact(&mut data)?;
+
+        if trim_entry(&mut data) {
+            entry.padded_len = Some(u32::try_from(info.len).map_err(|_| Error::TooLarge)?);
+        }
+        write_file(dst_path.join(&info.filename), &data)?;
+    }
+
+    let filename_list: Vec<_> = file_info_list.iter().map(|i| i.filename.clone()).collect();
+    write_entry_manifest(&dst_path.join(ENTRY_MANIFEST), &archive, &filename_list)
+}
+
+/// Writes the header fields and entry table of `archive`, along with the file holding
+/// each entry, as a text manifest.
+fn write_entry_manifest(
+    path: &Path,
+    archive: &EpacArchive,
+    filename_list: &[String],
+) -> Result<()> {
+    let endian = archive.endian;
+    let mut manifest = Table::new();
+    manifest.set("endian", Value::String(endian.name().to_string()));
+    manifest.set(
+        "header_unknown_field",
+        hex_value(endian, archive.header_unknown_field),
+    );
+    manifest.set("reserved", hex_value(endian, archive.reserved));
+    let footer_tag = archive
+        .footer_tag
+        .iter()
+        .rposition(|b| *b != 0)
+        .map_or(0, |i| i + 1);
+    manifest.set(
+        "footer_tag",
+        Value::String(bytes_to_string(&archive.footer_tag[..footer_tag])),
+    );
+    manifest.set(
+        "footer_unknown_field",
+        hex_value(endian, archive.footer_unknown_field),
+    );
+    for (key, padding) in [
+        ("header_padding", &archive.header_padding),
+        ("table_padding", &archive.table_padding),
+        ("footer_padding", &archive.footer_padding),
+    ] {
+        if !padding.is_empty() {
+            manifest.set(key, Value::String(bytes_to_string(padding)));
+        }
+    }
+
+    let mut filenames = filename_list.iter();
+    for section in &archive.sections {
+        let table = manifest.push_table("section");
+        if let Some(divider) = &section.divider {
+            table.set("divider", Value::String(bytes_to_string(&divider.name)));
+            table.set("unknown_field", hex_value(endian, divider.unknown_field));
+        }
+        for entry in &section.entries {
+            let entry_table = table.push_table("entry");
+            entry_table.set("name", Value::String(bytes_to_string(&entry.name)));
+            if let Some(filename) = filenames.next() {
+                entry_table.set("file", Value::String(filename.clone()));
+            }
+            if let Some(padded_len) = entry.padded_len {
+                entry_table.set("padded_len", Value::Integer(padded_len as _));
+            }
+        }
+    }
+    manifest::write(path, ENTRY_MANIFEST_COMMENT, &manifest)
+}
+
+/// Reads a manifest written by [`write_entry_manifest`], returning the archive without
+/// entry data and the file holding each entry. `endian` overrides the recorded byte order.
+fn read_entry_manifest(path: &Path, endian: Option<Endian>) -> Result<(EpacArchive, Vec<String>)> {
+    let manifest = manifest::read(path)?;
+    let endian = match endian {
+        Some(endian) => endian,
+        None => manifest.opt_endian("endian")?.unwrap_or_default(),
+    };
+    let mut archive = EpacArchive {
+        endian,
+        header_unknown_field: endian.u32_to_bytes(manifest.u32("header_unknown_field")?),
+        reserved: match manifest.opt_u64("reserved")? {
+            Some(_) => endian.u32_to_bytes(manifest.u32("reserved")?),
+            None => endian.words_from_le(RESERVED),
+        },
+        footer_tag: match manifest.opt_str("footer_tag")? {
+            Some(s) => footer_tag_from_str(s)?,
+            None => *FOOTER_TAG,
+        },
+        footer_unknown_field: endian.u32_to_bytes(manifest.u32("footer_unknown_field")?),
+        header_padding: manifest
+            .opt_byte_string("header_padding")?
+            .unwrap_or_default(),
+        table_padding: manifest
+            .opt_byte_string("table_padding")?
+            .unwrap_or_default(),
+        footer_padding: manifest
+            .opt_byte_string("footer_padding")?
+            .unwrap_or_default(),
+        sections: vec![],
+    };
+    let mut filename_map = FilenameMap::default();
+    let mut filename_list = vec![];
+    for table in manifest.tables("section") {
+        let divider = match table.opt_str("divider")? {
+            Some(_) => Some(EpacDivider {
+                name: table.bytes("divider")?,
+                unknown_field: endian.u32_to_bytes(table.u32("unknown_field")?),
+            }),
+            None => None,
+        };
+        let mut section = EpacSection {
+            divider,
+            entries: vec![],
+        };
+        for entry_table in table.tables("entry") {
+            let name: [u8; 4] = entry_table.bytes("name")?;
+            let filename = match entry_table.opt_str("file")? {
+                Some(filename) => {
+                    filename_map.insert(filename)?;
+                    filename.to_string()
                 }
+                None => filename_map.next(&String::from_utf8_lossy(&name)),
+            };
+            let padded_len = match entry_table.opt_u64("padded_len")? {
+                Some(_) => Some(entry_table.u32("padded_len")?),
+                None => None,
+            };
+            check_output_filename(&filename)?;
+            filename_list.push(filename);
+            section.entries.push(EpacEntry {
+                name,
+                data: vec![],
+                padded_len,
+            });
+        }
+        archive.sections.push(section);
+    }
+    Ok((archive, filename_list))
+}
+
+/// Drops the zeroes at the end of a padding field, which are written back anyway.
+fn trim_trailing_zeroes(mut bytes: Vec<u8>) -> Vec<u8> {
+    let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    bytes.truncate(len);
+    bytes
+}
+
+/// Writes `bytes` followed by zeroes up to `len` bytes.
+fn write_padded<W: Write>(writer: &mut W, bytes: &[u8], len: usize) -> Result<()> {
+    if bytes.len() > len {
+        return Err(Error::TooLarge);
+    }
+    writer.write_all(bytes)?;
+    write_padding_zeroes(writer, len - bytes.len())
+}
+
+/// Parses an entry or divider name, exactly the 4 bytes stored, padding spaces
+/// included. A zero byte would end the entry table, so the name can't start with one.
+pub fn name_from_str(s: &str) -> Result<[u8; 4]> {
+    let bytes = string_to_bytes(s).ok_or_else(|| Error::InvalidName(s.to_string()))?;
+    if bytes.len() > 4 {
+        return Err(Error::NameTooLong {
+            name: s.to_string(),
+            max: 4,
+        });
+    }
+    let name = <[u8; 4]>::try_from(&bytes[..]).map_err(|_| Error::InvalidName(s.to_string()))?;
+    if name[0] == 0 || name[0] == b' ' {
+        return Err(Error::InvalidName(s.to_string()));
+    }
+    Ok(name)
+}
+
+/// Parses a footer tag, padding it with zeroes.
+fn footer_tag_from_str(s: &str) -> Result<[u8; 16]> {
+    let bytes = string_to_bytes(s).ok_or_else(|| Error::InvalidName(s.to_string()))?;
+    if bytes.len() > 16 {
+        return Err(Error::NameTooLong {
+            name: s.to_string(),
+            max: 16,
+        });
+    }
+    let mut footer_tag = [0u8; 16];
+    footer_tag[..bytes.len()].copy_from_slice(&bytes);
+    Ok(footer_tag)
+}
+
+/// Reads the binary `__entry__` file written by earlier versions:
+/// the header and footer unknown fields, then 4-byte entry names, with each divider
+/// written as a zero word followed by its name and unknown field. Words in it are
+/// little-endian and are converted to `endian`.
+fn read_legacy_entry_list(
+    path: &Path,
+    endian: Option<Endian>,
+) -> Result<(EpacArchive, Vec<String>)> {
+    let endian = endian.unwrap_or_default();
+    let mut archive = EpacArchive::with_endian(endian);
+    let mut filename_list = vec![];
+
+    let buf = read_file(path)?;
+    if buf.len() < 8 || buf.len() % 4 != 0 {
+        return Err(Error::Truncated);
+    }
+    archive.header_unknown_field.copy_from_slice(&buf[..4]);
+    archive.header_unknown_field = endian.words_from_le(&archive.header_unknown_field);
+    archive.footer_unknown_field.copy_from_slice(&buf[4..8]);
+    archive.footer_unknown_field = endian.words_from_le(&archive.footer_unknown_field);
+
+    let buf = &buf[8..];
+    let len = buf.len() / 4;
+    let mut filename_map = FilenameMap::default();
+    let mut i = 0;
+    while i < len {
+        let start = i * 4;
+        let end = (i + 1) * 4;
+        let mut name = [0u8; 4];
+        name.clone_from_slice(&buf[start..end]);
+        if name == [0, 0, 0, 0] {
+            if i + 2 >= len {
+                return Err(Error::Truncated);
+            }
+            let mut divider_name = [0u8; 4];
+            divider_name.clone_from_slice(&buf[((i + 1) * 4)..((i + 2) * 4)]);
+            let mut unknown_field = [0u8; 4];
+            unknown_field.clone_from_slice(&buf[((i + 2) * 4)..((i + 3) * 4)]);
+            let unknown_field = endian.words_from_le(&unknown_field);
+            archive.sections.push(EpacSection {
+                divider: Some(EpacDivider {
+                    name: divider_name,
+                    unknown_field,
+                }),
+                entries: vec![],
+            });
+            i += 3;
+        } else {
+            filename_list.push(filename_map.next(&String::from_utf8_lossy(&name)));
+            if archive.sections.is_empty() {
+                archive.sections.push(EpacSection::default());
+            }
+            let section = archive.sections.last_mut().unwrap();
+            section.entries.push(EpacEntry {
+                name,
+                data: vec![],
+                padded_len: None,
+            });
+            i += 1;
+        }
+    }
+    Ok((archive, filename_list))
+}
+
+#[inline]
+fn hex_value(endian: Endian, field: [u8; 4]) -> Value {
+    Value::Hex(endian.u32_from_bytes(field) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive_with_rows(row_num: usize) -> EpacArchive {
+        let mut archive = EpacArchive::new();
+        let entries = (0..row_num)
+            .map(|i| EpacEntry::new(&format!("{:04}", i), vec![1]).unwrap())
+            .collect();
+        archive.sections.push(EpacSection {
+            divider: None,
+            entries,
+        });
+        archive
+    }
+
+    #[test]
+    fn write_to_rejects_table_without_room_for_terminator() {
+        let mut data = vec![];
+        let archive = archive_with_rows(MAX_TABLE_ROW_NUM + 1);
+        assert!(matches!(archive.write_to(&mut data), Err(Error::TooLarge)));
+
+        let mut archive = archive_with_rows(MAX_TABLE_ROW_NUM);
+        data.clear();
+        archive.write_to(&mut data).unwrap();
+        let read = EpacArchive::read_from(&mut Cursor::new(&data[..])).unwrap();
+        assert_eq!(read.entries().count(), MAX_TABLE_ROW_NUM);
+        assert!(matches!(
+            archive.add_entry(None, EpacEntry::new("LAST", vec![1]).unwrap()),
+            Err(Error::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn filename_map_never_repeats_a_name() {
+        let mut filename_map = FilenameMap::default();
+        let names: Vec<_> = ["A   ", "A.1 ", "A   ", "B   "]
+            .iter()
+            .map(|name| filename_map.next(name))
+            .collect();
+        assert_eq!(names, ["A", "A.1", "A.2", "B"]);
+        assert!(matches!(filename_map.insert("B"), Err(Error::Duplicate(_))));
+    }
+
+    #[test]
+    fn entry_manifest_rejects_duplicate_files() {
+        let path = std::env::temp_dir().join(format!(
+            "rr-mod-tool-test-{}-entry-manifest.toml",
+            std::process::id()
+        ));
+        let text = "header_unknown_field = 0\n\
+                    footer_unknown_field = 0\n\
+                    [[section]]\n\
+                    [[section.entry]]\n\
+                    name = \"A   \"\n\
+                    [[section.entry]]\n\
+                    name = \"B   \"\n\
+                    file = \"A\"\n";
+        write_file(&path, text.as_bytes()).unwrap();
+        let result = read_entry_manifest(&path, None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::Duplicate(name)) if name == "A"));
+    }
+
+    #[test]
+    fn padding_fields_round_trip() {
+        let mut archive = EpacArchive::new();
+        archive.header_padding = vec![0, 1, 2];
+        archive.table_padding = vec![0, 0, 0, 0, 0xff, 0, 0xee];
+        archive.footer_padding = vec![0xaa; 0x7ec];
+        archive.sections.push(EpacSection {
+            divider: None,
+            entries: vec![EpacEntry::new("A   ", vec![7; 256]).unwrap()],
+        });
+        archive.sections.push(EpacSection {
+            divider: Some(EpacDivider {
+                name: *b"E001",
+                unknown_field: [3, 0, 0, 0],
+            }),
+            entries: vec![EpacEntry::new("B   ", vec![8; 512]).unwrap()],
+        });
+        let mut data = vec![];
+        archive.write_to(&mut data).unwrap();
+        assert_eq!(data[16..19], [0, 1, 2]);
+        assert_eq!(data[0x800 + 3 * 12 + 4], 0xff);
+        let read = EpacArchive::read_from(&mut Cursor::new(&data[..])).unwrap();
+        assert_eq!(read, archive);
+
+        archive.table_padding[0] = 1;
+        assert!(archive.write_to(&mut vec![]).is_err());
+    }
+
+    /// Entries `E000` and `E001`, then the divider `DIV ` and the others.
+    fn sectioned_archive(endian: Endian, entries: &[Vec<u8>], unknown_field: u32) -> EpacArchive {
+        let mut archive = EpacArchive::with_endian(endian);
+        let entry = |i: usize| EpacEntry::new(&format!("E{:03}", i), entries[i].clone()).unwrap();
+        archive.sections.push(EpacSection {
+            divider: None,
+            entries: vec![entry(0), entry(1)],
+        });
+        archive.sections.push(EpacSection {
+            divider: Some(EpacDivider {
+                name: *b"DIV ",
+                unknown_field: endian.u32_to_bytes(unknown_field),
+            }),
+            entries: (2..entries.len()).map(entry).collect(),
+        });
+        archive
+    }
+
+    fn archive_bytes(endian: Endian, entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = vec![];
+        sectioned_archive(endian, entries, 0)
+            .write_to(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn replace_entry_moves_every_later_entry_and_divider() {
+        let entries = vec![vec![1; 100], vec![2; 3000], vec![], vec![4; 50]];
+        for endian in [Endian::Little, Endian::Big] {
+            for new_len in [5000, 10] {
+                let mut data = archive_bytes(endian, &entries);
+                let new_data = vec![0xaa; new_len];
+                replace_entry(&mut data, 1, &new_data, Some(endian)).unwrap();
+
+                let mut new_entries = entries.clone();
+                new_entries[1] = new_data;
+                let expected = archive_bytes(endian, &new_entries);
+                let listing = list_from(&mut Cursor::new(&data[..]), Some(endian)).unwrap();
+                let expected_listing =
+                    list_from(&mut Cursor::new(&expected[..]), Some(endian)).unwrap();
+                assert_eq!(listing, expected_listing);
+                assert_eq!(data, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn replace_entry_keeps_dividers_readable() {
+        // the divider starts at block 3, and its unknown field reads as block 4
+        let entries = vec![vec![1; 100], vec![2; 3000], vec![3; 10]];
+        for endian in [Endian::Little, Endian::Big] {
+            let archive = sectioned_archive(endian, &entries, 4);
+            let mut original = vec![];
+            archive.write_to(&mut original).unwrap();
+
+            for (index, new_len) in [(0, 10), (1, 10), (2, 5000)] {
+                let mut data = original.clone();
+                replace_entry(&mut data, index, &vec![0xaa; new_len], Some(endian)).unwrap();
+                let mut expected = archive.clone();
+                let section = &mut expected.sections[index / 2];
+                section.entries[index % 2].data = vec![0xaa; new_len];
+                let mut expected_data = vec![];
+                expected.write_to(&mut expected_data).unwrap();
+                assert!(data == expected_data, "replacing entry {}", index);
+
+                let read = EpacArchive::read_from(&mut Cursor::new(&data)).unwrap();
+                let names: Vec<_> = read.entries().map(|e| e.name).collect();
+                assert_eq!(names, [*b"E000", *b"E001", *b"E002"]);
+                assert_eq!(read.sections[1].divider, archive.sections[1].divider);
+            }
+
+            // one more block for E001 would put the divider at block 4
+            let mut data = original.clone();
+            assert!(matches!(
+                replace_entry(&mut data, 1, &[0xaa; 5000], Some(endian)),
+                Err(Error::Unsupported(_))
+            ));
+            let mut grown = archive.clone();
+            grown.sections[0].entries[1].data = vec![0xaa; 5000];
+            assert!(matches!(
+                grown.write_to(&mut vec![]),
+                Err(Error::Unsupported(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn trim_entry_finds_the_end_of_nested_archives() {
+        let mut pach = pach::PachArchive::new();
+        pach.entries = vec![(0, vec![1; 5])];
+        let mut tex = tex::TexArchive::new();
+        tex.add_entry(tex::TexEntry::new("a.dds", vec![2; 7]).unwrap())
+            .unwrap();
+        let mut payloads = vec![bpe::compress(&[3; 300]).unwrap()];
+        let mut pach_data = vec![];
+        pach.write_to(&mut pach_data).unwrap();
+        payloads.push(pach_data);
+        let mut tex_data = vec![];
+        tex.write_to(&mut tex_data).unwrap();
+        payloads.push(tex_data);
+
+        for payload in payloads {
+            let mut padded = payload.clone();
+            padded.resize(0x400, 0);
+            let mut data = padded.clone();
+            assert!(trim_entry(&mut data));
+            assert_eq!(data, payload);
+
+            // stored trimmed with its padded length, the table is written back the same
+            let archive = |data: Vec<u8>, padded_len| {
+                let mut entry = EpacEntry::new("A   ", data).unwrap();
+                entry.padded_len = padded_len;
+                let mut archive = EpacArchive::new();
+                archive.sections.push(EpacSection {
+                    divider: None,
+                    entries: vec![entry],
+                });
+                let mut bytes = vec![];
+                archive.write_to(&mut bytes).unwrap();
+                bytes
             };
+            assert!(archive(data, Some(0x400)) == archive(padded.clone(), None));
+
+            // anything but zeroes after the end is kept
+            let mut data = padded;
+            data[0x3ff] = 1;
+            assert!(!trim_entry(&mut data));
+            assert_eq!(data.len(), 0x400);
+        }
+
+        let mut unknown = vec![0xaa; 0x100];
+        unknown.resize(0x400, 0);
+        assert!(!trim_entry(&mut unknown));
+        assert_eq!(unknown.len(), 0x400);
+    }
+
+    #[test]
+    fn add_and_remove_entries() {
+        let entries = vec![vec![1; 100], vec![2; 3000], vec![3; 10]];
+        for endian in [Endian::Little, Endian::Big] {
+            let mut data = archive_bytes(endian, &entries);
+            add_entry(&mut data, "E003", vec![4; 50], Some("DIV "), Some(endian)).unwrap();
+            let mut new_entries = entries.clone();
+            new_entries.push(vec![4; 50]);
+            assert_eq!(data, archive_bytes(endian, &new_entries));
 
-            let file = create_file_to_write(output_path);
-            let mut writer = BufWriter::new(file);
+            remove_entry(&mut data, 3, Some(endian)).unwrap();
+            assert_eq!(data, archive_bytes(endian, &entries));
+            assert!(matches!(
+                add_entry(&mut data, "E003", vec![], Some("NONE"), Some(endian)),
+                Err(Error::NotFound(_))
+            ));
+        }
+    }
 
-            let mut vec = Vec::with_capacity(info.len as _);
-            unsafe { vec.set_len(info.len as _) };
-            reader.read_exact(&mut vec).unwrap();
-            writer.write_all(&vec).unwrap();
+    #[test]
+    fn names_are_exactly_four_bytes() {
+        assert_eq!(name_from_str("A   ").unwrap(), *b"A   ");
+        assert_eq!(name_from_str("AB C").unwrap(), *b"AB C");
+        for name in ["A", "ABC", " ABC", "\0ABC", ""] {
+            assert!(
+                matches!(name_from_str(name), Err(Error::InvalidName(_))),
+                "{:?}",
+                name
+            );
         }
+        assert!(matches!(
+            name_from_str("ABCDE"),
+            Err(Error::NameTooLong { max: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn header_endian_prefers_the_reserved_word() {
+        let be_reserved = Endian::Big.words_from_le(RESERVED);
+        let mut reserved = [0u8; 4];
+        reserved.copy_from_slice(&be_reserved);
+        // the reserved word wins over a data size that reads smaller the other way
+        assert_eq!(header_endian([0, 0, 0, 1], *RESERVED), Endian::Little);
+        assert_eq!(header_endian([1, 0, 0, 0], reserved), Endian::Big);
+        assert_eq!(header_endian([0, 0, 0, 1], [0; 4]), Endian::Big);
+        assert_eq!(header_endian([1, 0, 0, 0], [0; 4]), Endian::Little);
+    }
+
+    #[test]
+    fn big_endian_unpack_and_pack_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-epac-be", std::process::id()));
+        let src_path = dir.join("be.epac");
+        let unpacked_path = dir.join("be");
+        let packed_path = dir.join("packed.epac");
+        let data = archive_bytes(
+            Endian::Big,
+            &[vec![1; 0x100], vec![2; 0x900], vec![3; 0x100]],
+        );
+        assert_eq!(detect_endian(&data), Some(Endian::Big));
+        let archive = EpacArchive::read_from(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(archive.endian, Endian::Big);
+
+        crate::create_dir_all(&dir).unwrap();
+        crate::write_file(&src_path, &data).unwrap();
+        unpack(
+            src_path.clone(),
+            unpacked_path.clone(),
+            &UnpackOptions::default(),
+        )
+        .unwrap();
+        pack(unpacked_path, packed_path.clone(), &PackOptions::default()).unwrap();
+        let packed = read_file(&packed_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(packed, data);
     }
 }
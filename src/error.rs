@@ -0,0 +1,128 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O operation failed; `path` is known when the error comes from a file on disk.
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// The input ended in the middle of a header or entry.
+    Truncated,
+    /// The magic number doesn't match the expected format.
+    BadMagic { expected: [u8; 4], found: [u8; 4] },
+    /// An entry points outside of the input.
+    OffsetOutOfBounds { offset: u64, len: u64, size: u64 },
+    /// A name doesn't fit into its fixed-size field.
+    NameTooLong { name: String, max: usize },
+    /// A name is empty or can't be stored in the archive.
+    InvalidName(String),
+    /// There is nothing to pack, or the archive has no entries.
+    Empty,
+    /// No entry matches a selector.
+    NotFound(String),
+    /// An entry with the same id is already in the archive.
+    Duplicate(String),
+    /// The format can't do what was asked, e.g. adding entries to BPE.
+    Unsupported(String),
+    /// A length or offset doesn't fit into the field storing it.
+    TooLarge,
+    /// No registered format recognizes the input.
+    UnknownFormat,
+    /// The data is structurally invalid.
+    Malformed(String),
+    /// A compressed block is invalid; `offset` is where it starts in the stream.
+    BadBlock {
+        index: u64,
+        offset: u64,
+        reason: String,
+    },
+}
+
+impl Error {
+    pub(crate) fn io<P: AsRef<Path>>(path: P, source: io::Error) -> Self {
+        Error::Io {
+            path: Some(path.as_ref().to_path_buf()),
+            source,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        // unwrap errors that went through an `io::Read`/`io::Write` implementation
+        if source.get_ref().is_some_and(|e| e.is::<Error>()) {
+            if let Some(Ok(e)) = source.into_inner().map(|e| e.downcast::<Error>()) {
+                return *e;
+            }
+            unreachable!();
+        }
+        if source.kind() == io::ErrorKind::UnexpectedEof {
+            return Error::Truncated;
+        }
+        Error::Io { path: None, source }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io { source, .. } => source,
+            Error::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+            Error::Truncated => write!(f, "unexpected end of data"),
+            Error::BadMagic { expected, found } => write!(
+                f,
+                "bad magic number: expected {:?}, found {:?}",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(found)
+            ),
+            Error::OffsetOutOfBounds { offset, len, size } => write!(
+                f,
+                "entry at offset {:#x} with length {:#x} exceeds data size {:#x}",
+                offset, len, size
+            ),
+            Error::NameTooLong { name, max } => {
+                write!(f, "name {:?} is longer than {} bytes", name, max)
+            }
+            Error::InvalidName(name) => write!(f, "invalid name {:?}", name),
+            Error::Empty => write!(f, "no entries"),
+            Error::NotFound(selector) => write!(f, "no entry {:?}", selector),
+            Error::Duplicate(name) => write!(f, "entry {:?} already exists", name),
+            Error::Unsupported(what) => write!(f, "not supported: {}", what),
+            Error::TooLarge => write!(f, "data too large for the format"),
+            Error::UnknownFormat => write!(f, "unknown format"),
+            Error::Malformed(msg) => write!(f, "malformed data: {}", msg),
+            Error::BadBlock {
+                index,
+                offset,
+                reason,
+            } => write!(f, "bad block {} at offset {:#x}: {}", index, offset, reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
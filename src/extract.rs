@@ -0,0 +1,254 @@
+// Extraction of a single entry, which may be nested in other containers.
+//
+// A selector is a `/`-separated path of entry names, each named as `unpack` names its
+// file: a PACH file number, a TEX `name.ext`, an EPAC name with its `.N` suffix for
+// repeated names. `#N` selects the entry at index N instead. BPE data met on the way
+// is decompressed before looking into it, so `data.epac/0A1B/3/body.dds` reaches into
+// a compressed PACH stored in an EPAC.
+
+use std::io::{BufReader, Cursor};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::format::Registry;
+use crate::list::{ListEntry, Listing};
+use crate::{
+    bpe, check_output_filename, open_file, read_file, read_packed_file, write_file, Error,
+    PackedFileInfo, Result, UnpackOptions,
+};
+
+/// Returns the name and data of the entry `selector` in the archive `src_path`.
+///
+/// Only the entry table and the selected entry are read from the file, unless it is
+/// BPE compressed as a whole.
+pub fn extract_data(
+    registry: &Registry,
+    src_path: &Path,
+    selector: &str,
+    options: &UnpackOptions,
+) -> Result<(String, Vec<u8>)> {
+    let mut components = selector.split('/').filter(|c| !c.is_empty());
+    let mut name = match components.next() {
+        Some(component) => component.to_string(),
+        None => return Err(Error::InvalidName(selector.to_string())),
+    };
+    let format = registry
+        .detect_file(src_path)?
+        .ok_or(Error::UnknownFormat)?;
+
+    let mut data = if bpe::detect_format(src_path)? {
+        let data = bpe::decompress_with(&read_file(src_path)?, options.endian, options.strict)?;
+        let (entry_name, entry_data) = select(registry, &data, &name, options)?;
+        name = entry_name;
+        entry_data
+    } else {
+        let listing = format.list(src_path, options)?;
+        let entry = find_entry(&listing, &name)?;
+        let info = packed_file_info(entry);
+        let file = open_file(src_path)?;
+        let mut data = read_packed_file(&mut BufReader::new(file), &info)?;
+        format.trim_entry(&mut data);
+        name = info.filename;
+        data
+    };
+
+    // nested data is read in its own byte order
+    let options = UnpackOptions {
+        endian: None,
+        ..options.clone()
+    };
+    for component in components {
+        let (entry_name, entry_data) = select(registry, &data, component, &options)?;
+        name = entry_name;
+        data = entry_data;
+    }
+    Ok((name, data))
+}
+
+/// Extracts the entry `selector` in the archive `src_path` into `dst_path`, or into the
+/// current directory under the entry's name. If `dst_path` is a directory, the entry is
+/// written into it. Returns the path written.
+pub fn extract(
+    registry: &Registry,
+    src_path: &Path,
+    selector: &str,
+    dst_path: Option<&Path>,
+    options: &UnpackOptions,
+) -> Result<PathBuf> {
+    let (name, data) = extract_data(registry, src_path, selector, options)?;
+    let dst_path = match dst_path {
+        Some(path) if !path.is_dir() => path.to_path_buf(),
+        path => {
+            check_output_filename(&name)?;
+            path.unwrap_or_else(|| Path::new(".")).join(&name)
+        }
+    };
+    write_file(&dst_path, &data)?;
+    Ok(dst_path)
+}
+
+/// Looks up `name` in the archive held in `data`, decompressing it first if it is BPE.
+fn select(
+    registry: &Registry,
+    data: &[u8],
+    name: &str,
+    options: &UnpackOptions,
+) -> Result<(String, Vec<u8>)> {
+    if bpe::detect(data) {
+        let data = bpe::decompress_with(data, options.endian, options.strict)?;
+        return select(registry, &data, name, options);
+    }
+    let format = registry.detect(data).ok_or(Error::UnknownFormat)?;
+    let listing = format.list_data(data, options)?;
+    let entry = find_entry(&listing, name)?;
+    let info = packed_file_info(entry);
+    let mut entry_data = read_packed_file(&mut Cursor::new(data), &info)?;
+    format.trim_entry(&mut entry_data);
+    Ok((info.filename, entry_data))
+}
+
+/// Finds the entry named `name`, or at index N if `name` is `#N`.
+pub(crate) fn find_entry<'a>(listing: &'a Listing, name: &str) -> Result<&'a ListEntry> {
+    let index = name.strip_prefix('#').and_then(|i| usize::from_str(i).ok());
+    listing
+        .entries()
+        .find(|entry| match index {
+            Some(index) => entry.index == index,
+            None => entry.name == name,
+        })
+        .ok_or_else(|| Error::NotFound(name.to_string()))
+}
+
+fn packed_file_info(entry: &ListEntry) -> PackedFileInfo {
+    PackedFileInfo {
+        filename: entry.name.clone(),
+        offset: entry.offset,
+        len: entry.len,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epac::{EpacArchive, EpacEntry, EpacSection};
+    use crate::pach::PachArchive;
+    use crate::tex::{TexArchive, TexEntry};
+
+    /// An EPAC holding two entries named `DATA` around a PACH whose first entry is a
+    /// BPE compressed TEX.
+    fn nested_archive() -> Vec<u8> {
+        let mut tex = TexArchive::new();
+        tex.add_entry(TexEntry::new("a.dds", vec![1; 40]).unwrap())
+            .unwrap();
+        tex.add_entry(TexEntry::new("b.dds", vec![2; 24]).unwrap())
+            .unwrap();
+        let mut tex_data = vec![];
+        tex.write_to(&mut tex_data).unwrap();
+        let mut pach = PachArchive::new();
+        pach.entries = vec![(0, bpe::compress(&tex_data).unwrap()), (1, vec![3; 12])];
+        let mut pach_data = vec![];
+        pach.write_to(&mut pach_data).unwrap();
+        let mut epac = EpacArchive::new();
+        epac.sections.push(EpacSection {
+            divider: None,
+            entries: vec![
+                EpacEntry::new("DATA", vec![4; 0x100]).unwrap(),
+                EpacEntry::new("PACH", pach_data).unwrap(),
+                EpacEntry::new("DATA", vec![5; 0x100]).unwrap(),
+            ],
+        });
+        let mut data = vec![];
+        epac.write_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn extract_data_follows_selectors_into_nested_archives() {
+        let path = std::env::temp_dir().join(format!(
+            "rr-mod-tool-test-{}-extract.epac",
+            std::process::id()
+        ));
+        let bpe_path = path.with_extension("bpe");
+        let data = nested_archive();
+        write_file(&path, &data).unwrap();
+        write_file(&bpe_path, &bpe::compress(&data).unwrap()).unwrap();
+
+        let registry = Registry::default();
+        let options = UnpackOptions::default();
+        let extract =
+            |path: &Path, selector: &str| extract_data(&registry, path, selector, &options);
+        let results = [
+            extract(&path, "PACH/0/b.dds"),
+            extract(&path, "/PACH//0/a.dds/"),
+            extract(&path, "DATA"),
+            extract(&path, "DATA.1"),
+            extract(&path, "#2"),
+            extract(&path, "#1/#0/#1"),
+            extract(&bpe_path, "PACH/1"),
+            extract(&bpe_path, "DATA.1"),
+        ];
+        let errors = [
+            extract(&path, "DATA.2"),
+            extract(&path, "#3"),
+            extract(&path, "PACH/2"),
+            extract(&path, "PACH/0/c.dds"),
+            extract(&path, "PACH/1/0"),
+            extract(&path, ""),
+        ];
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&bpe_path).unwrap();
+
+        let results: Vec<_> = results.iter().map(|r| r.as_ref().unwrap()).collect();
+        assert_eq!(results[0], &("b.dds".to_string(), vec![2; 24]));
+        assert_eq!(results[1], &("a.dds".to_string(), vec![1; 40]));
+        assert_eq!(results[2], &("DATA".to_string(), vec![4; 0x100]));
+        assert_eq!(results[3], &("DATA.1".to_string(), vec![5; 0x100]));
+        assert_eq!(results[4], results[3]);
+        assert_eq!(results[5], results[0]);
+        assert_eq!(results[6], &("1".to_string(), vec![3; 12]));
+        assert_eq!(results[7], results[3]);
+
+        assert!(matches!(&errors[0], Err(Error::NotFound(name)) if name == "DATA.2"));
+        assert!(matches!(&errors[1], Err(Error::NotFound(name)) if name == "#3"));
+        assert!(matches!(&errors[2], Err(Error::NotFound(name)) if name == "2"));
+        assert!(matches!(&errors[3], Err(Error::NotFound(name)) if name == "c.dds"));
+        assert!(matches!(&errors[4], Err(Error::UnknownFormat)));
+        assert!(matches!(&errors[5], Err(Error::InvalidName(_))));
+    }
+
+    #[test]
+    fn select_decompresses_bpe_data() {
+        let data = nested_archive();
+        let registry = Registry::default();
+        let options = UnpackOptions::default();
+        let (name, pach_data) = select(&registry, &data, "PACH", &options).unwrap();
+        assert_eq!(name, "PACH");
+        let (_, bpe_data) = select(&registry, &pach_data, "0", &options).unwrap();
+        assert!(bpe::detect(&bpe_data));
+        let (name, entry_data) = select(&registry, &bpe_data, "#0", &options).unwrap();
+        assert_eq!((name.as_str(), entry_data), ("a.dds", vec![1; 40]));
+        let compressed = bpe::compress(&data).unwrap();
+        assert_eq!(
+            select(&registry, &compressed, "DATA", &options).unwrap().1,
+            vec![4; 0x100]
+        );
+    }
+
+    #[test]
+    fn find_entry_matches_names_and_indexes() {
+        let registry = Registry::default();
+        let data = nested_archive();
+        let format = registry.detect(&data).unwrap();
+        let listing = format.list_data(&data, &UnpackOptions::default()).unwrap();
+        assert_eq!(find_entry(&listing, "PACH").unwrap().index, 1);
+        assert_eq!(find_entry(&listing, "DATA.1").unwrap().index, 2);
+        assert_eq!(find_entry(&listing, "#0").unwrap().name, "DATA");
+        // `#` not followed by a number is looked up as a name
+        for name in ["#", "#x", "#-1", "data", "DATA.0"].iter() {
+            assert!(matches!(
+                find_entry(&listing, name),
+                Err(Error::NotFound(_))
+            ));
+        }
+    }
+}
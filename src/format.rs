@@ -0,0 +1,464 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::list::Listing;
+use crate::{bpe, epac, pach, read_file, tex, Endian, Error, PackOptions, Result, UnpackOptions};
+
+/// How sure a format is that some data belongs to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Confidence {
+    None,
+    Low,
+    High,
+    Certain,
+}
+
+/// A file format that can be unpacked into and packed from a directory (or a file).
+pub trait ContainerFormat: Send + Sync {
+    /// The name used on the command line, e.g. `pach`.
+    fn name(&self) -> &str;
+
+    /// Checks whether `data`, the whole content of a file, is in this format.
+    fn detect(&self, data: &[u8]) -> Confidence;
+
+    /// Like [`detect`](Self::detect), for the file `path`; formats that can tell from
+    /// the header alone avoid reading the rest.
+    fn detect_file(&self, path: &Path) -> Result<Confidence> {
+        Ok(self.detect(&read_file(path)?))
+    }
+
+    /// Whether `unpack` writes a directory; formats that just compress a single file
+    /// return false.
+    fn unpacks_to_dir(&self) -> bool {
+        true
+    }
+
+    fn unpack(&self, src_path: &Path, dst_path: &Path, options: &UnpackOptions) -> Result<()>;
+
+    fn pack(&self, src_path: &Path, dst_path: &Path, options: &PackOptions) -> Result<()>;
+
+    /// Reads the header and entry table of `src_path` without extracting anything.
+    fn list(&self, src_path: &Path, options: &UnpackOptions) -> Result<Listing>;
+
+    /// Like [`list`](Self::list), for an archive held in memory.
+    fn list_data(&self, data: &[u8], options: &UnpackOptions) -> Result<Listing>;
+
+    /// Removes the padding the archive adds after an entry's data, as `unpack` does.
+    fn trim_entry(&self, _data: &mut Vec<u8>) {}
+
+    /// Replaces the data of entry `index`, as numbered by [`list_data`](Self::list_data),
+    /// in the archive held in `data`, leaving everything else as it is.
+    fn replace_entry(
+        &self,
+        _data: &mut Vec<u8>,
+        _index: usize,
+        _new_data: &[u8],
+        _options: &UnpackOptions,
+    ) -> Result<()> {
+        Err(Error::Unsupported(format!(
+            "{} has no entries",
+            self.name()
+        )))
+    }
+
+    /// Adds an entry to the archive held in `data`, `name` being the file name `unpack`
+    /// would give it. `divider` names the section to add it to, in formats that have them.
+    fn add_entry(
+        &self,
+        _data: &mut Vec<u8>,
+        _name: &str,
+        _entry_data: Vec<u8>,
+        _divider: Option<&str>,
+        _options: &UnpackOptions,
+    ) -> Result<()> {
+        Err(Error::Unsupported(format!(
+            "{} has no entries",
+            self.name()
+        )))
+    }
+
+    /// Removes entry `index`, as numbered by [`list_data`](Self::list_data), from the
+    /// archive held in `data`.
+    fn remove_entry(
+        &self,
+        _data: &mut Vec<u8>,
+        _index: usize,
+        _options: &UnpackOptions,
+    ) -> Result<()> {
+        Err(Error::Unsupported(format!(
+            "{} has no entries",
+            self.name()
+        )))
+    }
+
+    /// Names the header, table or data field that `offset` falls in, for reporting
+    /// where two files differ.
+    fn describe_offset(&self, _data: &[u8], _offset: u64) -> Option<String> {
+        None
+    }
+
+    /// The byte order of `data`, for formats that have both variants.
+    fn detect_endian(&self, _data: &[u8]) -> Option<Endian> {
+        None
+    }
+}
+
+/// A set of formats, tried in order of detection confidence.
+pub struct Registry {
+    formats: Vec<Box<dyn ContainerFormat>>,
+}
+
+impl Registry {
+    /// Creates a registry without any formats.
+    pub fn new() -> Self {
+        Self { formats: vec![] }
+    }
+
+    /// Adds a format; on equal confidence, earlier registered formats win.
+    pub fn register<F: ContainerFormat + 'static>(&mut self, format: F) {
+        self.formats.push(Box::new(format));
+    }
+
+    pub fn formats(&self) -> impl Iterator<Item = &dyn ContainerFormat> {
+        self.formats.iter().map(|f| f.as_ref())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn ContainerFormat> {
+        self.formats().find(|f| f.name() == name)
+    }
+
+    /// Returns the format most confident about `data`, if any.
+    pub fn detect(&self, data: &[u8]) -> Option<&dyn ContainerFormat> {
+        self.most_confident(|format| Ok(format.detect(data)))
+            .unwrap_or_default()
+    }
+
+    /// Like [`detect`](Self::detect), reading only what each format needs from `path`.
+    pub fn detect_file<P: AsRef<Path>>(&self, path: P) -> Result<Option<&dyn ContainerFormat>> {
+        self.most_confident(|format| format.detect_file(path.as_ref()))
+    }
+
+    fn most_confident<F>(&self, mut confidence_of: F) -> Result<Option<&dyn ContainerFormat>>
+    where
+        F: FnMut(&dyn ContainerFormat) -> Result<Confidence>,
+    {
+        let mut best: Option<(&dyn ContainerFormat, Confidence)> = None;
+        for format in self.formats() {
+            let confidence = confidence_of(format)?;
+            if confidence == Confidence::None {
+                continue;
+            }
+            match best {
+                Some((_, c)) if c >= confidence => {}
+                _ => best = Some((format, confidence)),
+            }
+        }
+        Ok(best.map(|(format, _)| format))
+    }
+}
+
+impl Default for Registry {
+    /// Creates a registry with the built-in formats.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(Epac);
+        registry.register(Pach);
+        registry.register(Bpe);
+        registry.register(Tex);
+        registry
+    }
+}
+
+pub struct Epac;
+pub struct Pach;
+pub struct Bpe;
+pub struct Tex;
+
+impl ContainerFormat for Epac {
+    fn name(&self) -> &str {
+        "epac"
+    }
+
+    fn detect(&self, data: &[u8]) -> Confidence {
+        if epac::detect(data) {
+            Confidence::Certain
+        } else {
+            Confidence::None
+        }
+    }
+
+    fn detect_file(&self, path: &Path) -> Result<Confidence> {
+        if epac::detect_format(path)? {
+            Ok(Confidence::Certain)
+        } else {
+            Ok(Confidence::None)
+        }
+    }
+
+    fn unpack(&self, src_path: &Path, dst_path: &Path, options: &UnpackOptions) -> Result<()> {
+        epac::unpack(src_path.to_path_buf(), dst_path.to_path_buf(), options)
+    }
+
+    fn pack(&self, src_path: &Path, dst_path: &Path, options: &PackOptions) -> Result<()> {
+        epac::pack(src_path.to_path_buf(), dst_path.to_path_buf(), options)
+    }
+
+    fn list(&self, src_path: &Path, options: &UnpackOptions) -> Result<Listing> {
+        epac::list(src_path, options)
+    }
+
+    fn list_data(&self, data: &[u8], options: &UnpackOptions) -> Result<Listing> {
+        epac::list_from(&mut Cursor::new(data), options.endian)
+    }
+
+    fn replace_entry(
+        &self,
+        data: &mut Vec<u8>,
+        index: usize,
+        new_data: &[u8],
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        epac::replace_entry(data, index, new_data, options.endian)
+    }
+
+    fn add_entry(
+        &self,
+        data: &mut Vec<u8>,
+        name: &str,
+        entry_data: Vec<u8>,
+        divider: Option<&str>,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        epac::add_entry(data, name, entry_data, divider, options.endian)
+    }
+
+    fn remove_entry(
+        &self,
+        data: &mut Vec<u8>,
+        index: usize,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        epac::remove_entry(data, index, options.endian)
+    }
+
+    fn trim_entry(&self, data: &mut Vec<u8>) {
+        epac::trim_entry(data);
+    }
+
+    fn describe_offset(&self, data: &[u8], offset: u64) -> Option<String> {
+        epac::field_at(data, offset)
+    }
+
+    fn detect_endian(&self, data: &[u8]) -> Option<Endian> {
+        epac::detect_endian(data)
+    }
+}
+
+impl ContainerFormat for Pach {
+    fn name(&self) -> &str {
+        "pach"
+    }
+
+    fn detect(&self, data: &[u8]) -> Confidence {
+        if pach::detect(data) {
+            Confidence::Certain
+        } else {
+            Confidence::None
+        }
+    }
+
+    fn detect_file(&self, path: &Path) -> Result<Confidence> {
+        if pach::detect_format(path)? {
+            Ok(Confidence::Certain)
+        } else {
+            Ok(Confidence::None)
+        }
+    }
+
+    fn unpack(&self, src_path: &Path, dst_path: &Path, options: &UnpackOptions) -> Result<()> {
+        pach::unpack(src_path.to_path_buf(), dst_path.to_path_buf(), options)
+    }
+
+    fn pack(&self, src_path: &Path, dst_path: &Path, options: &PackOptions) -> Result<()> {
+        pach::pack(src_path.to_path_buf(), dst_path.to_path_buf(), options)
+    }
+
+    fn list(&self, src_path: &Path, options: &UnpackOptions) -> Result<Listing> {
+        pach::list(src_path, options)
+    }
+
+    fn list_data(&self, data: &[u8], options: &UnpackOptions) -> Result<Listing> {
+        pach::list_from(&mut Cursor::new(data), options.endian)
+    }
+
+    fn replace_entry(
+        &self,
+        data: &mut Vec<u8>,
+        index: usize,
+        new_data: &[u8],
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        pach::replace_entry(data, index, new_data, options.endian)
+    }
+
+    fn add_entry(
+        &self,
+        data: &mut Vec<u8>,
+        name: &str,
+        entry_data: Vec<u8>,
+        divider: Option<&str>,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        if divider.is_some() {
+            return Err(Error::Unsupported("dividers in pach".to_string()));
+        }
+        pach::add_entry(data, name, entry_data, options.endian)
+    }
+
+    fn remove_entry(
+        &self,
+        data: &mut Vec<u8>,
+        index: usize,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        pach::remove_entry(data, index, options.endian)
+    }
+
+    fn describe_offset(&self, data: &[u8], offset: u64) -> Option<String> {
+        pach::field_at(data, offset)
+    }
+
+    fn detect_endian(&self, data: &[u8]) -> Option<Endian> {
+        pach::detect_endian(data)
+    }
+}
+
+impl ContainerFormat for Bpe {
+    fn name(&self) -> &str {
+        "bpe"
+    }
+
+    fn unpacks_to_dir(&self) -> bool {
+        false
+    }
+
+    fn detect(&self, data: &[u8]) -> Confidence {
+        if bpe::detect(data) {
+            Confidence::Certain
+        } else {
+            Confidence::None
+        }
+    }
+
+    fn detect_file(&self, path: &Path) -> Result<Confidence> {
+        if bpe::detect_format(path)? {
+            Ok(Confidence::Certain)
+        } else {
+            Ok(Confidence::None)
+        }
+    }
+
+    fn unpack(&self, src_path: &Path, dst_path: &Path, options: &UnpackOptions) -> Result<()> {
+        bpe::unpack(src_path.to_path_buf(), dst_path.to_path_buf(), options)
+    }
+
+    fn pack(&self, src_path: &Path, dst_path: &Path, options: &PackOptions) -> Result<()> {
+        bpe::pack(src_path.to_path_buf(), dst_path.to_path_buf(), options)
+    }
+
+    fn list(&self, src_path: &Path, options: &UnpackOptions) -> Result<Listing> {
+        bpe::list(src_path, options)
+    }
+
+    fn list_data(&self, data: &[u8], options: &UnpackOptions) -> Result<Listing> {
+        bpe::list_from(data, options.endian)
+    }
+
+    fn describe_offset(&self, data: &[u8], offset: u64) -> Option<String> {
+        bpe::field_at(data, offset)
+    }
+
+    fn detect_endian(&self, data: &[u8]) -> Option<Endian> {
+        bpe::detect_endian(data)
+    }
+}
+
+impl ContainerFormat for Tex {
+    fn name(&self) -> &str {
+        "tex"
+    }
+
+    fn detect(&self, data: &[u8]) -> Confidence {
+        // TEX has no magic number, a consistent header is the best we can check
+        if tex::detect(data) {
+            Confidence::High
+        } else {
+            Confidence::None
+        }
+    }
+
+    fn detect_file(&self, path: &Path) -> Result<Confidence> {
+        if tex::detect_format(path)? {
+            Ok(Confidence::High)
+        } else {
+            Ok(Confidence::None)
+        }
+    }
+
+    fn unpack(&self, src_path: &Path, dst_path: &Path, options: &UnpackOptions) -> Result<()> {
+        tex::unpack(src_path.to_path_buf(), dst_path.to_path_buf(), options)
+    }
+
+    fn pack(&self, src_path: &Path, dst_path: &Path, options: &PackOptions) -> Result<()> {
+        tex::pack(src_path.to_path_buf(), dst_path.to_path_buf(), options)
+    }
+
+    fn list(&self, src_path: &Path, options: &UnpackOptions) -> Result<Listing> {
+        tex::list(src_path, options)
+    }
+
+    fn list_data(&self, data: &[u8], options: &UnpackOptions) -> Result<Listing> {
+        tex::list_from(&mut Cursor::new(data), options.endian)
+    }
+
+    fn replace_entry(
+        &self,
+        data: &mut Vec<u8>,
+        index: usize,
+        new_data: &[u8],
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        tex::replace_entry(data, index, new_data, options.endian)
+    }
+
+    fn add_entry(
+        &self,
+        data: &mut Vec<u8>,
+        name: &str,
+        entry_data: Vec<u8>,
+        divider: Option<&str>,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        if divider.is_some() {
+            return Err(Error::Unsupported("dividers in tex".to_string()));
+        }
+        tex::add_entry(data, name, entry_data, options.endian)
+    }
+
+    fn remove_entry(
+        &self,
+        data: &mut Vec<u8>,
+        index: usize,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        tex::remove_entry(data, index, options.endian)
+    }
+
+    fn describe_offset(&self, data: &[u8], offset: u64) -> Option<String> {
+        tex::field_at(data, offset)
+    }
+
+    fn detect_endian(&self, data: &[u8]) -> Option<Endian> {
+        tex::detect_endian(data)
+    }
+}
@@ -1,19 +1,29 @@
-use std::ffi::OsStr;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
 use std::fs::{read_dir, File, OpenOptions};
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+use manifest::{Table, Value};
 
 pub mod bpe;
+pub mod edit;
 pub mod epac;
+mod error;
+pub mod extract;
+pub mod format;
+pub mod list;
+mod manifest;
 pub mod pach;
 pub mod tex;
+pub mod tree;
+pub mod verify;
 
-struct FileInfo {
-    path: PathBuf,
-    len: u64,
-    padding_zero_num: u64,
-}
+pub use error::{Error, Result};
 
 struct PackedFileInfo {
     filename: String,
@@ -21,31 +31,133 @@ struct PackedFileInfo {
     len: u64,
 }
 
+/// Options for packing a directory into an archive.
+#[derive(Debug, Clone, Default)]
+pub struct PackOptions {
+    /// Leaves out entries whose file is empty. By default they are packed as empty
+    /// entries, keeping the position of every entry after them.
+    pub drop_empty: bool,
+    /// The byte order to write, overriding the one recorded when unpacking.
+    pub endian: Option<Endian>,
+    /// How hard to try when compressing BPE.
+    pub level: bpe::Level,
+    /// How the BPE encoder picks pairs and substitute bytes.
+    pub choices: bpe::Choices,
+}
+
+/// Options for unpacking an archive.
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    /// The byte order to read, instead of detecting it from the header.
+    pub endian: Option<Endian>,
+    /// Rejects BPE streams whose data doesn't decompress to exactly the length in the
+    /// header, instead of padding them with zeroes.
+    pub strict: bool,
+}
+
+/// The byte order of the integer fields in an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Endian {
+    /// Used by the PS2 release.
+    #[default]
+    Little,
+    /// Used by the Xbox 360 and PS3 releases.
+    Big,
+}
+
+impl Endian {
+    /// The name used on the command line and in manifests.
+    pub fn name(self) -> &'static str {
+        match self {
+            Endian::Little => "little",
+            Endian::Big => "big",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "little" | "le" => Some(Endian::Little),
+            "big" | "be" => Some(Endian::Big),
+            _ => None,
+        }
+    }
+
+    /// Guesses the byte order of a count or length field: real values are small, so
+    /// the order giving the smaller nonzero value wins, little-endian on ties.
+    fn guess(buf: [u8; 4]) -> Self {
+        let le = u32::from_le_bytes(buf);
+        let be = u32::from_be_bytes(buf);
+        if be != 0 && (be < le || le == 0) {
+            Endian::Big
+        } else {
+            Endian::Little
+        }
+    }
+
+    #[inline]
+    fn u32_from_bytes(self, buf: [u8; 4]) -> u32 {
+        match self {
+            Endian::Little => u32::from_le_bytes(buf),
+            Endian::Big => u32::from_be_bytes(buf),
+        }
+    }
+
+    #[inline]
+    fn u32_to_bytes(self, n: u32) -> [u8; 4] {
+        match self {
+            Endian::Little => n.to_le_bytes(),
+            Endian::Big => n.to_be_bytes(),
+        }
+    }
+
+    #[inline]
+    fn u16_from_bytes(self, buf: [u8; 2]) -> u16 {
+        match self {
+            Endian::Little => u16::from_le_bytes(buf),
+            Endian::Big => u16::from_be_bytes(buf),
+        }
+    }
+
+    #[inline]
+    fn u16_to_bytes(self, n: u16) -> [u8; 2] {
+        match self {
+            Endian::Little => n.to_le_bytes(),
+            Endian::Big => n.to_be_bytes(),
+        }
+    }
+
+    /// Converts little-endian words to this byte order, e.g. for default header fields.
+    fn words_from_le<const N: usize>(self, bytes: &[u8; N]) -> [u8; N] {
+        let mut result = *bytes;
+        if self == Endian::Big {
+            for word in result.chunks_mut(4) {
+                word.reverse();
+            }
+        }
+        result
+    }
+}
+
 #[macro_export]
 macro_rules! read_exact {
     ($reader:expr, $len:literal) => {{
         let mut buf = [0u8; $len];
-        $reader.read_exact(&mut buf).unwrap();
+        $reader.read_exact(&mut buf)?;
         buf
     }};
 }
 
-fn list_files(
-    dir_path: &PathBuf,
-    align_size: u64,
-    filter: Option<fn(&OsStr) -> bool>,
-) -> Vec<FileInfo> {
+fn list_files(dir_path: &Path, filter: Option<fn(&OsStr) -> bool>) -> Result<Vec<PathBuf>> {
     let mut vec = vec![];
-    let dir = read_dir(dir_path).unwrap();
+    let dir = read_dir(dir_path).map_err(|e| Error::io(dir_path, e))?;
     for entry in dir {
-        let entry = entry.unwrap();
-        let metadata = entry.metadata().unwrap();
+        let entry = entry.map_err(|e| Error::io(dir_path, e))?;
+        let metadata = entry.metadata().map_err(|e| Error::io(entry.path(), e))?;
         if !metadata.file_type().is_file() {
             continue;
         }
         let filename = entry.file_name();
-        let len = metadata.len();
-        if len == 0 {
+        if is_manifest_name(&filename) {
             continue;
         }
         if let Some(filter) = filter {
@@ -53,69 +165,665 @@ fn list_files(
                 continue;
             }
         }
-        let padding_zero_num = if align_size > 0 {
-            let rem = len % align_size;
-            if rem > 0 {
-                align_size - rem
-            } else {
-                0
-            }
-        } else {
-            0
-        };
-        let path = dir_path.join(filename);
-        vec.push(FileInfo {
-            path,
-            len,
-            padding_zero_num,
-        });
+        vec.push(dir_path.join(filename));
     }
-    // Sort files numerically
-    vec.sort_by(|i1, i2| {
-        let name1 = i1.path.file_name().unwrap().to_string_lossy();
-        let name2 = i2.path.file_name().unwrap().to_string_lossy();
+    vec.sort_by(|p1, p2| compare_filenames(p1, p2));
+    Ok(vec)
+}
+
+/// The order in which files are packed when no manifest says otherwise: names that are
+/// decimal numbers first, by value, then all other names by their bytes.
+fn compare_filenames(p1: &Path, p2: &Path) -> Ordering {
+    let name1 = p1.file_name().unwrap_or_default().to_string_lossy();
+    let name2 = p2.file_name().unwrap_or_default().to_string_lossy();
 
-        let num1 = u32::from_str(&name1).unwrap_or_default();
-        let num2 = u32::from_str(&name2).unwrap_or_default();
+    match (u32::from_str(&name1), u32::from_str(&name2)) {
+        (Ok(num1), Ok(num2)) => num1.cmp(&num2).then_with(|| name1.cmp(&name2)),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => name1.as_bytes().cmp(name2.as_bytes()),
+    }
+}
 
-        num1.cmp(&num2)
-    });
-    vec
+/// Names starting with `__` are reserved for manifests written by unpacking.
+#[inline]
+fn is_manifest_name(filename: &OsStr) -> bool {
+    filename.to_string_lossy().starts_with("__")
 }
 
 #[inline]
-fn write_padding_zeroes<W: Write>(writer: &mut W, zero_num: usize) {
-    let zero = [0u8];
-    for _ in 0..zero_num {
-        writer.write_all(&zero).unwrap();
+fn padding_zero_num(len: u64, align_size: u64) -> u64 {
+    if align_size > 0 {
+        let rem = len % align_size;
+        if rem > 0 {
+            return align_size - rem;
+        }
     }
+    0
+}
+
+/// Puts the files named in `ordered_names` first, in that order, followed by the rest of
+/// `path_list`. A name listed twice is an error.
+fn order_files(
+    dir_path: &Path,
+    ordered_names: &[String],
+    path_list: Vec<PathBuf>,
+) -> Result<Vec<PathBuf>> {
+    let mut vec = Vec::with_capacity(ordered_names.len() + path_list.len());
+    let mut listed = HashSet::new();
+    for name in ordered_names {
+        check_output_filename(name)?;
+        if !listed.insert(name.as_str()) {
+            return Err(Error::Duplicate(name.clone()));
+        }
+        vec.push(dir_path.join(name));
+    }
+    for path in path_list {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !listed.contains(&*name) {
+            vec.push(path);
+        }
+    }
+    Ok(vec)
+}
+
+#[inline]
+fn write_padding_zeroes<W: Write>(writer: &mut W, zero_num: usize) -> Result<()> {
+    let zeroes = [0u8; 256];
+    let mut rem = zero_num;
+    while rem > 0 {
+        let n = rem.min(zeroes.len());
+        writer.write_all(&zeroes[..n])?;
+        rem -= n;
+    }
+    Ok(())
+}
+
+#[inline]
+fn open_file<P: AsRef<Path>>(path: P) -> Result<File> {
+    File::open(&path).map_err(|e| Error::io(path, e))
+}
+
+#[inline]
+fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let mut file = open_file(&path)?;
+    let mut vec = vec![];
+    file.read_to_end(&mut vec).map_err(|e| Error::io(path, e))?;
+    Ok(vec)
+}
+
+fn write_file<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
+    let mut file = create_file_to_write(&path)?;
+    file.write_all(data).map_err(|e| Error::io(path, e))
+}
+
+/// Writes `data` over the existing file `path` through a file next to it, renamed over
+/// `path` once complete, so that a failed write leaves the original as it was. The new
+/// file keeps the permissions of the original.
+fn replace_file<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let filename = path
+        .file_name()
+        .ok_or_else(|| Error::InvalidName(path.display().to_string()))?;
+    let mut tmp_filename = OsString::from(".");
+    tmp_filename.push(filename);
+    tmp_filename.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_filename);
+
+    let result = write_and_rename(&tmp_path, path, data);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn write_and_rename(tmp_path: &Path, path: &Path, data: &[u8]) -> Result<()> {
+    let permissions = std::fs::metadata(path)
+        .map_err(|e| Error::io(path, e))?
+        .permissions();
+    let mut file = create_file_to_write(tmp_path)?;
+    file.write_all(data)
+        .and_then(|_| file.set_permissions(permissions))
+        .and_then(|_| file.sync_all())
+        .map_err(|e| Error::io(tmp_path, e))?;
+    drop(file);
+    std::fs::rename(tmp_path, path).map_err(|e| Error::io(path, e))
 }
 
 #[inline]
-fn create_file_to_write<P: AsRef<Path>>(path: P) -> File {
+fn create_file_to_write<P: AsRef<Path>>(path: P) -> Result<File> {
     OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
-        .open(path)
-        .unwrap()
+        .open(&path)
+        .map_err(|e| Error::io(path, e))
+}
+
+#[inline]
+fn create_dir_all<P: AsRef<Path>>(path: P) -> Result<()> {
+    std::fs::create_dir_all(&path).map_err(|e| Error::io(path, e))
+}
+
+/// A directory under the system temporary directory, removed on drop.
+struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a new directory only this user can enter, picking another name whenever
+    /// one is taken, so that nothing already there is ever used.
+    fn new() -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        loop {
+            let name = format!(
+                "rr-mod-tool-{}-{:08x}-{}",
+                std::process::id(),
+                nanos,
+                COUNTER.fetch_add(1, AtomicOrdering::Relaxed)
+            );
+            let path = std::env::temp_dir().join(name);
+            match builder.create(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(Error::io(&path, e)),
+            }
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn has_magic_num<P: AsRef<Path>>(path: P, magic_num: &[u8; 4]) -> Result<bool> {
+    let mut file = open_file(&path)?;
+    let mut buf = [0u8; 4];
+    match file.read_exact(&mut buf) {
+        Ok(()) => Ok(&buf == magic_num),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(Error::io(path, e)),
+    }
+}
+
+#[inline]
+fn check_magic_num(buf: [u8; 4], magic_num: &[u8; 4]) -> Result<()> {
+    if &buf != magic_num {
+        return Err(Error::BadMagic {
+            expected: *magic_num,
+            found: buf,
+        });
+    }
+    Ok(())
+}
+
+#[inline]
+fn check_bounds(offset: u64, len: u64, size: u64) -> Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(Error::OffsetOutOfBounds { offset, len, size }),
+    }
+}
+
+#[inline]
+fn check_output_filename(filename: &str) -> Result<()> {
+    if filename.is_empty()
+        || filename == "."
+        || filename == ".."
+        || filename.contains(['/', '\\', '\0'])
+    {
+        return Err(Error::InvalidName(filename.to_string()));
+    }
+    Ok(())
+}
+
+/// Replaces the entry data at `offset`, `old_len` bytes followed by zeroes up to a
+/// multiple of `align_size`, with `new_data` padded the same way. Returns by how many
+/// bytes the data following it moved, always a multiple of `align_size`.
+fn splice_packed_file(
+    data: &mut Vec<u8>,
+    offset: u64,
+    old_len: u64,
+    new_data: &[u8],
+    align_size: u64,
+) -> Result<i64> {
+    check_bounds(offset, old_len, data.len() as u64)?;
+    let old_end = (offset + old_len + padding_zero_num(old_len, align_size)).min(data.len() as u64);
+    let zero_num = padding_zero_num(new_data.len() as u64, align_size);
+    let new_end = offset + new_data.len() as u64 + zero_num;
+    let padded = new_data
+        .iter()
+        .copied()
+        .chain(std::iter::repeat_n(0, zero_num as usize));
+    data.splice(offset as usize..old_end as usize, padded);
+    Ok(new_end as i64 - old_end as i64)
+}
+
+/// Moves a stored offset by `delta` after an entry was resized.
+#[inline]
+fn shift_offset(offset: u64, delta: i64) -> Result<u32> {
+    offset
+        .checked_add_signed(delta)
+        .and_then(|offset| u32::try_from(offset).ok())
+        .ok_or(Error::TooLarge)
+}
+
+/// Whether entry `other` at `other_offset` lies after entry `index` at `offset`, and
+/// so moves when that entry is resized. Empty entries may share an offset with the
+/// next one, in which case table order decides.
+#[inline]
+fn is_after(other: usize, other_offset: u64, index: usize, offset: u64) -> bool {
+    other_offset > offset || (other_offset == offset && other > index)
+}
+
+/// Checks that no two entries in `info_list` are unpacked to the same file.
+fn check_unique_filenames(info_list: &[PackedFileInfo]) -> Result<()> {
+    let mut filenames = HashSet::new();
+    for info in info_list {
+        if !filenames.insert(info.filename.as_str()) {
+            return Err(Error::Duplicate(info.filename.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Where the entries of a PACH or TEX archive sit when packing wouldn't put them there
+/// by itself, and the bytes around them, recorded by unpacking so that packing can
+/// write the archive back as it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DataLayout {
+    /// The offset of each entry from the start of the archive, in table order.
+    offsets: Vec<u64>,
+    /// The length of the whole archive.
+    len: u64,
+    /// Runs of bytes after the table that no entry covers, nonzero at both ends.
+    fill: Vec<(u64, Vec<u8>)>,
+}
+
+impl DataLayout {
+    /// Reads the layout of the entries in `info_list` from `reader`, or returns `None`
+    /// when packing gives it back anyway: the data of each entry follows the one before
+    /// it, aligned, from `data_offset` on, and everything else is zero.
+    fn read<R: Read + Seek>(
+        reader: &mut R,
+        info_list: &[PackedFileInfo],
+        data_offset: u64,
+        align_size: u64,
+    ) -> Result<Option<Self>> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        let mut is_packed = true;
+        let mut offset = data_offset;
+        let mut ranges = Vec::with_capacity(info_list.len() + 1);
+        for info in info_list {
+            check_bounds(info.offset, info.len, size)?;
+            is_packed &= info.offset == offset;
+            offset += info.len + padding_zero_num(info.len, align_size);
+            ranges.push((info.offset, info.offset + info.len));
+        }
+        is_packed &= size == offset;
+        ranges.push((size, size));
+        ranges.sort_unstable();
+
+        let mut fill = vec![];
+        let mut pos = data_offset;
+        for (start, end) in ranges {
+            if start > pos {
+                reader.seek(SeekFrom::Start(pos))?;
+                let mut buf = vec![0u8; (start - pos) as _];
+                reader.read_exact(&mut buf)?;
+                if let Some(first) = buf.iter().position(|b| *b != 0) {
+                    let last = buf.iter().rposition(|b| *b != 0).unwrap_or(first);
+                    fill.push((pos + first as u64, buf[first..=last].to_vec()));
+                }
+            }
+            pos = pos.max(end);
+        }
+        if is_packed && fill.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            offsets: info_list.iter().map(|info| info.offset).collect(),
+            len: size,
+            fill,
+        }))
+    }
+
+    /// The entries at their offsets and the fill, by offset, longest first on ties.
+    fn pieces<'a>(&'a self, entries: &[&'a [u8]]) -> Vec<(u64, &'a [u8])> {
+        let mut pieces: Vec<_> = self
+            .offsets
+            .iter()
+            .copied()
+            .zip(entries.iter().copied())
+            .collect();
+        pieces.extend(
+            self.fill
+                .iter()
+                .map(|(offset, bytes)| (*offset, &bytes[..])),
+        );
+        pieces.sort_by(|(o1, d1), (o2, d2)| o1.cmp(o2).then(d2.len().cmp(&d1.len())));
+        pieces
+    }
+
+    /// Checks that `entries`, in table order, can still be written at the recorded
+    /// offsets: from `data_offset` on, within the recorded length, and overlapping
+    /// neither the fill nor each other unless the bytes there are the same.
+    fn fits(&self, entries: &[&[u8]], data_offset: u64) -> bool {
+        if entries.len() != self.offsets.len() {
+            return false;
+        }
+        let mut covering: (u64, &[u8]) = (data_offset, &[]);
+        for (offset, data) in self.pieces(entries) {
+            let end = offset + data.len() as u64;
+            if offset < data_offset || end > self.len {
+                return false;
+            }
+            let covering_end = covering.0 + covering.1.len() as u64;
+            if offset >= covering_end {
+                covering = (offset, data);
+            } else if end > covering_end
+                || covering.1[(offset - covering.0) as usize..][..data.len()] != *data
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Writes `entries` and the fill from `data_offset` to the end of the archive, once
+    /// [`fits`](Self::fits) has accepted them.
+    fn write_data<W: Write>(
+        &self,
+        writer: &mut W,
+        entries: &[&[u8]],
+        data_offset: u64,
+    ) -> Result<()> {
+        let mut pos = data_offset;
+        for (offset, data) in self.pieces(entries) {
+            let end = offset + data.len() as u64;
+            if end <= pos {
+                continue;
+            }
+            write_padding_zeroes(writer, (offset - pos) as _)?;
+            writer.write_all(data)?;
+            pos = end;
+        }
+        write_padding_zeroes(writer, (self.len - pos) as _)
+    }
+
+    /// Records the layout in a manifest, as `offsets`, `len` and `[[fill]]` tables.
+    fn write_manifest(&self, manifest: &mut Table) {
+        let offsets = self.offsets.iter().map(|offset| Value::Hex(*offset));
+        manifest.set("offsets", Value::Array(offsets.collect()));
+        manifest.set("len", Value::Hex(self.len));
+        for (offset, bytes) in &self.fill {
+            let table = manifest.push_table("fill");
+            table.set("offset", Value::Hex(*offset));
+            table.set("bytes", Value::String(manifest::bytes_to_string(bytes)));
+        }
+    }
+
+    /// Reads a layout written by [`write_manifest`](Self::write_manifest), if any.
+    fn read_manifest(manifest: &Table) -> Result<Option<Self>> {
+        let values = match manifest.opt_array("offsets")? {
+            Some(values) => values,
+            None => return Ok(None),
+        };
+        let mut offsets = Vec::with_capacity(values.len());
+        for value in values {
+            match value {
+                Value::Integer(offset) => offsets.push(*offset),
+                _ => {
+                    return Err(Error::Malformed(
+                        "manifest: \"offsets\" must hold integers".to_string(),
+                    ))
+                }
+            }
+        }
+        let mut fill = vec![];
+        for table in manifest.tables("fill") {
+            let bytes = table.opt_byte_string("bytes")?.unwrap_or_default();
+            fill.push((table.u64("offset")?, bytes));
+        }
+        Ok(Some(Self {
+            offsets,
+            len: manifest.u64("len")?,
+            fill,
+        }))
+    }
+}
+
+/// Reads the data of one entry, checking that it lies within the input.
+fn read_packed_file<R: Read + Seek>(reader: &mut R, info: &PackedFileInfo) -> Result<Vec<u8>> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    check_bounds(info.offset, info.len, size)?;
+    reader.seek(SeekFrom::Start(info.offset))?;
+    let mut vec = vec![0u8; info.len as _];
+    reader.read_exact(&mut vec)?;
+    Ok(vec)
 }
 
 fn unpack_files<R: Read + Seek>(
     reader: &mut R,
     info_list: &[PackedFileInfo],
-    output_dir_path: &PathBuf,
-) {
+    output_dir_path: &Path,
+) -> Result<()> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    for info in info_list {
+        check_output_filename(&info.filename)?;
+        check_bounds(info.offset, info.len, size)?;
+    }
     for info in info_list {
-        reader.seek(SeekFrom::Start(info.offset as u64)).unwrap();
-
         let dst_path = output_dir_path.join(&info.filename);
-        let file = create_file_to_write(dst_path);
+        let file = create_file_to_write(&dst_path)?;
         let mut writer = BufWriter::new(file);
 
-        let mut vec = Vec::with_capacity(info.len as _);
-        unsafe { vec.set_len(info.len as _) };
-        reader.read_exact(&mut vec).unwrap();
-        writer.write_all(&vec).unwrap();
+        let vec = read_packed_file(reader, info)?;
+        writer
+            .write_all(&vec)
+            .and_then(|_| writer.flush())
+            .map_err(|e| Error::io(&dst_path, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn info(filename: &str, offset: u64, len: u64) -> PackedFileInfo {
+        PackedFileInfo {
+            filename: filename.to_string(),
+            offset,
+            len,
+        }
+    }
+
+    #[test]
+    fn files_are_ordered_listed_then_numeric_then_by_name() {
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-order", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        for name in ["b", "10", "100", "a", "9", "x", "__pach__.toml"].iter() {
+            write_file(dir.join(name), &[]).unwrap();
+        }
+        let path_list = list_files(&dir, None).unwrap();
+        let ordered = order_files(
+            &dir,
+            &["x".to_string(), "10".to_string()],
+            path_list.clone(),
+        );
+        let repeated = order_files(&dir, &["x".to_string(), "x".to_string()], path_list.clone());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names = |paths: &[PathBuf]| -> Vec<String> {
+            paths
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(names(&path_list), ["9", "10", "100", "a", "b", "x"]);
+        assert_eq!(names(&ordered.unwrap()), ["x", "10", "9", "100", "a", "b"]);
+        assert!(matches!(repeated, Err(Error::Duplicate(name)) if name == "x"));
+    }
+
+    #[test]
+    fn unique_filenames() {
+        assert!(check_unique_filenames(&[info("a", 0x20, 4), info("b", 0x20, 4)]).is_ok());
+        let duplicate = [info("a", 0x20, 4), info("a", 0x24, 4)];
+        assert!(matches!(
+            check_unique_filenames(&duplicate),
+            Err(Error::Duplicate(name)) if name == "a"
+        ));
+    }
+
+    #[test]
+    fn data_layout_is_only_recorded_when_packing_would_lose_it() {
+        let mut data = vec![0xff; 0x20];
+        data.extend_from_slice(&[1; 5]);
+        data.extend_from_slice(&[0; 3]);
+        data.extend_from_slice(&[3; 8]);
+        let packed = [info("a", 0x20, 5), info("b", 0x28, 0), info("c", 0x28, 8)];
+        let layout = DataLayout::read(&mut Cursor::new(&data), &packed, 0x20, 4).unwrap();
+        assert_eq!(layout, None);
+
+        // a gap, out of order entries, nonzero padding and trailing zeroes
+        data[0x25] = 0xee;
+        data.extend_from_slice(&[0; 4]);
+        let layout = [info("a", 0x28, 8), info("b", 0x20, 5)];
+        let layout = DataLayout::read(&mut Cursor::new(&data), &layout, 0x20, 4)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            layout,
+            DataLayout {
+                offsets: vec![0x28, 0x20],
+                len: 0x34,
+                fill: vec![(0x25, vec![0xee])],
+            }
+        );
+
+        let entries: [&[u8]; 2] = [&[3; 8], &[1; 5]];
+        assert!(layout.fits(&entries, 0x20));
+        let mut written = vec![0xff; 0x20];
+        layout.write_data(&mut written, &entries, 0x20).unwrap();
+        assert_eq!(written, data);
+
+        let mut manifest = Table::new();
+        layout.write_manifest(&mut manifest);
+        let manifest = manifest::parse(&manifest.to_string()).unwrap();
+        assert_eq!(
+            DataLayout::read_manifest(&manifest).unwrap(),
+            Some(layout.clone())
+        );
+
+        // entries that no longer fit where they were
+        assert!(!layout.fits(&[&[3; 8], &[1; 6]], 0x20));
+        assert!(!layout.fits(&[&[3; 13], &[1; 5]], 0x20));
+        assert!(!layout.fits(&[&[3; 8]], 0x20));
+        assert!(!layout.fits(&entries, 0x24));
+    }
+
+    #[test]
+    fn data_layout_allows_entries_sharing_bytes() {
+        let layout = DataLayout {
+            offsets: vec![0x10, 0x12],
+            len: 0x14,
+            fill: vec![],
+        };
+        assert!(layout.fits(&[b"abcd", b"cd"], 0x10));
+        assert!(!layout.fits(&[b"abcd", b"ce"], 0x10));
+        let mut written = vec![];
+        layout
+            .write_data(&mut written, &[b"abcd", b"cd"], 0x10)
+            .unwrap();
+        assert_eq!(written, b"abcd");
+    }
+
+    #[test]
+    fn splice_packed_file_keeps_alignment() {
+        // header, then "abcde" padded to 8 and "fg" padded to 4
+        let mut original = b"HEAD".to_vec();
+        original.extend_from_slice(b"abcde\0\0\0fg\0\0");
+
+        let mut data = original.clone();
+        let delta = splice_packed_file(&mut data, 4, 5, b"0123456789", 4).unwrap();
+        assert_eq!(delta, 4);
+        assert_eq!(data, b"HEAD0123456789\0\0fg\0\0");
+
+        let mut data = original.clone();
+        let delta = splice_packed_file(&mut data, 4, 5, b"x", 4).unwrap();
+        assert_eq!(delta, -4);
+        assert_eq!(data, b"HEADx\0\0\0fg\0\0");
+
+        // the last entry may be stored without its padding
+        let mut data = b"HEADabcde".to_vec();
+        let delta = splice_packed_file(&mut data, 4, 5, b"xy", 4).unwrap();
+        assert_eq!(delta, -1);
+        assert_eq!(data, b"HEADxy\0\0");
+
+        let mut data = original;
+        assert!(splice_packed_file(&mut data, 12, 8, b"", 4).is_err());
+    }
+
+    #[test]
+    fn shift_offset_stays_in_range() {
+        assert_eq!(shift_offset(0x100, 0x20).unwrap(), 0x120);
+        assert_eq!(shift_offset(0x100, -0x100).unwrap(), 0);
+        assert!(matches!(shift_offset(0x100, -0x101), Err(Error::TooLarge)));
+        assert!(matches!(
+            shift_offset(u32::MAX as u64, 1),
+            Err(Error::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn is_after_breaks_ties_by_table_order() {
+        assert!(is_after(0, 0x30, 1, 0x20));
+        assert!(!is_after(2, 0x10, 1, 0x20));
+        // an empty entry shares its offset with the entry after it
+        assert!(is_after(2, 0x20, 1, 0x20));
+        assert!(!is_after(0, 0x20, 1, 0x20));
+    }
+
+    #[test]
+    fn replace_file_renames_over_the_original() {
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-replace", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("archive");
+        write_file(&path, b"old data").unwrap();
+        replace_file(&path, b"new").unwrap();
+        let data = read_file(&path).unwrap();
+        let names: Vec<_> = read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert!(replace_file(dir.join("missing"), b"new").is_err());
+        let names_after_failure = read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(data, b"new");
+        assert_eq!(names, ["archive"]);
+        assert_eq!(names_after_failure, 1);
+    }
+
+    #[test]
+    fn endian_guess_prefers_the_smaller_nonzero_value() {
+        assert_eq!(Endian::guess([0, 0, 0, 1]), Endian::Big);
+        assert_eq!(Endian::guess([0, 0, 1, 0]), Endian::Big);
+        assert_eq!(Endian::guess([1, 0, 0, 0]), Endian::Little);
+        assert_eq!(Endian::guess([0, 1, 0, 0]), Endian::Little);
+        // ties and zero go to little-endian
+        assert_eq!(Endian::guess([1, 0, 0, 1]), Endian::Little);
+        assert_eq!(Endian::guess([0; 4]), Endian::Little);
     }
 }
@@ -0,0 +1,350 @@
+// Archive contents as read from the entry tables, without extracting any data.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::{Endian, PackedFileInfo};
+
+/// The header and entry table of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    /// The format name, e.g. `pach`.
+    pub format: String,
+    pub endian: Endian,
+    /// Header fields worth showing, by name.
+    pub header: Vec<(String, u64)>,
+    /// Entries grouped by divider; formats without dividers have one section.
+    pub sections: Vec<ListSection>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListSection {
+    /// `None` for entries listed before the first divider.
+    pub divider: Option<ListDivider>,
+    pub entries: Vec<ListEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListDivider {
+    pub name: String,
+    pub unknown_field: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    /// The position of the entry in the table, dividers not counted.
+    pub index: usize,
+    /// The file name `unpack` writes the entry to.
+    pub name: String,
+    /// Where the entry's data starts in the archive.
+    pub offset: u64,
+    pub len: u64,
+    /// The zeroes between the end of the data and the next aligned offset.
+    pub padding: u64,
+}
+
+/// How [`Listing::write`] prints a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ListStyle {
+    /// Entries indented under their dividers.
+    #[default]
+    Tree,
+    /// One row per divider or entry, in aligned columns.
+    Table,
+    Json,
+}
+
+impl ListStyle {
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            ListStyle::Tree => "tree",
+            ListStyle::Table => "table",
+            ListStyle::Json => "json",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tree" => Some(ListStyle::Tree),
+            "table" => Some(ListStyle::Table),
+            "json" => Some(ListStyle::Json),
+            _ => None,
+        }
+    }
+}
+
+impl Listing {
+    /// Creates a listing without header fields or entries.
+    pub(crate) fn new(format: &str, endian: Endian) -> Self {
+        Self {
+            format: format.to_string(),
+            endian,
+            header: vec![],
+            sections: vec![],
+        }
+    }
+
+    /// Adds a section without divider holding `entries`.
+    pub(crate) fn push_entries(&mut self, entries: Vec<ListEntry>) {
+        self.sections.push(ListSection {
+            divider: None,
+            entries,
+        });
+    }
+
+    /// Iterates over all entries in table order.
+    pub fn entries(&self) -> impl Iterator<Item = &ListEntry> {
+        self.sections.iter().flat_map(|s| &s.entries)
+    }
+
+    /// Prints the listing of the archive named `title` in the given style.
+    pub fn write<W: Write>(&self, writer: &mut W, title: &str, style: ListStyle) -> io::Result<()> {
+        let s = match style {
+            ListStyle::Tree => self.to_tree(title),
+            ListStyle::Table => self.to_table(),
+            ListStyle::Json => self.to_json(title),
+        };
+        writer.write_all(s.as_bytes())
+    }
+
+    fn to_tree(&self, title: &str) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "{} ({}, {})", title, self.format, self.endian.name());
+        for (name, value) in &self.header {
+            let _ = writeln!(s, "  {}: {:#x}", name, value);
+        }
+        for section in &self.sections {
+            let indent = match &section.divider {
+                Some(divider) => {
+                    let _ = writeln!(
+                        s,
+                        "  [{}] unknown field {:#010x}",
+                        divider.name, divider.unknown_field
+                    );
+                    "    "
+                }
+                None => "  ",
+            };
+            for entry in &section.entries {
+                let _ = writeln!(
+                    s,
+                    "{}{} {}: offset {:#x}, length {:#x}, padding {:#x}",
+                    indent, entry.index, entry.name, entry.offset, entry.len, entry.padding
+                );
+            }
+        }
+        s
+    }
+
+    fn to_table(&self) -> String {
+        let name_width = self
+            .entries()
+            .map(|e| e.name.len())
+            .chain(
+                self.sections
+                    .iter()
+                    .flat_map(|s| &s.divider)
+                    .map(|d| d.name.len()),
+            )
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        let mut s = String::new();
+        for (name, value) in &self.header {
+            let _ = writeln!(s, "{}: {:#x}", name, value);
+        }
+        let _ = writeln!(
+            s,
+            "{:>5}  {:<w$}  {:>10}  {:>10}  {:>8}",
+            "index",
+            "name",
+            "offset",
+            "length",
+            "padding",
+            w = name_width
+        );
+        for section in &self.sections {
+            if let Some(divider) = &section.divider {
+                let _ = writeln!(
+                    s,
+                    "{:>5}  {:<w$}  unknown field {:#010x}",
+                    "-",
+                    divider.name,
+                    divider.unknown_field,
+                    w = name_width
+                );
+            }
+            for entry in &section.entries {
+                let _ = writeln!(
+                    s,
+                    "{:>5}  {:<w$}  {:>10}  {:>10}  {:>8}",
+                    entry.index,
+                    entry.name,
+                    format!("{:#x}", entry.offset),
+                    format!("{:#x}", entry.len),
+                    format!("{:#x}", entry.padding),
+                    w = name_width
+                );
+            }
+        }
+        s
+    }
+
+    fn to_json(&self, title: &str) -> String {
+        let mut s = String::new();
+        let _ = write!(
+            s,
+            "{{\"file\":{},\"format\":{},\"endian\":{},\"header\":{{",
+            json_string(title),
+            json_string(&self.format),
+            json_string(self.endian.name())
+        );
+        for (i, (name, value)) in self.header.iter().enumerate() {
+            let sep = if i > 0 { "," } else { "" };
+            let _ = write!(s, "{}{}:{}", sep, json_string(name), value);
+        }
+        s.push_str("},\"sections\":[");
+        for (i, section) in self.sections.iter().enumerate() {
+            if i > 0 {
+                s.push(',');
+            }
+            s.push_str("{\"divider\":");
+            match &section.divider {
+                Some(divider) => {
+                    let _ = write!(
+                        s,
+                        "{{\"name\":{},\"unknown_field\":{}}}",
+                        json_string(&divider.name),
+                        divider.unknown_field
+                    );
+                }
+                None => s.push_str("null"),
+            }
+            s.push_str(",\"entries\":[");
+            for (j, entry) in section.entries.iter().enumerate() {
+                if j > 0 {
+                    s.push(',');
+                }
+                let _ = write!(
+                    s,
+                    "{{\"index\":{},\"name\":{},\"offset\":{},\"length\":{},\"padding\":{}}}",
+                    entry.index,
+                    json_string(&entry.name),
+                    entry.offset,
+                    entry.len,
+                    entry.padding
+                );
+            }
+            s.push_str("]}");
+        }
+        s.push_str("]}\n");
+        s
+    }
+}
+
+impl ListEntry {
+    pub(crate) fn from_info(index: usize, info: &PackedFileInfo, padding: u64) -> Self {
+        Self {
+            index,
+            name: info.filename.clone(),
+            offset: info.offset,
+            len: info.len,
+            padding,
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(result, "\\u{:04x}", c as u32);
+            }
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing() -> Listing {
+        let mut listing = Listing::new("epac", Endian::Big);
+        listing.header.push(("data offset".to_string(), 0x4000));
+        listing.push_entries(vec![ListEntry {
+            index: 0,
+            name: "BGM".to_string(),
+            offset: 0x4000,
+            len: 0x100,
+            padding: 0,
+        }]);
+        listing.sections.push(ListSection {
+            divider: Some(ListDivider {
+                name: "ST01".to_string(),
+                unknown_field: 0x10,
+            }),
+            entries: vec![ListEntry {
+                index: 1,
+                name: "a\"b\\c".to_string(),
+                offset: 0x4800,
+                len: 0x20,
+                padding: 0x7e0,
+            }],
+        });
+        listing
+    }
+
+    fn written(style: ListStyle) -> String {
+        let mut output = vec![];
+        listing().write(&mut output, "x.epac", style).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn writes_tree() {
+        assert_eq!(
+            written(ListStyle::Tree),
+            "x.epac (epac, big)\n\
+             \x20 data offset: 0x4000\n\
+             \x20 0 BGM: offset 0x4000, length 0x100, padding 0x0\n\
+             \x20 [ST01] unknown field 0x00000010\n\
+             \x20   1 a\"b\\c: offset 0x4800, length 0x20, padding 0x7e0\n"
+        );
+    }
+
+    #[test]
+    fn writes_table() {
+        assert_eq!(
+            written(ListStyle::Table),
+            "data offset: 0x4000\n\
+             index  name       offset      length   padding\n\
+             \x20   0  BGM        0x4000       0x100       0x0\n\
+             \x20   -  ST01   unknown field 0x00000010\n\
+             \x20   1  a\"b\\c      0x4800        0x20     0x7e0\n"
+        );
+    }
+
+    #[test]
+    fn writes_json() {
+        assert_eq!(
+            written(ListStyle::Json),
+            concat!(
+                r#"{"file":"x.epac","format":"epac","endian":"big","header":{"data offset":16384},"#,
+                r#""sections":[{"divider":null,"entries":[{"index":0,"name":"BGM","offset":16384,"#,
+                r#""length":256,"padding":0}]},{"divider":{"name":"ST01","unknown_field":16},"#,
+                r#""entries":[{"index":1,"name":"a\"b\\c","offset":18432,"length":32,"#,
+                r#""padding":2016}]}]}"#,
+                "\n"
+            )
+        );
+        assert_eq!(json_string("\t\u{1f}é"), r#""\u0009\u001fé""#);
+    }
+}
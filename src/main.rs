@@ -1,78 +1,368 @@
 use std::env::args;
-use std::path::PathBuf;
+use std::fs::read;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use rr_mod_tool::bpe::{self, Level, SubstituteOrder, TieBreak};
+use rr_mod_tool::edit::{add, remove, replace};
+use rr_mod_tool::extract::{extract, extract_data};
+use rr_mod_tool::format::Registry;
+use rr_mod_tool::list::ListStyle;
+use rr_mod_tool::tree::{pack_recursive, unpack_recursive};
+use rr_mod_tool::verify::verify_roundtrip;
+use rr_mod_tool::{Endian, Error, PackOptions, Result, UnpackOptions};
+
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO_ERROR: i32 = 3;
+const EXIT_FORMAT_ERROR: i32 = 4;
+const EXIT_MISMATCH: i32 = 5;
 
 fn main() {
+    let registry = Registry::default();
     let mut args = args().skip(1);
-    match args.next() {
-        Some(s) if s == "-p" => {
-            work_in_pack_mode(args);
+    let result = match args.next() {
+        Some(s) if s == "-p" => work_in_pack_mode(&registry, args),
+        Some(s) if s == "-u" => work_in_unpack_mode(&registry, args),
+        Some(s) if s == "--verify-roundtrip" => work_in_verify_mode(&registry, args),
+        Some(s) if s == "--analyze-bpe" => work_in_analyze_mode(args),
+        Some(s) if s == "list" || s == "info" => work_in_list_mode(&registry, args),
+        Some(s) if s == "extract" => work_in_extract_mode(&registry, args),
+        Some(s) if s == "replace" => work_in_replace_mode(&registry, args),
+        Some(s) if s == "add" => work_in_add_mode(&registry, args),
+        Some(s) if s == "remove" => work_in_remove_mode(&registry, args),
+        _ => None,
+    };
+    match result {
+        None => {
+            usage(&registry);
+            exit(EXIT_USAGE);
         }
-        Some(s) if s == "-u" => {
-            work_in_unpack_mode(args);
+        Some(Err(e)) => {
+            eprintln!("error: {}", e);
+            exit(exit_code(&e));
         }
-        _ => {
-            usage();
-            return;
+        Some(Ok(())) => {}
+    }
+}
+
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Io { .. } => EXIT_IO_ERROR,
+        _ => EXIT_FORMAT_ERROR,
+    }
+}
+
+fn work_in_pack_mode<I: Iterator<Item = String>>(
+    registry: &Registry,
+    mut args: I,
+) -> Option<Result<()>> {
+    let mut options = PackOptions::default();
+    let mut recursive = false;
+    let mut arg = args.next()?;
+    loop {
+        if arg == "-r" || arg == "--recursive" {
+            recursive = true;
+        } else if arg == "--drop-empty" {
+            options.drop_empty = true;
+        } else if arg == "--endian" {
+            options.endian = Some(Endian::from_name(&args.next()?)?);
+        } else if arg == "--level" {
+            options.level = Level::from_name(&args.next()?)?;
+        } else if arg == "--ties" {
+            options.choices.tie_break = TieBreak::from_name(&args.next()?)?;
+        } else if arg == "--substitutes" {
+            options.choices.substitute = SubstituteOrder::from_name(&args.next()?)?;
+        } else {
+            break;
         }
-    };
+        arg = args.next()?;
+    }
+    if recursive {
+        let src_path = PathBuf::from(arg);
+        let dst_path = PathBuf::from(args.next()?);
+        return Some(pack_recursive(registry, &src_path, &dst_path, &options));
+    }
+    let format = registry.get(&arg)?;
+    let src_path = PathBuf::from(args.next()?);
+    let dst_path = PathBuf::from(args.next()?);
+    Some(format.pack(&src_path, &dst_path, &options))
 }
 
-fn work_in_pack_mode<I: Iterator<Item = String>>(mut args: I) {
-    let func = match args.next() {
-        Some(s) if s == "tex" => rr_mod_tool::tex::pack,
-        Some(s) if s == "bpe" => rr_mod_tool::bpe::pack,
-        Some(s) if s == "pach" => rr_mod_tool::pach::pack,
-        Some(s) if s == "epac" => rr_mod_tool::epac::pack,
-        _ => {
-            usage();
-            return;
+fn work_in_unpack_mode<I: Iterator<Item = String>>(
+    registry: &Registry,
+    mut args: I,
+) -> Option<Result<()>> {
+    let mut options = UnpackOptions::default();
+    let mut recursive = false;
+    let mut arg = args.next()?;
+    loop {
+        if arg == "-r" || arg == "--recursive" {
+            recursive = true;
+        } else if arg == "--endian" {
+            options.endian = Some(Endian::from_name(&args.next()?)?);
+        } else if arg == "--strict" {
+            options.strict = true;
+        } else {
+            break;
         }
-    };
-    let src_path = match args.next() {
-        Some(s) => PathBuf::from(s),
-        None => {
-            usage();
-            return;
+        arg = args.next()?;
+    }
+    let src_path = PathBuf::from(arg);
+    let dst_path = PathBuf::from(args.next()?);
+    if recursive {
+        return Some(unpack_recursive(registry, &src_path, &dst_path, &options));
+    }
+    Some(unpack(registry, src_path, dst_path, &options))
+}
+
+fn work_in_verify_mode<I: Iterator<Item = String>>(
+    registry: &Registry,
+    mut args: I,
+) -> Option<Result<()>> {
+    let src_path = PathBuf::from(args.next()?);
+    match verify_roundtrip(registry, &src_path) {
+        Ok(None) => {
+            println!("{}: OK", src_path.display());
+            Some(Ok(()))
         }
-    };
-    let dst_path = match args.next() {
-        Some(s) => PathBuf::from(s),
-        None => {
-            usage();
-            return;
+        Ok(Some(mismatch)) => {
+            println!("{}: {}", src_path.display(), mismatch);
+            exit(EXIT_MISMATCH);
         }
+        Err(e) => Some(Err(e)),
+    }
+}
+
+fn work_in_analyze_mode<I: Iterator<Item = String>>(mut args: I) -> Option<Result<()>> {
+    let src_path = PathBuf::from(args.next()?);
+    let analysis = match read(&src_path) {
+        Ok(data) => bpe::analyze(&data),
+        Err(e) => Err(Error::Io {
+            path: Some(src_path),
+            source: e,
+        }),
     };
-    func(src_path, dst_path);
+    let result = analysis.and_then(|analysis| {
+        let mut stdout = io::stdout().lock();
+        for (index, block) in analysis.blocks.iter().enumerate() {
+            writeln!(stdout, "{}: {}", index, block)?;
+            for substitution in &block.substitutions {
+                writeln!(stdout, "    {}", substitution)?;
+            }
+        }
+        let choices = analysis.matching_choices();
+        if choices.is_empty() {
+            let replay = if analysis.blocks.iter().all(|b| b.replayed) {
+                "replaying the cuts and substitutions above does"
+            } else {
+                "neither does replaying the cuts and substitutions above"
+            };
+            writeln!(stdout, "no choices reproduce every block, {}", replay)?;
+        }
+        for c in choices {
+            writeln!(stdout, "reproduced by: {}", c)?;
+        }
+        Ok(())
+    });
+    Some(result)
 }
 
-fn work_in_unpack_mode<I: Iterator<Item = String>>(mut args: I) {
-    let src_path = match args.next() {
-        Some(s) => PathBuf::from(s),
-        None => {
-            usage();
-            return;
+fn work_in_list_mode<I: Iterator<Item = String>>(
+    registry: &Registry,
+    mut args: I,
+) -> Option<Result<()>> {
+    let mut options = UnpackOptions::default();
+    let mut style = ListStyle::default();
+    let mut arg = args.next()?;
+    loop {
+        if arg == "--style" {
+            style = ListStyle::from_name(&args.next()?)?;
+        } else if arg == "--endian" {
+            options.endian = Some(Endian::from_name(&args.next()?)?);
+        } else {
+            break;
         }
+        arg = args.next()?;
+    }
+    let src_path = PathBuf::from(arg);
+    let listing = match registry.detect_file(&src_path) {
+        Ok(Some(format)) => format.list(&src_path, &options),
+        Ok(None) => Err(Error::UnknownFormat),
+        Err(e) => Err(e),
     };
-    let dst_path = match args.next() {
-        Some(s) => PathBuf::from(s),
-        None => {
-            usage();
-            return;
+    let result = listing.and_then(|listing| {
+        let title = src_path.display().to_string();
+        let mut stdout = io::stdout().lock();
+        listing.write(&mut stdout, &title, style)?;
+        Ok(())
+    });
+    Some(result)
+}
+
+fn work_in_extract_mode<I: Iterator<Item = String>>(
+    registry: &Registry,
+    mut args: I,
+) -> Option<Result<()>> {
+    let mut options = UnpackOptions::default();
+    let mut positional = vec![];
+    let mut dst_path = None;
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            dst_path = Some(PathBuf::from(args.next()?));
+        } else if arg == "--endian" {
+            options.endian = Some(Endian::from_name(&args.next()?)?);
+        } else if arg == "--strict" {
+            options.strict = true;
+        } else {
+            positional.push(arg);
         }
-    };
-    if rr_mod_tool::epac::detect_format(&src_path) {
-        rr_mod_tool::epac::unpack(src_path, dst_path);
-    } else if rr_mod_tool::pach::detect_format(&src_path) {
-        rr_mod_tool::pach::unpack(src_path, dst_path);
-    } else if rr_mod_tool::bpe::detect_format(&src_path) {
-        rr_mod_tool::bpe::unpack(src_path, dst_path);
-    } else {
-        rr_mod_tool::tex::unpack(src_path, dst_path);
+    }
+    if positional.len() != 2 {
+        return None;
+    }
+    let src_path = PathBuf::from(&positional[0]);
+    if dst_path.as_deref() == Some(Path::new("-")) {
+        let result = extract_data(registry, &src_path, &positional[1], &options)
+            .and_then(|(_, data)| Ok(io::stdout().lock().write_all(&data)?));
+        return Some(result);
+    }
+    let result = extract(
+        registry,
+        &src_path,
+        &positional[1],
+        dst_path.as_deref(),
+        &options,
+    );
+    Some(result.map(|path| println!("{}", path.display())))
+}
+
+fn work_in_replace_mode<I: Iterator<Item = String>>(
+    registry: &Registry,
+    mut args: I,
+) -> Option<Result<()>> {
+    let mut options = UnpackOptions::default();
+    let mut arg = args.next()?;
+    loop {
+        if arg == "--endian" {
+            options.endian = Some(Endian::from_name(&args.next()?)?);
+        } else if arg == "--strict" {
+            options.strict = true;
+        } else {
+            break;
+        }
+        arg = args.next()?;
+    }
+    let src_path = PathBuf::from(arg);
+    let selector = args.next()?;
+    let new_path = PathBuf::from(args.next()?);
+    let result = read(&new_path)
+        .map_err(|e| Error::Io {
+            path: Some(new_path),
+            source: e,
+        })
+        .and_then(|new_data| replace(registry, &src_path, &selector, &new_data, &options));
+    Some(result)
+}
+
+fn work_in_add_mode<I: Iterator<Item = String>>(
+    registry: &Registry,
+    mut args: I,
+) -> Option<Result<()>> {
+    let mut options = UnpackOptions::default();
+    let mut divider = None;
+    let mut arg = args.next()?;
+    loop {
+        if arg == "--endian" {
+            options.endian = Some(Endian::from_name(&args.next()?)?);
+        } else if arg == "--strict" {
+            options.strict = true;
+        } else if arg == "--divider" {
+            divider = Some(args.next()?);
+        } else {
+            break;
+        }
+        arg = args.next()?;
+    }
+    let src_path = PathBuf::from(arg);
+    let selector = args.next()?;
+    let new_path = PathBuf::from(args.next()?);
+    let result = read(&new_path)
+        .map_err(|e| Error::Io {
+            path: Some(new_path),
+            source: e,
+        })
+        .and_then(|new_data| {
+            add(
+                registry,
+                &src_path,
+                &selector,
+                &new_data,
+                divider.as_deref(),
+                &options,
+            )
+        });
+    Some(result)
+}
+
+fn work_in_remove_mode<I: Iterator<Item = String>>(
+    registry: &Registry,
+    mut args: I,
+) -> Option<Result<()>> {
+    let mut options = UnpackOptions::default();
+    let mut arg = args.next()?;
+    loop {
+        if arg == "--endian" {
+            options.endian = Some(Endian::from_name(&args.next()?)?);
+        } else if arg == "--strict" {
+            options.strict = true;
+        } else {
+            break;
+        }
+        arg = args.next()?;
+    }
+    let src_path = PathBuf::from(arg);
+    let selector = args.next()?;
+    Some(remove(registry, &src_path, &selector, &options))
+}
+
+fn unpack(
+    registry: &Registry,
+    src_path: PathBuf,
+    dst_path: PathBuf,
+    options: &UnpackOptions,
+) -> Result<()> {
+    match registry.detect_file(&src_path)? {
+        Some(format) => format.unpack(&src_path, &dst_path, options),
+        None => Err(Error::UnknownFormat),
     }
 }
 
-fn usage() {
-    println!("Usage: ./rr-mod-tool -p format src dst");
-    println!("   or: ./rr-mod-tool -u src dst");
-    println!("Available formats: tex, bpe, pach, epac.")
+fn usage(registry: &Registry) {
+    println!("Usage: ./rr-mod-tool -p [options] format src dst");
+    println!("   or: ./rr-mod-tool -p [options] --recursive src dst");
+    println!("   or: ./rr-mod-tool -u [options] src dst");
+    println!("   or: ./rr-mod-tool --verify-roundtrip src");
+    println!("   or: ./rr-mod-tool --analyze-bpe src");
+    println!("   or: ./rr-mod-tool list [--style tree|table|json] [--endian little|big] src");
+    println!(
+        "   or: ./rr-mod-tool extract [--endian little|big] [--strict] src selector [-o dst|-]"
+    );
+    println!("   or: ./rr-mod-tool replace [--endian little|big] [--strict] src selector new_file");
+    println!(
+        "   or: ./rr-mod-tool add [--endian little|big] [--strict] [--divider name] src selector new_file"
+    );
+    println!("   or: ./rr-mod-tool remove [--endian little|big] [--strict] src selector");
+    println!("Selectors are entry names joined by '/', e.g. 0A1B/3/body.dds, where '#N'");
+    println!("stands for the entry at index N; for add, the last name is the new entry's.");
+    println!("Replace moves only the entries after the one replaced. Add and remove write");
+    println!("the whole archive again, so a PACH or TEX archive loses any gaps and bytes");
+    println!("between its other entries, as packing its files without a manifest would.");
+    println!("New EPAC entry and divider names are 4 bytes, padding spaces included, e.g. 'A   '.");
+    println!("BPE streams on the way are compressed again at --level normal, default choices.");
+    println!("Pack options: --drop-empty, --endian little|big, --level normal|max,");
+    println!("              --ties greatest|least, --substitutes lowest|highest.");
+    println!("Unpack options: --recursive, --endian little|big, --strict.");
+    let names: Vec<_> = registry.formats().map(|f| f.name()).collect();
+    println!("Available formats: {}.", names.join(", "))
 }
@@ -0,0 +1,573 @@
+// Manifests are written next to unpacked files to remember what the file names can't:
+// container formats, entry order, unknown header fields, etc.
+//
+// They use a small subset of TOML: key/value pairs and arrays of tables (`[[name]]`,
+// `[[name.child]]`), with string, integer, boolean and array values.
+
+use std::convert::TryFrom;
+use std::fmt::{self, Write as _};
+use std::path::Path;
+
+use crate::{read_file, write_file, Endian, Error, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    String(String),
+    Integer(u64),
+    /// An integer written in hexadecimal, read back as `Integer`.
+    Hex(u64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Table {
+    values: Vec<(String, Value)>,
+    arrays: Vec<(String, Vec<Table>)>,
+}
+
+impl Table {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn set<K: Into<String>>(&mut self, key: K, value: Value) -> &mut Self {
+        let key = key.into();
+        match self.values.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.values.push((key, value)),
+        }
+        self
+    }
+
+    /// Appends a new table to the array of tables `name`.
+    pub(crate) fn push_table(&mut self, name: &str) -> &mut Table {
+        let index = match self.arrays.iter().position(|(n, _)| n == name) {
+            Some(i) => i,
+            None => {
+                self.arrays.push((name.to_string(), vec![]));
+                self.arrays.len() - 1
+            }
+        };
+        let tables = &mut self.arrays[index].1;
+        tables.push(Table::new());
+        tables.last_mut().unwrap()
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        self.values.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub(crate) fn tables(&self, name: &str) -> &[Table] {
+        match self.arrays.iter().find(|(n, _)| n == name) {
+            Some((_, tables)) => tables,
+            None => &[],
+        }
+    }
+
+    pub(crate) fn opt_str(&self, key: &str) -> Result<Option<&str>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(type_error(key, "a string")),
+        }
+    }
+
+    pub(crate) fn str(&self, key: &str) -> Result<&str> {
+        self.opt_str(key)?.ok_or_else(|| missing_error(key))
+    }
+
+    pub(crate) fn opt_u64(&self, key: &str) -> Result<Option<u64>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) | Some(Value::Hex(n)) => Ok(Some(*n)),
+            Some(_) => Err(type_error(key, "an integer")),
+        }
+    }
+
+    pub(crate) fn u64(&self, key: &str) -> Result<u64> {
+        self.opt_u64(key)?.ok_or_else(|| missing_error(key))
+    }
+
+    pub(crate) fn u32(&self, key: &str) -> Result<u32> {
+        let n = self.u64(key)?;
+        if n > u32::MAX as u64 {
+            return Err(type_error(key, "a 32-bit integer"));
+        }
+        Ok(n as u32)
+    }
+
+    pub(crate) fn opt_array(&self, key: &str) -> Result<Option<&[Value]>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Array(values)) => Ok(Some(values)),
+            Some(_) => Err(type_error(key, "an array")),
+        }
+    }
+
+    /// Reads a byte order written as `"little"` or `"big"`.
+    pub(crate) fn opt_endian(&self, key: &str) -> Result<Option<Endian>> {
+        match self.opt_str(key)? {
+            None => Ok(None),
+            Some(name) => Endian::from_name(name)
+                .map(Some)
+                .ok_or_else(|| type_error(key, "\"little\" or \"big\"")),
+        }
+    }
+
+    /// Reads a string holding raw bytes, see [`bytes_to_string`].
+    pub(crate) fn bytes<const N: usize>(&self, key: &str) -> Result<[u8; N]> {
+        let s = self.str(key)?;
+        let mut buf = [0u8; N];
+        match string_to_bytes(s) {
+            Some(bytes) if bytes.len() == N => {
+                buf.copy_from_slice(&bytes);
+                Ok(buf)
+            }
+            _ => Err(type_error(key, &format!("a string of {} bytes", N))),
+        }
+    }
+
+    /// Reads a string holding raw bytes of any length, see [`bytes_to_string`].
+    pub(crate) fn opt_byte_string(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.opt_str(key)? {
+            None => Ok(None),
+            Some(s) => string_to_bytes(s)
+                .map(Some)
+                .ok_or_else(|| type_error(key, "a string of bytes")),
+        }
+    }
+
+    fn write_to(&self, out: &mut String, prefix: &str) -> fmt::Result {
+        for (key, value) in &self.values {
+            write_key(out, key)?;
+            out.push_str(" = ");
+            write_value(out, value)?;
+            out.push('\n');
+        }
+        for (name, tables) in &self.arrays {
+            let name = if prefix.is_empty() {
+                name.clone()
+            } else {
+                format!("{}.{}", prefix, name)
+            };
+            for table in tables {
+                writeln!(out, "\n[[{}]]", name)?;
+                table.write_to(out, &name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = String::new();
+        self.write_to(&mut out, "")?;
+        f.write_str(&out)
+    }
+}
+
+#[inline]
+fn missing_error(key: &str) -> Error {
+    Error::Malformed(format!("manifest: missing key {:?}", key))
+}
+
+#[inline]
+fn type_error(key: &str, expected: &str) -> Error {
+    Error::Malformed(format!("manifest: {:?} must be {}", key, expected))
+}
+
+/// Maps raw bytes to a string one char per byte, so that names which aren't valid
+/// UTF-8 survive a round trip through a manifest.
+pub(crate) fn bytes_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+pub(crate) fn string_to_bytes(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c as u32).ok()).collect()
+}
+
+pub(crate) fn read<P: AsRef<Path>>(path: P) -> Result<Table> {
+    let data = read_file(&path)?;
+    let text = String::from_utf8(data)
+        .map_err(|_| Error::Malformed("manifest: not valid UTF-8".to_string()))?;
+    parse(&text)
+}
+
+pub(crate) fn write<P: AsRef<Path>>(path: P, comment: &str, table: &Table) -> Result<()> {
+    let mut text = String::new();
+    for line in comment.lines() {
+        text.push_str("# ");
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push_str(&table.to_string());
+    write_file(path, text.as_bytes())
+}
+
+fn write_key(out: &mut String, key: &str) -> fmt::Result {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        out.push_str(key);
+        Ok(())
+    } else {
+        write_string(out, key)
+    }
+}
+
+fn write_value(out: &mut String, value: &Value) -> fmt::Result {
+    match value {
+        Value::String(s) => write_string(out, s),
+        Value::Integer(n) => write!(out, "{}", n),
+        Value::Hex(n) => write!(out, "{:#010x}", n),
+        Value::Boolean(b) => write!(out, "{}", b),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_value(out, value)?;
+            }
+            out.push(']');
+            Ok(())
+        }
+    }
+}
+
+fn write_string(out: &mut String, s: &str) -> fmt::Result {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04X}", c as u32)?,
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    Ok(())
+}
+
+pub(crate) fn parse(text: &str) -> Result<Table> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    let mut root = Table::new();
+    let mut current: Vec<String> = vec![];
+    loop {
+        parser.skip_whitespace_and_comments(true);
+        match parser.peek() {
+            None => break,
+            Some('[') => {
+                if parser.peek_at(1) != Some('[') {
+                    return Err(parser.error("only arrays of tables are supported"));
+                }
+                parser.pos += 2;
+                let mut path = vec![];
+                loop {
+                    parser.skip_whitespace_and_comments(false);
+                    path.push(parser.parse_key()?);
+                    parser.skip_whitespace_and_comments(false);
+                    match parser.next() {
+                        Some('.') => continue,
+                        Some(']') if parser.peek() == Some(']') => {
+                            parser.pos += 1;
+                            break;
+                        }
+                        _ => return Err(parser.error("expected ]]")),
+                    }
+                }
+                let (name, parents) = path.split_last().unwrap();
+                let parent = table_at(&mut root, parents)
+                    .ok_or_else(|| parser.error("parent table not defined"))?;
+                parent.push_table(name);
+                current = path;
+            }
+            Some(_) => {
+                let key = parser.parse_key()?;
+                parser.skip_whitespace_and_comments(false);
+                if parser.next() != Some('=') {
+                    return Err(parser.error("expected ="));
+                }
+                parser.skip_whitespace_and_comments(false);
+                let value = parser.parse_value()?;
+                let table = table_at(&mut root, &current).unwrap();
+                if table.get(&key).is_some() {
+                    return Err(parser.error(&format!("duplicate key {:?}", key)));
+                }
+                table.set(key, value);
+            }
+        }
+        parser.skip_whitespace_and_comments(false);
+        match parser.next() {
+            None | Some('\n') => {}
+            Some(_) => return Err(parser.error("expected end of line")),
+        }
+        parser.line += 1;
+    }
+    Ok(root)
+}
+
+/// Returns the last table of each nested array along `path`.
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Option<&'a mut Table> {
+    let mut table = root;
+    for name in path {
+        let (_, tables) = table.arrays.iter_mut().find(|(n, _)| n == name)?;
+        table = tables.last_mut()?;
+    }
+    Some(table)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> Error {
+        Error::Malformed(format!("manifest line {}: {}", self.line, msg))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.chars.get(self.pos + n).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace_and_comments(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newlines => {
+                    self.pos += 1;
+                    self.line += 1;
+                }
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<String> {
+        if self.peek() == Some('"') {
+            return self.parse_string();
+        }
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if start == self.pos {
+            return Err(self.error("expected a key"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.parse_string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut values = vec![];
+                loop {
+                    self.skip_whitespace_and_comments(true);
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        break;
+                    }
+                    values.push(self.parse_value()?);
+                    self.skip_whitespace_and_comments(true);
+                    match self.next() {
+                        Some(',') => {}
+                        Some(']') => break,
+                        _ => return Err(self.error("expected , or ]")),
+                    }
+                }
+                Ok(Value::Array(values))
+            }
+            _ => {
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                let digits = word.replace('_', "");
+                let result = match word.as_str() {
+                    "true" => Some(Value::Boolean(true)),
+                    "false" => Some(Value::Boolean(false)),
+                    _ => match digits.strip_prefix("0x") {
+                        Some(hex) => u64::from_str_radix(hex, 16).ok().map(Value::Integer),
+                        None => digits.parse().ok().map(Value::Integer),
+                    },
+                };
+                result.ok_or_else(|| self.error(&format!("invalid value {:?}", word)))
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.pos += 1; // opening quote
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| self.next()).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut root = Table::new();
+        root.set("endian", Value::String("big".to_string()));
+        root.set("count", Value::Integer(42));
+        root.set("field", Value::Hex(0xdead_beef));
+        root.set("flag", Value::Boolean(true));
+        root.set(
+            "escaped key",
+            Value::String("quote \" backslash \\ newline \n tab \t bell \u{7} é".to_string()),
+        );
+        root.set(
+            "list",
+            Value::Array(vec![Value::Integer(1), Value::String("two".to_string())]),
+        );
+        let section = root.push_table("section");
+        section.set("divider", Value::String("E001".to_string()));
+        section
+            .push_table("entry")
+            .set("name", Value::String("AB\0\u{ff}".to_string()));
+        section
+            .push_table("entry")
+            .set("name", Value::String("CD".to_string()));
+        root.push_table("section")
+            .set("divider", Value::String("E002".to_string()));
+
+        let parsed = parse(&root.to_string()).unwrap();
+        // hexadecimal integers are read back as plain ones
+        root.set("field", Value::Integer(0xdead_beef));
+        assert_eq!(parsed, root);
+        assert_eq!(parsed.tables("section")[0].tables("entry").len(), 2);
+    }
+
+    #[test]
+    fn comments_and_whitespace() {
+        let text = "# header comment\n\
+                    \n\
+                    a = 1 # trailing comment\n\
+                    \tb = \"x # not a comment\"\r\n\
+                    list = [\n  1,  # one\n  2,\n]\n\
+                    \n\
+                    [[t]] # table\n\
+                    c = 0x10\n\
+                    [[t.u]]\n\
+                    d = false\n";
+        let table = parse(text).unwrap();
+        assert_eq!(table.u64("a").unwrap(), 1);
+        assert_eq!(table.str("b").unwrap(), "x # not a comment");
+        assert_eq!(
+            table.opt_array("list").unwrap().unwrap(),
+            &[Value::Integer(1), Value::Integer(2)][..]
+        );
+        let t = &table.tables("t")[0];
+        assert_eq!(t.u64("c").unwrap(), 0x10);
+        assert_eq!(t.tables("u")[0].get("d"), Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        for text in [
+            "a = \"unterminated\n",
+            "a = \"unterminated",
+            "a = \"bad \\q escape\"",
+            "a = 1\na = 2\n",
+            "[[t]]\nb = 1\nb = 2\n",
+            "[t]\n",
+            "[[t.u]]\n",
+            "a = 1 b = 2\n",
+            "a = nope\n",
+            "a 1\n",
+        ] {
+            assert!(
+                matches!(parse(text), Err(Error::Malformed(_))),
+                "{:?} was accepted",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn same_key_in_different_tables() {
+        let table = parse("[[t]]\na = 1\n[[t]]\na = 2\n").unwrap();
+        let values: Vec<_> = table
+            .tables("t")
+            .iter()
+            .map(|t| t.u64("a").unwrap())
+            .collect();
+        assert_eq!(values, [1, 2]);
+    }
+
+    #[test]
+    fn bytes_survive_as_strings() {
+        let bytes: Vec<u8> = (0..=u8::MAX).collect();
+        let mut table = Table::new();
+        table.set("raw", Value::String(bytes_to_string(&bytes)));
+        let parsed = parse(&table.to_string()).unwrap();
+        assert_eq!(string_to_bytes(parsed.str("raw").unwrap()).unwrap(), bytes);
+        assert_eq!(parsed.bytes::<2>("raw").ok(), None);
+    }
+}
@@ -1,15 +1,18 @@
 use std::convert::TryFrom;
-use std::fs::{create_dir_all, File};
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use crate::list::{ListEntry, Listing};
+use crate::manifest::{self, Table, Value};
 use crate::{
-    create_file_to_write, list_files, read_exact, unpack_files, write_padding_zeroes,
-    PackedFileInfo,
+    check_bounds, check_magic_num, check_unique_filenames, create_dir_all, create_file_to_write,
+    has_magic_num, is_after, list_files, open_file, order_files, padding_zero_num, read_exact,
+    read_file, shift_offset, splice_packed_file, unpack_files, write_padding_zeroes, DataLayout,
+    Endian, Error, PackOptions, PackedFileInfo, Result, UnpackOptions,
 };
 
-// PACH (align=4)
+// PACH (align=4), little-endian on PS2 and big-endian on Xbox 360 / PS3
 // [magic_num: u32][file_num: u32]
 // [file_no: u32][offset: u32][len: u32]
 // ...
@@ -17,17 +20,213 @@ use crate::{
 
 const MAGIC_NUM: &[u8; 4] = b"PACH";
 const ALIGN_SIZE: u64 = 4;
+const PACH_MANIFEST: &str = "__pach__.toml";
+const PACH_MANIFEST_COMMENT: &str = "PACH entry order written by `rr-mod-tool -u`.
+Files not listed are packed after these, in numeric order.
+`endian` is the byte order of the archive, \"little\" or \"big\".
+`offsets`, `len` and `fill` are only written when the entries aren't packed back to
+back: the offset of each entry in the file, the file length, and the nonzero bytes
+outside every entry. They are kept as long as the files still fit there.";
 
-pub fn detect_format<P: AsRef<Path>>(path: P) -> bool {
-    let mut file = File::open(path).unwrap();
-    let buf = read_exact!(&mut file, 4);
-    &buf == MAGIC_NUM
+/// A PACH archive held in memory, entries kept in table order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PachArchive {
+    pub endian: Endian,
+    pub entries: Vec<(u32, Vec<u8>)>,
 }
 
-pub fn pack(src_path: PathBuf, dst_path: PathBuf) {
-    let file_info_list = list_files(
+impl PachArchive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads an archive, detecting its byte order from the file count.
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        Self::read_from_endian(reader, None)
+    }
+
+    /// Like [`read_from`](Self::read_from), in the given byte order or the guessed one.
+    pub fn read_from_endian<R: Read + Seek>(
+        reader: &mut R,
+        endian: Option<Endian>,
+    ) -> Result<Self> {
+        let (endian, table) = read_table(reader, endian)?;
+
+        let size = reader.seek(SeekFrom::End(0))?;
+        let mut entries = Vec::with_capacity(table.len());
+        for (file_no, info) in table {
+            check_bounds(info.offset, info.len, size)?;
+            reader.seek(SeekFrom::Start(info.offset))?;
+            let mut data = vec![0u8; info.len as _];
+            reader.read_exact(&mut data)?;
+            entries.push((file_no, data));
+        }
+        Ok(Self { endian, entries })
+    }
+
+    /// Appends an entry; file numbers must be unique.
+    pub fn add_entry(&mut self, file_no: u32, data: Vec<u8>) -> Result<()> {
+        if self.entries.iter().any(|(no, _)| *no == file_no) {
+            return Err(Error::Duplicate(file_no.to_string()));
+        }
+        self.entries.push((file_no, data));
+        Ok(())
+    }
+
+    /// Removes the entry `file_no`, returning its data.
+    pub fn remove_entry(&mut self, file_no: u32) -> Option<Vec<u8>> {
+        let i = self.entries.iter().position(|(no, _)| *no == file_no)?;
+        Some(self.entries.remove(i).1)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.write_laid_out(writer, None)
+    }
+
+    /// Like [`write_to`](Self::write_to), putting the entries where `layout` says if
+    /// they still fit there, and back to back otherwise.
+    fn write_laid_out<W: Write>(&self, writer: &mut W, layout: Option<&DataLayout>) -> Result<()> {
+        let file_num = u32::try_from(self.entries.len()).map_err(|_| Error::TooLarge)?;
+        if file_num == 0 {
+            return Err(Error::Empty);
+        }
+        let base_offset = 8 + self.entries.len() as u64 * 12;
+        let entries: Vec<_> = self.entries.iter().map(|(_, data)| &data[..]).collect();
+        let layout = layout.filter(|layout| layout.fits(&entries, base_offset));
+
+        writer.write_all(MAGIC_NUM)?;
+        let endian = self.endian;
+        writer.write_all(&endian.u32_to_bytes(file_num))?;
+
+        let mut global_offset = 0u32;
+        for (i, (file_no, data)) in self.entries.iter().enumerate() {
+            writer.write_all(&endian.u32_to_bytes(*file_no))?;
+
+            let offset = match layout {
+                Some(layout) => {
+                    u32::try_from(layout.offsets[i] - base_offset).map_err(|_| Error::TooLarge)?
+                }
+                None => global_offset,
+            };
+            writer.write_all(&endian.u32_to_bytes(offset))?;
+
+            let len = u32::try_from(data.len()).map_err(|_| Error::TooLarge)?;
+            writer.write_all(&endian.u32_to_bytes(len))?;
+
+            global_offset = len
+                .checked_add(padding_zero_num(data.len() as u64, ALIGN_SIZE) as u32)
+                .and_then(|len| global_offset.checked_add(len))
+                .ok_or(Error::TooLarge)?;
+        }
+        if let Some(layout) = layout {
+            return layout.write_data(writer, &entries, base_offset);
+        }
+        for (_, data) in &self.entries {
+            writer.write_all(data)?;
+            write_padding_zeroes(writer, padding_zero_num(data.len() as u64, ALIGN_SIZE) as _)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the entry table in the given byte order, or the one guessed from the file count.
+fn read_table<R: Read>(
+    reader: &mut R,
+    endian: Option<Endian>,
+) -> Result<(Endian, Vec<(u32, PackedFileInfo)>)> {
+    let buf = read_exact!(reader, 4);
+    check_magic_num(buf, MAGIC_NUM)?;
+
+    let buf = read_exact!(reader, 4);
+    let endian = endian.unwrap_or_else(|| Endian::guess(buf));
+    let file_num = endian.u32_from_bytes(buf);
+    if file_num == 0 {
+        return Err(Error::Empty);
+    }
+    let base_offset = 8 + file_num as u64 * 12;
+
+    let mut table = vec![];
+    for _ in 0..file_num {
+        let buf = read_exact!(reader, 4);
+        let file_no = endian.u32_from_bytes(buf);
+
+        let buf = read_exact!(reader, 4);
+        let offset = endian.u32_from_bytes(buf) as u64 + base_offset;
+
+        let buf = read_exact!(reader, 4);
+        let len = endian.u32_from_bytes(buf);
+
+        table.push((
+            file_no,
+            PackedFileInfo {
+                filename: file_no.to_string(),
+                offset,
+                len: len as _,
+            },
+        ));
+    }
+    Ok((endian, table))
+}
+
+pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<bool> {
+    has_magic_num(path, MAGIC_NUM)
+}
+
+pub fn detect(data: &[u8]) -> bool {
+    data.starts_with(MAGIC_NUM)
+}
+
+/// Returns the byte order of the PACH archive `data`, guessed from its file count.
+pub fn detect_endian(data: &[u8]) -> Option<Endian> {
+    if !detect(data) || data.len() < 8 {
+        return None;
+    }
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&data[4..8]);
+    Some(Endian::guess(buf))
+}
+
+/// Returns the length of the PACH archive at the start of `data`, which may be followed
+/// by padding: the end of its last entry, aligned.
+pub fn exact_len(data: &[u8]) -> Option<usize> {
+    let mut reader = Cursor::new(data);
+    let (_, table) = read_table(&mut reader, None).ok()?;
+    let mut len = reader.position();
+    for (_, info) in table {
+        len = len.max(info.offset + info.len);
+    }
+    let len = len + padding_zero_num(len, ALIGN_SIZE);
+    if len > data.len() as u64 {
+        return None;
+    }
+    Some(len as _)
+}
+
+/// Names the field of the PACH archive `data` that `offset` falls in.
+pub fn field_at(data: &[u8], offset: u64) -> Option<String> {
+    match offset {
+        0..=3 => return Some("magic number".to_string()),
+        4..=7 => return Some("file count".to_string()),
+        _ => {}
+    }
+    let (_, table) = read_table(&mut Cursor::new(data), None).ok()?;
+    let table_end = 8 + table.len() as u64 * 12;
+    if offset < table_end {
+        let i = (offset - 8) / 12;
+        let field = ["file number", "offset", "length"][((offset - 8) % 12 / 4) as usize];
+        return Some(format!("entry {} {}", i, field));
+    }
+    for (file_no, info) in &table {
+        if offset >= info.offset && offset < info.offset + info.len {
+            return Some(format!("data of file {}", file_no));
+        }
+    }
+    Some("padding".to_string())
+}
+
+pub fn pack(src_path: PathBuf, dst_path: PathBuf, options: &PackOptions) -> Result<()> {
+    let path_list = list_files(
         &src_path,
-        ALIGN_SIZE,
         Some(|filename| {
             let filename = filename.to_string_lossy();
             let filename = filename.as_bytes();
@@ -38,76 +237,319 @@ pub fn pack(src_path: PathBuf, dst_path: PathBuf) {
             }
             true
         }),
-    );
-    let file_num = u32::try_from(file_info_list.len()).unwrap();
-    assert!(file_num > 0);
+    )?;
+
+    let mut archive = PachArchive::new();
+    let mut layout = None;
+    let manifest_path = src_path.join(PACH_MANIFEST);
+    let path_list = if manifest_path.exists() {
+        let manifest = manifest::read(&manifest_path)?;
+        archive.endian = manifest.opt_endian("endian")?.unwrap_or_default();
+        layout = DataLayout::read_manifest(&manifest)?;
+        let mut ordered_names = vec![];
+        for value in manifest.opt_array("entries")?.unwrap_or_default() {
+            match value {
+                Value::Integer(file_no) => ordered_names.push(file_no.to_string()),
+                _ => return Err(Error::Malformed("manifest: bad PACH entry".to_string())),
+            }
+        }
+        order_files(&src_path, &ordered_names, path_list)?
+    } else {
+        path_list
+    };
+
+    if let Some(endian) = options.endian {
+        archive.endian = endian;
+    }
+    for path in path_list {
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        let file_no =
+            u32::from_str(&filename).map_err(|_| Error::InvalidName(filename.to_string()))?;
+        archive.entries.push((file_no, read_file(&path)?));
+    }
+    if options.drop_empty {
+        archive.entries.retain(|(_, data)| !data.is_empty());
+    }
 
     if let Some(dst_dir) = dst_path.parent() {
-        create_dir_all(dst_dir).unwrap()
+        create_dir_all(dst_dir)?;
     }
-    let file = create_file_to_write(dst_path);
+    let file = create_file_to_write(&dst_path)?;
     let mut writer = BufWriter::new(file);
+    archive.write_laid_out(&mut writer, layout.as_ref())?;
+    writer.flush().map_err(|e| Error::io(&dst_path, e))
+}
 
-    writer.write_all(MAGIC_NUM).unwrap();
-    writer.write_all(&file_num.to_le_bytes()).unwrap();
+/// Lists the entries of the archive `src_path` without reading their data.
+pub fn list(src_path: &Path, options: &UnpackOptions) -> Result<Listing> {
+    let file = open_file(src_path)?;
+    list_from(&mut BufReader::new(file), options.endian)
+}
 
-    let mut global_offset = 0u32;
-    for info in &file_info_list {
-        let filename = info.path.file_name().unwrap().to_string_lossy();
-        let file_no = u32::from_str(&filename).unwrap();
-        writer.write_all(&file_no.to_le_bytes()).unwrap();
+/// Like [`list`], reading the archive from `reader` in the given byte order, or the
+/// guessed one.
+pub fn list_from<R: Read>(reader: &mut R, endian: Option<Endian>) -> Result<Listing> {
+    let (endian, table) = read_table(reader, endian)?;
+    let entries = table
+        .iter()
+        .enumerate()
+        .map(|(i, (_, info))| ListEntry::from_info(i, info, padding_zero_num(info.len, ALIGN_SIZE)))
+        .collect();
+    let mut listing = Listing::new("pach", endian);
+    listing.push_entries(entries);
+    Ok(listing)
+}
 
-        let offset = global_offset;
-        writer.write_all(&offset.to_le_bytes()).unwrap();
+/// Replaces the data of entry `index` in the archive `data`, read in the given byte
+/// order or the guessed one. The entries after it keep their bytes and move by whole
+/// aligned steps; only its length and their offsets change in the table.
+pub fn replace_entry(
+    data: &mut Vec<u8>,
+    index: usize,
+    new_data: &[u8],
+    endian: Option<Endian>,
+) -> Result<()> {
+    let (endian, table) = read_table(&mut Cursor::new(&data[..]), endian)?;
+    let info = table
+        .get(index)
+        .map(|(_, info)| info)
+        .ok_or_else(|| Error::NotFound(format!("#{}", index)))?;
+    let len = u32::try_from(new_data.len()).map_err(|_| Error::TooLarge)?;
+    let base_offset = 8 + table.len() as u64 * 12;
+    let delta = splice_packed_file(data, info.offset, info.len, new_data, ALIGN_SIZE)?;
 
-        let len = u32::try_from(info.len).unwrap();
-        writer.write_all(&len.to_le_bytes()).unwrap();
+    for (i, (_, other)) in table.iter().enumerate() {
+        let row = 8 + i * 12;
+        if i == index {
+            data[row + 8..row + 12].copy_from_slice(&endian.u32_to_bytes(len));
+        } else if is_after(i, other.offset, index, info.offset) {
+            let offset = shift_offset(other.offset - base_offset, delta)?;
+            data[row + 4..row + 8].copy_from_slice(&endian.u32_to_bytes(offset));
+        }
+    }
+    Ok(())
+}
 
-        global_offset =
-            u32::checked_add(global_offset, len + info.padding_zero_num as u32).unwrap();
+/// Adds the entry `name`, a file number, to the end of the archive held in `data`, read
+/// in the given byte order or the guessed one.
+pub fn add_entry(
+    data: &mut Vec<u8>,
+    name: &str,
+    entry_data: Vec<u8>,
+    endian: Option<Endian>,
+) -> Result<()> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::InvalidName(name.to_string()));
     }
-    for info in file_info_list {
-        let mut file = File::open(info.path).unwrap();
-        let mut vec = vec![];
-        file.read_to_end(&mut vec).unwrap();
-        writer.write_all(&vec).unwrap();
-        write_padding_zeroes(&mut writer, info.padding_zero_num as _);
+    let file_no = u32::from_str(name).map_err(|_| Error::InvalidName(name.to_string()))?;
+    let mut archive = PachArchive::read_from_endian(&mut Cursor::new(&data[..]), endian)?;
+    archive.add_entry(file_no, entry_data)?;
+    data.clear();
+    archive.write_to(data)
+}
+
+/// Removes entry `index` from the archive held in `data`, read in the given byte order
+/// or the guessed one.
+pub fn remove_entry(data: &mut Vec<u8>, index: usize, endian: Option<Endian>) -> Result<()> {
+    let mut archive = PachArchive::read_from_endian(&mut Cursor::new(&data[..]), endian)?;
+    if index >= archive.entries.len() {
+        return Err(Error::NotFound(format!("#{}", index)));
     }
+    archive.entries.remove(index);
+    data.clear();
+    archive.write_to(data)
 }
 
-pub fn unpack(src_path: PathBuf, dst_path: PathBuf) {
-    let file = File::open(src_path).unwrap();
+pub fn unpack(src_path: PathBuf, dst_path: PathBuf, options: &UnpackOptions) -> Result<()> {
+    let file = open_file(&src_path)?;
     let mut reader = BufReader::new(file);
+    let (endian, table) = read_table(&mut reader, options.endian)?;
+    let base_offset = 8 + table.len() as u64 * 12;
+    let (file_no_list, file_info_list): (Vec<_>, Vec<_>) = table.into_iter().unzip();
+    check_unique_filenames(&file_info_list)?;
+    let layout = DataLayout::read(&mut reader, &file_info_list, base_offset, ALIGN_SIZE)?;
 
-    let buf = read_exact!(&mut reader, 4);
-    assert_eq!(&buf, MAGIC_NUM);
+    create_dir_all(&dst_path)?;
+    let mut manifest = Table::new();
+    manifest.set("endian", Value::String(endian.name().to_string()));
+    let file_no_list = file_no_list.iter().map(|no| Value::Integer(*no as _));
+    manifest.set("entries", Value::Array(file_no_list.collect()));
+    if let Some(layout) = &layout {
+        layout.write_manifest(&mut manifest);
+    }
+    manifest::write(
+        dst_path.join(PACH_MANIFEST),
+        PACH_MANIFEST_COMMENT,
+        &manifest,
+    )?;
 
-    let buf = read_exact!(&mut reader, 4);
-    let file_num = u32::from_le_bytes(buf);
-    assert!(file_num > 0);
-    let base_offset = 8 + file_num * 12;
+    unpack_files(&mut reader, &file_info_list, &dst_path)
+}
 
-    let mut file_info_list = vec![];
-    for _ in 0..file_num {
-        let mut buf = [0u8; 4];
-        reader.read_exact(&mut buf).unwrap();
-        let file_no = u32::from_le_bytes(buf);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive_bytes(endian: Endian, entries: &[Vec<u8>]) -> Vec<u8> {
+        let archive = PachArchive {
+            endian,
+            entries: entries
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(|(i, data)| (i as u32, data))
+                .collect(),
+        };
+        let mut data = vec![];
+        archive.write_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn replace_entry_moves_every_later_entry() {
+        let entries = vec![vec![1; 3], vec![2; 5], vec![], vec![4; 6]];
+        for endian in [Endian::Little, Endian::Big] {
+            for new_len in [13, 1] {
+                let mut data = archive_bytes(endian, &entries);
+                let new_data = vec![0xaa; new_len];
+                replace_entry(&mut data, 1, &new_data, Some(endian)).unwrap();
+
+                let mut new_entries = entries.clone();
+                new_entries[1] = new_data;
+                let expected = archive_bytes(endian, &new_entries);
+                let listing = list_from(&mut &data[..], Some(endian)).unwrap();
+                let expected_listing = list_from(&mut &expected[..], Some(endian)).unwrap();
+                assert_eq!(listing, expected_listing);
+                assert_eq!(data, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn add_and_remove_entries() {
+        let entries = vec![vec![1; 3], vec![2; 5]];
+        for endian in [Endian::Little, Endian::Big] {
+            let mut data = archive_bytes(endian, &entries);
+            add_entry(&mut data, "2", vec![3; 7], Some(endian)).unwrap();
+            let mut new_entries = entries.clone();
+            new_entries.push(vec![3; 7]);
+            assert_eq!(data, archive_bytes(endian, &new_entries));
 
-        let mut buf = [0u8; 4];
-        reader.read_exact(&mut buf).unwrap();
-        let offset = u32::from_le_bytes(buf) + base_offset;
+            assert!(matches!(
+                add_entry(&mut data, "1", vec![], Some(endian)),
+                Err(Error::Duplicate(_))
+            ));
+            assert!(matches!(
+                add_entry(&mut data, "x1", vec![], Some(endian)),
+                Err(Error::InvalidName(_))
+            ));
 
-        let mut buf = [0u8; 4];
-        reader.read_exact(&mut buf).unwrap();
-        let len = u32::from_le_bytes(buf);
+            remove_entry(&mut data, 2, Some(endian)).unwrap();
+            assert_eq!(data, archive_bytes(endian, &entries));
+            assert!(matches!(
+                remove_entry(&mut data, 2, Some(endian)),
+                Err(Error::NotFound(_))
+            ));
+        }
+    }
+    #[test]
+    fn unpack_and_pack_keep_entry_offsets_and_gaps() {
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-pach", std::process::id()));
+        let src_path = dir.join("gap.pach");
+        let unpacked_path = dir.join("gap");
+        let packed_path = dir.join("packed.pach");
+        // three entries, with a 4-byte gap before the first one's data at 0x30
+        let mut data = b"PACH".to_vec();
+        data.extend_from_slice(&3u32.to_le_bytes());
+        for (file_no, offset, len) in [(0u32, 4u32, 5u32), (1, 12, 4), (2, 16, 3)] {
+            for n in [file_no, offset, len] {
+                data.extend_from_slice(&n.to_le_bytes());
+            }
+        }
+        data.extend_from_slice(b"\x00\x00\x00\x00aaaaa\x00\x00\x00bbbbccc\x00");
+        crate::create_dir_all(&dir).unwrap();
+        crate::write_file(&src_path, &data).unwrap();
+
+        unpack(src_path, unpacked_path.clone(), &UnpackOptions::default()).unwrap();
+        pack(
+            unpacked_path.clone(),
+            packed_path.clone(),
+            &PackOptions::default(),
+        )
+        .unwrap();
+        let packed = read_file(&packed_path).unwrap();
+
+        // a file that no longer fits is packed back to back with the others
+        crate::write_file(unpacked_path.join("0"), b"aaaaaaaaa").unwrap();
+        pack(unpacked_path, packed_path.clone(), &PackOptions::default()).unwrap();
+        let repacked = read_file(&packed_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(packed, data);
+        let entries = [b"aaaaaaaaa".to_vec(), b"bbbb".to_vec(), b"ccc".to_vec()];
+        assert_eq!(repacked, archive_bytes(Endian::Little, &entries));
+    }
+
+    #[test]
+    fn big_endian_unpack_and_pack_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-pach-be", std::process::id()));
+        let src_path = dir.join("be.pach");
+        let unpacked_path = dir.join("be");
+        let packed_path = dir.join("packed.pach");
+        let mut archive = PachArchive::new();
+        archive.endian = Endian::Big;
+        archive.entries = vec![(3, vec![1; 5])];
+        let mut data = vec![];
+        archive.write_to(&mut data).unwrap();
+        assert_eq!(&data[4..8], &[0, 0, 0, 1]);
+        assert_eq!(detect_endian(&data), Some(Endian::Big));
+        let read = PachArchive::read_from(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(read.endian, Endian::Big);
+        assert_eq!(read.entries, archive.entries);
 
-        file_info_list.push(PackedFileInfo {
-            filename: file_no.to_string(),
-            offset: offset as _,
-            len: len as _,
-        })
+        crate::create_dir_all(&dir).unwrap();
+        crate::write_file(&src_path, &data).unwrap();
+        unpack(
+            src_path.clone(),
+            unpacked_path.clone(),
+            &UnpackOptions::default(),
+        )
+        .unwrap();
+        pack(unpacked_path, packed_path.clone(), &PackOptions::default()).unwrap();
+        let packed = read_file(&packed_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(packed, data);
     }
 
-    create_dir_all(&dst_path).unwrap();
-    unpack_files(&mut reader, &file_info_list, &dst_path);
+    #[test]
+    fn pack_keeps_empty_files_unless_dropped() {
+        let dir = std::env::temp_dir().join(format!(
+            "rr-mod-tool-test-{}-pach-empty",
+            std::process::id()
+        ));
+        let src_path = dir.join("files");
+        let packed_path = dir.join("packed.pach");
+        crate::create_dir_all(&src_path).unwrap();
+        crate::write_file(src_path.join("0"), &[1; 4]).unwrap();
+        crate::write_file(src_path.join("1"), &[]).unwrap();
+        crate::write_file(src_path.join("2"), &[2; 4]).unwrap();
+
+        let mut options = PackOptions::default();
+        pack(src_path.clone(), packed_path.clone(), &options).unwrap();
+        let kept = read_file(&packed_path).unwrap();
+        options.drop_empty = true;
+        pack(src_path, packed_path.clone(), &options).unwrap();
+        let dropped = read_file(&packed_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let kept = PachArchive::read_from(&mut Cursor::new(&kept)).unwrap();
+        assert_eq!(
+            kept.entries,
+            [(0, vec![1; 4]), (1, vec![]), (2, vec![2; 4])]
+        );
+        let dropped = PachArchive::read_from(&mut Cursor::new(&dropped)).unwrap();
+        assert_eq!(dropped.entries, [(0, vec![1; 4]), (2, vec![2; 4])]);
+    }
 }
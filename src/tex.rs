@@ -1,14 +1,17 @@
 use std::convert::TryFrom;
-use std::fs::{create_dir_all, File};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
+use crate::list::{ListEntry, Listing};
+use crate::manifest::{self, bytes_to_string, Table, Value};
 use crate::{
-    create_file_to_write, list_files, read_exact, unpack_files, write_padding_zeroes,
-    PackedFileInfo,
+    check_bounds, check_output_filename, check_unique_filenames, create_dir_all,
+    create_file_to_write, is_after, list_files, open_file, padding_zero_num, read_exact, read_file,
+    shift_offset, splice_packed_file, unpack_files, write_padding_zeroes, DataLayout, Endian,
+    Error, PackOptions, PackedFileInfo, Result, UnpackOptions,
 };
 
-// TEX (align=16)
+// TEX (align=16), little-endian on PS2 and big-endian on Xbox 360 / PS3
 // [file_num: u32][reserved(?): u32*3]
 // [file_name: u8*16]
 // [ext: u8*4][len: u32][offset: u32][padding: u32]
@@ -16,113 +19,859 @@ use crate::{
 
 const RESERVED: &[u8; 12] = b"\x00\x01\x00\x00\x00\x00\x00\x00\x10\x00\x00\x00";
 const ALIGN_SIZE: u64 = 16;
+const MAX_FILE_NUM: u64 = 0x10000;
+const TEX_MANIFEST: &str = "__tex__.toml";
+const TEX_MANIFEST_COMMENT: &str = "TEX entry table written by `rr-mod-tool -u`.
+`reserved` holds the header's three words after the file count.
+`endian` is the byte order of the archive, \"little\" or \"big\".
+Entries are packed in the order listed; files not listed are packed after them,
+those named with a decimal number first by value, then the rest by name byte by byte.
+`offsets`, `len` and `fill` are only written when the entries aren't packed back to
+back: the offset of each listed entry in the file, the file length, and the nonzero
+bytes outside every entry. They are kept as long as the files still fit there.";
 
-pub fn pack(src_path: PathBuf, dst_path: PathBuf) {
-    let file_info_list = list_files(&src_path, ALIGN_SIZE, None);
-    let file_num = u32::try_from(file_info_list.len()).unwrap();
-    assert!(file_num > 0);
+/// A TEX archive held in memory.
+///
+/// Header fields are kept as read, including the ones whose meaning is unknown,
+/// so that an unmodified archive is written back byte for byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TexArchive {
+    pub endian: Endian,
+    /// The three words after the file count, as stored.
+    pub reserved: [u8; 12],
+    pub entries: Vec<TexEntry>,
+}
 
-    if let Some(dst_dir) = dst_path.parent() {
-        create_dir_all(dst_dir).unwrap()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TexEntry {
+    /// Zero-padded file name.
+    pub name: [u8; 16],
+    /// Zero-padded file extension.
+    pub ext: [u8; 4],
+    /// The word following the offset, zero in every known file.
+    pub padding: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Default for TexArchive {
+    fn default() -> Self {
+        Self {
+            endian: Endian::Little,
+            reserved: *RESERVED,
+            entries: vec![],
+        }
     }
-    let file = create_file_to_write(dst_path);
-    let mut writer = BufWriter::new(file);
+}
 
-    writer.write_all(&file_num.to_le_bytes()).unwrap();
-    writer.write_all(RESERVED).unwrap();
+impl TexArchive {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    let mut global_offset = 16 + 32 * file_info_list.len();
-    for info in &file_info_list {
-        let filename = info.path.file_name().unwrap().to_string_lossy();
-        let (filename, ext) = split_filename_and_ext(&filename);
-        let filename = filename.as_bytes();
-        let ext = ext.as_bytes();
-        assert!(filename.len() > 0 && filename.len() <= 16);
-        assert!(ext.len() > 0 && ext.len() <= 4);
+    /// Creates an empty archive in the given byte order, with the usual reserved words.
+    pub fn with_endian(endian: Endian) -> Self {
+        Self {
+            endian,
+            reserved: endian.words_from_le(RESERVED),
+            entries: vec![],
+        }
+    }
 
-        writer.write_all(filename).unwrap();
-        write_padding_zeroes(&mut writer, 16 - filename.len());
+    /// Reads an archive, detecting its byte order from the header.
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        Self::read_from_endian(reader, None)
+    }
+
+    /// Like [`read_from`](Self::read_from), in the given byte order or the detected one.
+    pub fn read_from_endian<R: Read + Seek>(
+        reader: &mut R,
+        endian: Option<Endian>,
+    ) -> Result<Self> {
+        let (mut archive, file_info_list) = read_detected_table(reader, endian)?;
 
-        writer.write_all(ext).unwrap();
-        write_padding_zeroes(&mut writer, 4 - ext.len());
+        let size = reader.seek(SeekFrom::End(0))?;
+        for (entry, info) in archive.entries.iter_mut().zip(&file_info_list) {
+            check_bounds(info.offset, info.len, size)?;
+            reader.seek(SeekFrom::Start(info.offset))?;
+            entry.data = vec![0u8; info.len as _];
+            reader.read_exact(&mut entry.data)?;
+        }
+        Ok(archive)
+    }
 
-        let len = u32::try_from(info.len).unwrap();
-        writer.write_all(&len.to_le_bytes()).unwrap();
+    /// Appends an entry; file names must be unique, since they name unpacked files.
+    pub fn add_entry(&mut self, entry: TexEntry) -> Result<()> {
+        let filename = entry.filename();
+        if self.entries.iter().any(|e| e.filename() == filename) {
+            return Err(Error::Duplicate(filename));
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
 
-        let offset = u32::try_from(global_offset).unwrap();
-        writer.write_all(&offset.to_le_bytes()).unwrap();
+    /// Removes the entry named `filename`, as `name.ext`.
+    pub fn remove_entry(&mut self, filename: &str) -> Option<TexEntry> {
+        let i = self.entries.iter().position(|e| e.filename() == filename)?;
+        Some(self.entries.remove(i))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.write_laid_out(writer, None)
+    }
+
+    /// Like [`write_to`](Self::write_to), putting the entries where `layout` says if
+    /// they still fit there, and back to back otherwise.
+    fn write_laid_out<W: Write>(&self, writer: &mut W, layout: Option<&DataLayout>) -> Result<()> {
+        let file_num = u32::try_from(self.entries.len()).map_err(|_| Error::TooLarge)?;
+        if file_num == 0 {
+            return Err(Error::Empty);
+        }
+        let table_end = 16 + 32 * self.entries.len() as u64;
+        let entries: Vec<_> = self.entries.iter().map(|entry| &entry.data[..]).collect();
+        let layout = layout.filter(|layout| layout.fits(&entries, table_end));
 
-        write_padding_zeroes(&mut writer, 4);
+        let endian = self.endian;
+        writer.write_all(&endian.u32_to_bytes(file_num))?;
+        writer.write_all(&self.reserved)?;
+
+        let mut global_offset = table_end;
+        for (i, entry) in self.entries.iter().enumerate() {
+            writer.write_all(&entry.name)?;
+            writer.write_all(&entry.ext)?;
+
+            let len = u32::try_from(entry.data.len()).map_err(|_| Error::TooLarge)?;
+            writer.write_all(&endian.u32_to_bytes(len))?;
+
+            let offset = layout.map_or(global_offset, |layout| layout.offsets[i]);
+            let offset = u32::try_from(offset).map_err(|_| Error::TooLarge)?;
+            writer.write_all(&endian.u32_to_bytes(offset))?;
+
+            writer.write_all(&entry.padding)?;
+
+            global_offset += len as u64 + padding_zero_num(len as u64, ALIGN_SIZE);
+        }
+
+        if let Some(layout) = layout {
+            return layout.write_data(writer, &entries, table_end);
+        }
+        for entry in &self.entries {
+            writer.write_all(&entry.data)?;
+            let zero_num = padding_zero_num(entry.data.len() as u64, ALIGN_SIZE);
+            write_padding_zeroes(writer, zero_num as _)?;
+        }
+        Ok(())
+    }
+}
+
+impl TexEntry {
+    /// Creates an entry from a file name such as `body.dds`.
+    pub fn new(filename: &str, data: Vec<u8>) -> Result<Self> {
+        let (name, ext) = split_filename_and_ext(filename);
+        check_name(name, 16)?;
+        check_name(ext, 4)?;
+        let mut entry = Self {
+            name: [0; 16],
+            ext: [0; 4],
+            padding: [0; 4],
+            data,
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry.ext[..ext.len()].copy_from_slice(ext.as_bytes());
+        Ok(entry)
+    }
+
+    /// The file name as `name.ext`.
+    pub fn filename(&self) -> String {
+        format!(
+            "{}.{}",
+            String::from_utf8_lossy(get_bytes_before_zero(&self.name)),
+            String::from_utf8_lossy(get_bytes_before_zero(&self.ext))
+        )
+    }
+}
+
+/// TEX has no magic number, so the header is checked for consistency instead:
+/// the reserved bytes match, names are zero-padded ASCII, and every entry's data is
+/// 16-aligned, inside the file and not overlapping other entries.
+pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<bool> {
+    Ok(detect_format_endian(path)?.is_some())
+}
+
+/// Like [`detect_format`], returning the byte order in which the header is consistent.
+pub fn detect_format_endian<P: AsRef<Path>>(path: P) -> Result<Option<Endian>> {
+    let mut file = open_file(&path)?;
+    detect_reader_endian(&mut file)
+}
 
-        global_offset += (info.len + info.padding_zero_num) as usize;
+/// Like [`detect_format_endian`], reading the header from the start of `reader`.
+fn detect_reader_endian<R: Read + Seek>(reader: &mut R) -> Result<Option<Endian>> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < 16 {
+        return Ok(None);
     }
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = vec![0u8; 16];
+    reader.read_exact(&mut header)?;
+    let header_len = [Endian::Little, Endian::Big]
+        .iter()
+        .filter_map(|endian| header_len(&header, file_len, *endian))
+        .max();
+    let header_len = match header_len {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    header.resize(header_len as _, 0);
+    reader.read_exact(&mut header[16..])?;
+    Ok(detect_header_endian(&header, file_len))
+}
+
+pub fn detect(data: &[u8]) -> bool {
+    detect_endian(data).is_some()
+}
 
+/// Returns the byte order in which the header of `data` is consistent, if any.
+pub fn detect_endian(data: &[u8]) -> Option<Endian> {
+    detect_header_endian(data, data.len() as u64)
+}
+
+fn detect_header_endian(header: &[u8], file_len: u64) -> Option<Endian> {
+    [Endian::Little, Endian::Big]
+        .iter()
+        .copied()
+        .find(|endian| check_header(header, file_len, *endian))
+}
+
+/// Returns the length of the TEX archive at the start of `data`, which may be followed
+/// by padding: the end of its last entry, aligned.
+pub fn exact_len(data: &[u8]) -> Option<usize> {
+    let endian = detect_endian(data)?;
+    let (_, file_info_list) = read_table(&mut Cursor::new(data), Some(endian)).ok()?;
+    let mut len = 0;
     for info in file_info_list {
-        let mut file = File::open(info.path).unwrap();
-        let mut vec = vec![];
-        file.read_to_end(&mut vec).unwrap();
-        writer.write_all(&vec).unwrap();
-        write_padding_zeroes(&mut writer, info.padding_zero_num as _);
+        len = len.max(info.offset + info.len);
+    }
+    let len = len + padding_zero_num(len, ALIGN_SIZE);
+    if len > data.len() as u64 {
+        return None;
     }
+    Some(len as _)
 }
 
-#[inline]
-fn split_filename_and_ext(filename: &str) -> (&str, &str) {
-    match filename.rfind('.') {
-        Some(i) => (&filename[..i], &filename[i + 1..]),
-        None => (&filename, ""),
+/// Returns the length of the header and entry table, if it fits in the file.
+fn header_len(header: &[u8], file_len: u64, endian: Endian) -> Option<u64> {
+    if header.len() < 16 {
+        return None;
+    }
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&header[..4]);
+    let file_num = endian.u32_from_bytes(buf) as u64;
+    if file_num == 0 || file_num > MAX_FILE_NUM {
+        return None;
+    }
+    let len = 16 + 32 * file_num;
+    if len > file_len {
+        return None;
     }
+    Some(len)
 }
 
-pub fn unpack(src_path: PathBuf, dst_path: PathBuf) {
-    let file = File::open(src_path).unwrap();
-    let mut reader = BufReader::new(file);
+fn check_header(header: &[u8], file_len: u64, endian: Endian) -> bool {
+    let header_len = match header_len(header, file_len, endian) {
+        Some(len) if len <= header.len() as u64 => len,
+        _ => return false,
+    };
+    if header[4..16] != endian.words_from_le(RESERVED) {
+        return false;
+    }
 
-    let buf = read_exact!(&mut reader, 4);
-    let file_num = u32::from_le_bytes(buf);
-    assert!(file_num > 0);
+    let mut ranges = vec![];
+    for entry in header[16..header_len as usize].chunks(32) {
+        if !is_zero_padded_ascii(&entry[..16]) || !is_zero_padded_ascii(&entry[16..20]) {
+            return false;
+        }
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&entry[20..24]);
+        let len = endian.u32_from_bytes(buf) as u64;
+        buf.copy_from_slice(&entry[24..28]);
+        let offset = endian.u32_from_bytes(buf) as u64;
+        if !offset.is_multiple_of(ALIGN_SIZE) || offset < header_len || offset + len > file_len {
+            return false;
+        }
+        ranges.push((offset, len));
+    }
+    ranges.sort_unstable();
+    ranges
+        .windows(2)
+        .all(|pair| pair[0].0 + pair[0].1 <= pair[1].0)
+}
 
-    reader.seek(SeekFrom::Current(12)).unwrap();
+/// Checks for printable ASCII followed only by zeroes, with at least one character.
+#[inline]
+fn is_zero_padded_ascii(bytes: &[u8]) -> bool {
+    let len = get_bytes_before_zero(bytes).len();
+    len > 0
+        && bytes[..len]
+            .iter()
+            .all(|b| b.is_ascii_graphic() || *b == b' ')
+        && bytes[len..].iter().all(|b| *b == 0)
+}
 
-    let mut file_info_list = vec![];
-    for _ in 0..file_num {
-        let buf = read_exact!(&mut reader, 16);
-        let filename = get_bytes_before_zero(&buf);
+/// Reads the header and entry table in the given byte order, or the one guessed from
+/// the file count.
+fn read_table<R: Read>(
+    reader: &mut R,
+    endian: Option<Endian>,
+) -> Result<(TexArchive, Vec<PackedFileInfo>)> {
+    let buf = read_exact!(reader, 4);
+    let endian = endian.unwrap_or_else(|| Endian::guess(buf));
+    let file_num = endian.u32_from_bytes(buf);
+    if file_num == 0 {
+        return Err(Error::Empty);
+    }
 
-        let buf = read_exact!(&mut reader, 4);
-        let ext = get_bytes_before_zero(&buf);
+    let reserved = read_exact!(reader, 12);
 
-        let filename = format!(
-            "{}.{}",
-            String::from_utf8_lossy(filename),
-            String::from_utf8_lossy(ext)
-        );
+    let mut entries = vec![];
+    let mut file_info_list = vec![];
+    for _ in 0..file_num {
+        let name = read_exact!(reader, 16);
+        let ext = read_exact!(reader, 4);
 
-        let buf = read_exact!(&mut reader, 4);
-        let len = u32::from_le_bytes(buf);
+        let buf = read_exact!(reader, 4);
+        let len = endian.u32_from_bytes(buf);
 
-        let buf = read_exact!(&mut reader, 4);
-        let offset = u32::from_le_bytes(buf);
+        let buf = read_exact!(reader, 4);
+        let offset = endian.u32_from_bytes(buf);
 
-        reader.seek(SeekFrom::Current(4)).unwrap();
+        let padding = read_exact!(reader, 4);
 
+        let entry = TexEntry {
+            name,
+            ext,
+            padding,
+            data: vec![],
+        };
         file_info_list.push(PackedFileInfo {
-            filename,
+            filename: entry.filename(),
             offset: offset as _,
             len: len as _,
         });
+        entries.push(entry);
     }
+    let archive = TexArchive {
+        endian,
+        reserved,
+        entries,
+    };
+    Ok((archive, file_info_list))
+}
 
-    create_dir_all(&dst_path).unwrap();
-    unpack_files(&mut reader, &file_info_list, &dst_path);
+/// Like [`read_table`], from the start of `reader`, taking the byte order in which the
+/// header is consistent if none is given.
+fn read_detected_table<R: Read + Seek>(
+    reader: &mut R,
+    endian: Option<Endian>,
+) -> Result<(TexArchive, Vec<PackedFileInfo>)> {
+    let endian = match endian {
+        Some(endian) => Some(endian),
+        None => detect_reader_endian(reader)?,
+    };
+    reader.seek(SeekFrom::Start(0))?;
+    read_table(reader, endian)
+}
+
+/// Names the field of the TEX archive `data` that `offset` falls in.
+pub fn field_at(data: &[u8], offset: u64) -> Option<String> {
+    match offset {
+        0..=3 => return Some("file count".to_string()),
+        4..=15 => return Some("reserved".to_string()),
+        _ => {}
+    }
+    let endian = detect_endian(data);
+    let (archive, file_info_list) = read_table(&mut Cursor::new(data), endian).ok()?;
+    let table_end = 16 + archive.entries.len() as u64 * 32;
+    if offset < table_end {
+        let i = ((offset - 16) / 32) as usize;
+        let field = match (offset - 16) % 32 {
+            0..=15 => "name",
+            16..=19 => "extension",
+            20..=23 => "length",
+            24..=27 => "offset",
+            _ => "padding",
+        };
+        return Some(format!(
+            "entry {} ({}) {}",
+            i, file_info_list[i].filename, field
+        ));
+    }
+    for info in &file_info_list {
+        if offset >= info.offset && offset < info.offset + info.len {
+            return Some(format!("data of {}", info.filename));
+        }
+    }
+    Some("padding".to_string())
+}
+
+/// Packs the files in `src_path` in the order recorded by `unpack`, followed by any
+/// files missing from it: names that are decimal numbers first, by value, then the
+/// others by their bytes.
+pub fn pack(src_path: PathBuf, dst_path: PathBuf, options: &PackOptions) -> Result<()> {
+    let path_list = list_files(&src_path, None)?;
+
+    let manifest_path = src_path.join(TEX_MANIFEST);
+    let (mut archive, filename_list, layout) = if manifest_path.exists() {
+        read_tex_manifest(&manifest_path, options.endian)?
+    } else {
+        let endian = options.endian.unwrap_or_default();
+        (TexArchive::with_endian(endian), vec![], None)
+    };
+    for (entry, filename) in archive.entries.iter_mut().zip(&filename_list) {
+        entry.data = read_file(src_path.join(filename))?;
+    }
+    for path in path_list {
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        if filename_list.iter().any(|f| *f == filename) {
+            continue;
+        }
+        let entry = TexEntry::new(&filename, read_file(&path)?)?;
+        archive.entries.push(entry);
+    }
+    if options.drop_empty {
+        archive.entries.retain(|entry| !entry.data.is_empty());
+    }
+
+    if let Some(dst_dir) = dst_path.parent() {
+        create_dir_all(dst_dir)?;
+    }
+    let file = create_file_to_write(&dst_path)?;
+    let mut writer = BufWriter::new(file);
+    archive.write_laid_out(&mut writer, layout.as_ref())?;
+    writer.flush().map_err(|e| Error::io(&dst_path, e))
+}
+
+/// Writes the reserved header and entry table of `archive` as a text manifest, with
+/// the layout of its data if packing wouldn't give it back.
+///
+/// Name and extension fields are only written when they can't be derived from the
+/// file name, e.g. when there are bytes after the terminating zero.
+fn write_tex_manifest(
+    path: &Path,
+    archive: &TexArchive,
+    layout: Option<&DataLayout>,
+) -> Result<()> {
+    let endian = archive.endian;
+    let mut manifest = Table::new();
+    manifest.set("endian", Value::String(endian.name().to_string()));
+    let reserved = archive.reserved.chunks(4).map(|word| {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(word);
+        Value::Hex(endian.u32_from_bytes(buf) as _)
+    });
+    manifest.set("reserved", Value::Array(reserved.collect()));
+
+    for entry in &archive.entries {
+        let table = manifest.push_table("entry");
+        let filename = entry.filename();
+        table.set("file", Value::String(filename.clone()));
+        let derived = TexEntry::new(&filename, vec![]).ok();
+        if derived.map(|d| (d.name, d.ext)) != Some((entry.name, entry.ext)) {
+            table.set("name", Value::String(bytes_to_string(&entry.name)));
+            table.set("ext", Value::String(bytes_to_string(&entry.ext)));
+        }
+        if entry.padding != [0; 4] {
+            let padding = endian.u32_from_bytes(entry.padding);
+            table.set("padding", Value::Hex(padding as _));
+        }
+    }
+    if let Some(layout) = layout {
+        layout.write_manifest(&mut manifest);
+    }
+    manifest::write(path, TEX_MANIFEST_COMMENT, &manifest)
+}
+
+/// Reads a manifest written by [`write_tex_manifest`], returning the archive without
+/// entry data, the file holding each entry and the recorded layout. `endian` overrides
+/// the recorded byte order.
+fn read_tex_manifest(
+    path: &Path,
+    endian: Option<Endian>,
+) -> Result<(TexArchive, Vec<String>, Option<DataLayout>)> {
+    let manifest = manifest::read(path)?;
+    let endian = match endian {
+        Some(endian) => endian,
+        None => manifest.opt_endian("endian")?.unwrap_or_default(),
+    };
+    let mut archive = TexArchive::with_endian(endian);
+    if let Some(values) = manifest.opt_array("reserved")? {
+        if values.len() != 3 {
+            return Err(Error::Malformed(
+                "manifest: \"reserved\" must have 3 words".to_string(),
+            ));
+        }
+        for (i, value) in values.iter().enumerate() {
+            let word = match value {
+                Value::Integer(n) if *n <= u32::MAX as u64 => *n as u32,
+                _ => {
+                    return Err(Error::Malformed(
+                        "manifest: \"reserved\" must hold 32-bit integers".to_string(),
+                    ))
+                }
+            };
+            archive.reserved[i * 4..(i + 1) * 4].copy_from_slice(&endian.u32_to_bytes(word));
+        }
+    }
+
+    let mut filename_list = vec![];
+    for table in manifest.tables("entry") {
+        let filename = table.str("file")?;
+        check_output_filename(filename)?;
+        if filename_list.iter().any(|f| f == filename) {
+            return Err(Error::Duplicate(filename.to_string()));
+        }
+        let mut entry = match table.opt_str("name")? {
+            Some(_) => TexEntry {
+                name: table.bytes("name")?,
+                ext: table.bytes("ext")?,
+                padding: [0; 4],
+                data: vec![],
+            },
+            None => TexEntry::new(filename, vec![])?,
+        };
+        if table.opt_u64("padding")?.is_some() {
+            entry.padding = endian.u32_to_bytes(table.u32("padding")?);
+        }
+        archive.entries.push(entry);
+        filename_list.push(filename.to_string());
+    }
+    let layout = DataLayout::read_manifest(&manifest)?;
+    Ok((archive, filename_list, layout))
+}
+
+#[inline]
+fn split_filename_and_ext(filename: &str) -> (&str, &str) {
+    match filename.rfind('.') {
+        Some(i) => (&filename[..i], &filename[i + 1..]),
+        None => (filename, ""),
+    }
+}
+
+#[inline]
+fn check_name(name: &str, max: usize) -> Result<()> {
+    if name.is_empty() {
+        return Err(Error::InvalidName(name.to_string()));
+    }
+    if name.len() > max {
+        return Err(Error::NameTooLong {
+            name: name.to_string(),
+            max,
+        });
+    }
+    Ok(())
+}
+
+/// Lists the entries of the archive `src_path` without reading their data.
+pub fn list(src_path: &Path, options: &UnpackOptions) -> Result<Listing> {
+    let file = open_file(src_path)?;
+    list_from(&mut BufReader::new(file), options.endian)
+}
+
+/// Like [`list`], reading the archive from `reader` in the given byte order, or the one
+/// in which its header is consistent.
+pub fn list_from<R: Read + Seek>(reader: &mut R, endian: Option<Endian>) -> Result<Listing> {
+    let (archive, file_info_list) = read_detected_table(reader, endian)?;
+    let entries = file_info_list
+        .iter()
+        .enumerate()
+        .map(|(i, info)| ListEntry::from_info(i, info, padding_zero_num(info.len, ALIGN_SIZE)))
+        .collect();
+    let mut listing = Listing::new("tex", archive.endian);
+    listing.push_entries(entries);
+    Ok(listing)
+}
+
+/// Replaces the data of entry `index` in the archive `data`, read in the given byte
+/// order or the one in which its header is consistent. The entries after it keep their
+/// bytes and move by whole aligned steps; only its length and their offsets change in
+/// the table.
+pub fn replace_entry(
+    data: &mut Vec<u8>,
+    index: usize,
+    new_data: &[u8],
+    endian: Option<Endian>,
+) -> Result<()> {
+    let (archive, file_info_list) = read_detected_table(&mut Cursor::new(&data[..]), endian)?;
+    let endian = archive.endian;
+    let info = file_info_list
+        .get(index)
+        .ok_or_else(|| Error::NotFound(format!("#{}", index)))?;
+    let len = u32::try_from(new_data.len()).map_err(|_| Error::TooLarge)?;
+    let delta = splice_packed_file(data, info.offset, info.len, new_data, ALIGN_SIZE)?;
+
+    for (i, other) in file_info_list.iter().enumerate() {
+        let row = 16 + i * 32;
+        if i == index {
+            data[row + 20..row + 24].copy_from_slice(&endian.u32_to_bytes(len));
+        } else if is_after(i, other.offset, index, info.offset) {
+            let offset = shift_offset(other.offset, delta)?;
+            data[row + 24..row + 28].copy_from_slice(&endian.u32_to_bytes(offset));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the entry `name`, as `name.ext`, to the end of the archive held in `data`, read
+/// in the given byte order or the detected one.
+pub fn add_entry(
+    data: &mut Vec<u8>,
+    name: &str,
+    entry_data: Vec<u8>,
+    endian: Option<Endian>,
+) -> Result<()> {
+    let mut archive = TexArchive::read_from_endian(&mut Cursor::new(&data[..]), endian)?;
+    archive.add_entry(TexEntry::new(name, entry_data)?)?;
+    data.clear();
+    archive.write_to(data)
+}
+
+/// Removes entry `index` from the archive held in `data`, read in the given byte order
+/// or the detected one.
+pub fn remove_entry(data: &mut Vec<u8>, index: usize, endian: Option<Endian>) -> Result<()> {
+    let mut archive = TexArchive::read_from_endian(&mut Cursor::new(&data[..]), endian)?;
+    if index >= archive.entries.len() {
+        return Err(Error::NotFound(format!("#{}", index)));
+    }
+    archive.entries.remove(index);
+    data.clear();
+    archive.write_to(data)
+}
+
+pub fn unpack(src_path: PathBuf, dst_path: PathBuf, options: &UnpackOptions) -> Result<()> {
+    let file = open_file(&src_path)?;
+    let mut reader = BufReader::new(file);
+    let endian = match options.endian {
+        Some(endian) => Some(endian),
+        None => detect_format_endian(&src_path)?,
+    };
+    let (archive, file_info_list) = read_table(&mut reader, endian)?;
+    let table_end = 16 + file_info_list.len() as u64 * 32;
+    check_unique_filenames(&file_info_list)?;
+    let layout = DataLayout::read(&mut reader, &file_info_list, table_end, ALIGN_SIZE)?;
+
+    create_dir_all(&dst_path)?;
+    let manifest_path = dst_path.join(TEX_MANIFEST);
+    write_tex_manifest(&manifest_path, &archive, layout.as_ref())?;
+    unpack_files(&mut reader, &file_info_list, &dst_path)
 }
 
 #[inline]
 fn get_bytes_before_zero(bytes: &[u8]) -> &[u8] {
-    for i in 0..bytes.len() {
-        if bytes[i] == 0 {
-            return &bytes[..i];
+    match bytes.iter().position(|b| *b == 0) {
+        Some(i) => &bytes[..i],
+        None => bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive_bytes(endian: Endian, entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = TexArchive::with_endian(endian);
+        for (i, data) in entries.iter().enumerate() {
+            archive
+                .add_entry(TexEntry::new(&format!("{}.dds", i), data.clone()).unwrap())
+                .unwrap();
+        }
+        let mut data = vec![];
+        archive.write_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn replace_entry_moves_every_later_entry() {
+        let entries = vec![vec![1; 3], vec![2; 20], vec![], vec![4; 6]];
+        for endian in [Endian::Little, Endian::Big] {
+            for new_len in [40, 1] {
+                let mut data = archive_bytes(endian, &entries);
+                let new_data = vec![0xaa; new_len];
+                replace_entry(&mut data, 1, &new_data, Some(endian)).unwrap();
+
+                let mut new_entries = entries.clone();
+                new_entries[1] = new_data;
+                let expected = archive_bytes(endian, &new_entries);
+                let listing = list_from(&mut Cursor::new(&data[..]), Some(endian)).unwrap();
+                let expected_listing =
+                    list_from(&mut Cursor::new(&expected[..]), Some(endian)).unwrap();
+                assert_eq!(listing, expected_listing);
+                assert_eq!(data, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn add_and_remove_entries() {
+        let entries = vec![vec![1; 3], vec![2; 20]];
+        for endian in [Endian::Little, Endian::Big] {
+            let mut data = archive_bytes(endian, &entries);
+            // detected from the header, as replace_entry does
+            add_entry(&mut data, "2.dds", vec![3; 7], None).unwrap();
+            let mut new_entries = entries.clone();
+            new_entries.push(vec![3; 7]);
+            assert_eq!(data, archive_bytes(endian, &new_entries));
+
+            assert!(matches!(
+                add_entry(&mut data, "1.dds", vec![], None),
+                Err(Error::Duplicate(_))
+            ));
+            assert!(matches!(
+                add_entry(&mut data, "a_name_over_16_bytes.dds", vec![], None),
+                Err(Error::NameTooLong { max: 16, .. })
+            ));
+
+            remove_entry(&mut data, 2, Some(endian)).unwrap();
+            assert_eq!(data, archive_bytes(endian, &entries));
+        }
+    }
+
+    #[test]
+    fn unpack_and_pack_keep_padding_bytes_and_trailing_data() {
+        let dir = std::env::temp_dir().join(format!("rr-mod-tool-test-{}-tex", std::process::id()));
+        let src_path = dir.join("padded.tex");
+        let unpacked_path = dir.join("padded");
+        let packed_path = dir.join("packed.tex");
+        for endian in [Endian::Little, Endian::Big] {
+            let mut data = archive_bytes(endian, &[vec![1; 3], vec![2; 20]]);
+            // nonzero alignment padding after the first entry, and data after the last
+            data[16 + 64 + 3] = 0xcd;
+            data.extend_from_slice(b"trailer");
+            crate::create_dir_all(&dir).unwrap();
+            crate::write_file(&src_path, &data).unwrap();
+
+            unpack(
+                src_path.clone(),
+                unpacked_path.clone(),
+                &UnpackOptions::default(),
+            )
+            .unwrap();
+            pack(
+                unpacked_path.clone(),
+                packed_path.clone(),
+                &PackOptions::default(),
+            )
+            .unwrap();
+            let packed = read_file(&packed_path).unwrap();
+            std::fs::remove_dir_all(&dir).unwrap();
+            assert_eq!(packed, data);
         }
     }
-    bytes
+
+    #[test]
+    fn pack_rejects_a_manifest_listing_a_file_twice() {
+        let dir = std::env::temp_dir().join(format!(
+            "rr-mod-tool-test-{}-tex-manifest",
+            std::process::id()
+        ));
+        crate::create_dir_all(&dir).unwrap();
+        crate::write_file(dir.join("a.dds"), &[1; 4]).unwrap();
+        let mut manifest = Table::new();
+        for _ in 0..2 {
+            let table = manifest.push_table("entry");
+            table.set("file", Value::String("a.dds".to_string()));
+        }
+        manifest::write(dir.join(TEX_MANIFEST), TEX_MANIFEST_COMMENT, &manifest).unwrap();
+        let result = pack(dir.clone(), dir.join("a.tex"), &PackOptions::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(Error::Duplicate(name)) if name == "a.dds"));
+    }
+
+    #[test]
+    fn detects_tex_but_not_truncated_tex_pach_or_bpe() {
+        let path = std::env::temp_dir().join(format!(
+            "rr-mod-tool-test-{}-detect.tex",
+            std::process::id()
+        ));
+        for endian in [Endian::Little, Endian::Big] {
+            // the last entry needs no padding, so any truncation cuts into it
+            let data = archive_bytes(endian, &[vec![1; 20], vec![2; 48]]);
+            assert_eq!(detect_endian(&data), Some(endian));
+            crate::write_file(&path, &data).unwrap();
+            let detected = detect_format_endian(&path).unwrap();
+            assert_eq!(detected, Some(endian));
+            assert!(detect_format(&path).unwrap());
+
+            // the last entry ends past the end of the file, or the table does
+            assert_eq!(detect_endian(&data[..data.len() - 1]), None);
+            assert_eq!(detect_endian(&data[..16 + 32]), None);
+            assert_eq!(detect_endian(&data[..15]), None);
+            crate::write_file(&path, &data[..data.len() - 1]).unwrap();
+            assert!(!detect_format(&path).unwrap());
+
+            let mut pach = crate::pach::PachArchive::new();
+            pach.endian = endian;
+            pach.entries = vec![(0, vec![1; 20]), (1, vec![2; 40])];
+            let mut pach_data = vec![];
+            pach.write_to(&mut pach_data).unwrap();
+            let bpe_data = crate::bpe::compress_with_endian(&data, endian).unwrap();
+            for other in [pach_data, bpe_data].iter() {
+                assert_eq!(detect_endian(other), None);
+                crate::write_file(&path, other).unwrap();
+                assert!(!detect_format(&path).unwrap());
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn big_endian_archives_read_back_big_endian() {
+        let data = archive_bytes(Endian::Big, &[vec![1; 16]]);
+        assert_eq!(detect_endian(&data), Some(Endian::Big));
+        assert_eq!(
+            detect_endian(&archive_bytes(Endian::Little, &[vec![1; 16]])),
+            Some(Endian::Little)
+        );
+        let archive = TexArchive::read_from(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(archive.endian, Endian::Big);
+        let mut written = vec![];
+        archive.write_to(&mut written).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn pack_keeps_empty_files_unless_dropped() {
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-tex-empty", std::process::id()));
+        let src_path = dir.join("files");
+        let packed_path = dir.join("packed.tex");
+        crate::create_dir_all(&src_path).unwrap();
+        crate::write_file(src_path.join("a.dds"), &[1; 4]).unwrap();
+        crate::write_file(src_path.join("b.dds"), &[]).unwrap();
+        crate::write_file(src_path.join("c.dds"), &[2; 4]).unwrap();
+
+        let mut options = PackOptions::default();
+        pack(src_path.clone(), packed_path.clone(), &options).unwrap();
+        let kept = read_file(&packed_path).unwrap();
+        options.drop_empty = true;
+        pack(src_path, packed_path.clone(), &options).unwrap();
+        let dropped = read_file(&packed_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let entries = |data: &[u8]| -> Vec<(String, Vec<u8>)> {
+            let archive = TexArchive::read_from(&mut Cursor::new(data)).unwrap();
+            archive
+                .entries
+                .into_iter()
+                .map(|entry| (entry.filename(), entry.data))
+                .collect()
+        };
+        let names: Vec<_> = entries(&kept).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["a.dds", "b.dds", "c.dds"]);
+        assert_eq!(entries(&kept)[1].1, vec![0u8; 0]);
+        assert_eq!(
+            entries(&dropped),
+            [
+                ("a.dds".to_string(), vec![1; 4]),
+                ("c.dds".to_string(), vec![2; 4])
+            ]
+        );
+    }
 }
@@ -0,0 +1,361 @@
+// Recursive unpacking of nested containers, and repacking them back.
+//
+// Every recognized file is unpacked into a sibling directory named `<file>.<format>`;
+// a BPE file's directory holds the decompressed payload under the file's own name.
+// A nested file that only looks like a container and fails to unpack is left as it is.
+// What each node was is recorded in `__tree__.toml` at the top of the tree, and
+// repacking walks those nodes backwards so that children are packed before parents,
+// into scratch copies of the directories that hold them.
+// Nodes also record their byte order, which a BPE file has no other place to keep.
+
+use std::collections::HashMap;
+use std::fs::read_dir;
+use std::path::{Component, Path, PathBuf};
+
+use crate::format::{ContainerFormat, Registry};
+use crate::manifest::{self, Table, Value};
+use crate::{
+    create_dir_all, list_files, read_file, Error, PackOptions, Result, TempDir, UnpackOptions,
+};
+
+pub const TREE_MANIFEST: &str = "__tree__.toml";
+const MAX_DEPTH: usize = 16;
+
+/// Unpacks `src_path` into the directory `dst_path`, then every nested container found
+/// in it, level by level.
+pub fn unpack_recursive(
+    registry: &Registry,
+    src_path: &Path,
+    dst_path: &Path,
+    options: &UnpackOptions,
+) -> Result<()> {
+    let data = read_file(src_path)?;
+    let format = registry.detect(&data).ok_or(Error::UnknownFormat)?;
+    unpack_node(format, src_path, dst_path, options)?;
+
+    let mut manifest = Table::new();
+    manifest.set("format", Value::String(format.name().to_string()));
+    let name = src_path.file_name().unwrap_or_default().to_string_lossy();
+    manifest.set("name", Value::String(name.to_string()));
+    set_endian(&mut manifest, format, &data, options);
+
+    visit(registry, dst_path, dst_path, 0, options, &mut manifest)?;
+
+    manifest::write(
+        dst_path.join(TREE_MANIFEST),
+        "Written by `rr-mod-tool -u --recursive`, nodes in unpacking order.",
+        &manifest,
+    )
+}
+
+/// Repacks a tree written by [`unpack_recursive`] from the leaves up. Each nested
+/// container is packed into a scratch copy of the directory holding it, so the tree
+/// itself is left as it is.
+pub fn pack_recursive(
+    registry: &Registry,
+    src_path: &Path,
+    dst_path: &Path,
+    options: &PackOptions,
+) -> Result<()> {
+    let manifest = manifest::read(src_path.join(TREE_MANIFEST))?;
+    let scratch = TempDir::new()?;
+    let mut copies = HashMap::new();
+
+    for node in manifest.tables("node").iter().rev() {
+        let format = get_format(registry, node.str("format")?)?;
+        let packed_path = join_relative_path(src_path, node.str("path")?)?;
+        let unpacked_path = join_relative_path(src_path, node.str("unpacked")?)?;
+        let options = node_pack_options(node, options)?;
+        let unpacked_path = copies.get(&unpacked_path).unwrap_or(&unpacked_path).clone();
+        let name = packed_path.file_name().unwrap_or_default();
+        let dir_path = packed_path.parent().unwrap_or(src_path);
+        let dst_path = scratch_copy(&scratch.path, &mut copies, dir_path)?.join(name);
+        pack_node(format, &unpacked_path, &dst_path, &options)?;
+    }
+
+    let format = get_format(registry, manifest.str("format")?)?;
+    let options = &node_pack_options(&manifest, options)?;
+    let src_path = copies.get(src_path).map_or(src_path, PathBuf::as_path);
+    if format.unpacks_to_dir() {
+        format.pack(src_path, dst_path, options)
+    } else {
+        let name = join_relative_path(Path::new(""), manifest.str("name")?)?;
+        format.pack(&src_path.join(name), dst_path, options)
+    }
+}
+
+/// Returns the copy of the files directly in `dir_path` under `scratch_path`, making it
+/// the first time the directory is asked for.
+fn scratch_copy(
+    scratch_path: &Path,
+    copies: &mut HashMap<PathBuf, PathBuf>,
+    dir_path: &Path,
+) -> Result<PathBuf> {
+    if let Some(copy_path) = copies.get(dir_path) {
+        return Ok(copy_path.clone());
+    }
+    let copy_path = scratch_path.join(copies.len().to_string());
+    create_dir_all(&copy_path)?;
+    for entry in read_dir(dir_path).map_err(|e| Error::io(dir_path, e))? {
+        let path = entry.map_err(|e| Error::io(dir_path, e))?.path();
+        if path.is_file() {
+            let name = path.file_name().unwrap_or_default();
+            std::fs::copy(&path, copy_path.join(name)).map_err(|e| Error::io(&path, e))?;
+        }
+    }
+    copies.insert(dir_path.to_path_buf(), copy_path.clone());
+    Ok(copy_path)
+}
+
+/// Records the byte order of `data` in `table`, if the format has one.
+fn set_endian(
+    table: &mut Table,
+    format: &dyn ContainerFormat,
+    data: &[u8],
+    options: &UnpackOptions,
+) {
+    if let Some(endian) = options.endian.or_else(|| format.detect_endian(data)) {
+        table.set("endian", Value::String(endian.name().to_string()));
+    }
+}
+
+/// Uses the byte order recorded for a node unless `options` overrides it.
+fn node_pack_options(table: &Table, options: &PackOptions) -> Result<PackOptions> {
+    let mut options = options.clone();
+    if options.endian.is_none() {
+        options.endian = table.opt_endian("endian")?;
+    }
+    Ok(options)
+}
+
+#[inline]
+fn get_format<'a>(registry: &'a Registry, name: &str) -> Result<&'a dyn ContainerFormat> {
+    registry
+        .get(name)
+        .ok_or_else(|| Error::Malformed(format!("unknown format {:?} in manifest", name)))
+}
+
+fn pack_node(
+    format: &dyn ContainerFormat,
+    src_path: &Path,
+    dst_path: &Path,
+    options: &PackOptions,
+) -> Result<()> {
+    if format.unpacks_to_dir() {
+        format.pack(src_path, dst_path, options)
+    } else {
+        let name = dst_path.file_name().unwrap_or_default();
+        format.pack(&src_path.join(name), dst_path, options)
+    }
+}
+
+fn unpack_node(
+    format: &dyn ContainerFormat,
+    src_path: &Path,
+    dst_path: &Path,
+    options: &UnpackOptions,
+) -> Result<()> {
+    if format.unpacks_to_dir() {
+        format.unpack(src_path, dst_path, options)
+    } else {
+        create_dir_all(dst_path)?;
+        let name = src_path.file_name().unwrap_or_default();
+        format.unpack(src_path, &dst_path.join(name), options)
+    }
+}
+
+fn visit(
+    registry: &Registry,
+    root_path: &Path,
+    dir_path: &Path,
+    depth: usize,
+    options: &UnpackOptions,
+    manifest: &mut Table,
+) -> Result<()> {
+    if depth >= MAX_DEPTH {
+        return Err(Error::Malformed(format!(
+            "{}: containers nested too deeply",
+            dir_path.display()
+        )));
+    }
+    let mut path_list = list_files(dir_path, None)?;
+    path_list.sort();
+    for path in path_list {
+        let data = read_file(&path)?;
+        let format = match registry.detect(&data) {
+            Some(format) => format,
+            None => continue,
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let unpacked_path = dir_path.join(format!("{}.{}", name, format.name()));
+        match unpack_node(format, &path, &unpacked_path, options) {
+            Ok(()) => {}
+            // a file that only looks like a container, e.g. data starting with "BPE ",
+            // is kept as a leaf
+            Err(e) if !matches!(e, Error::Io { .. }) => {
+                remove_unpacked(&unpacked_path)?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        let node = manifest.push_table("node");
+        node.set("path", Value::String(relative_path(root_path, &path)));
+        node.set("format", Value::String(format.name().to_string()));
+        node.set(
+            "unpacked",
+            Value::String(relative_path(root_path, &unpacked_path)),
+        );
+        set_endian(node, format, &data, options);
+
+        visit(
+            registry,
+            root_path,
+            &unpacked_path,
+            depth + 1,
+            options,
+            manifest,
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes whatever a failed [`unpack_node`] left at `path`.
+fn remove_unpacked(path: &Path) -> Result<()> {
+    let result = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else if path.exists() {
+        std::fs::remove_file(path)
+    } else {
+        Ok(())
+    };
+    result.map_err(|e| Error::io(path, e))
+}
+
+/// Formats `path` relative to `root_path` with `/` separators.
+fn relative_path(root_path: &Path, path: &Path) -> String {
+    let path = path.strip_prefix(root_path).unwrap_or(path);
+    let components: Vec<_> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    components.join("/")
+}
+
+/// Resolves a `/`-separated path from a manifest, refusing to leave `root_path`.
+fn join_relative_path(root_path: &Path, path: &str) -> Result<PathBuf> {
+    let mut result = root_path.to_path_buf();
+    for name in path.split('/') {
+        if name.is_empty() || name == "." || name == ".." || name.contains('\\') {
+            return Err(Error::InvalidName(path.to_string()));
+        }
+        result.push(name);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bpe;
+    use crate::epac::{EpacArchive, EpacEntry, EpacSection};
+    use crate::pach::PachArchive;
+    use crate::tex::{TexArchive, TexEntry};
+    use crate::write_file;
+    use std::collections::BTreeMap;
+
+    fn snapshot(dir_path: &Path, files: &mut BTreeMap<PathBuf, Vec<u8>>) {
+        for entry in read_dir(dir_path).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                snapshot(&path, files);
+            } else {
+                files.insert(path.clone(), read_file(&path).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn pack_recursive_rebuilds_nested_archives_without_touching_the_tree() {
+        let mut tex = TexArchive::new();
+        tex.add_entry(TexEntry::new("a.dds", vec![1; 40]).unwrap())
+            .unwrap();
+        tex.add_entry(TexEntry::new("b.dds", vec![2; 24]).unwrap())
+            .unwrap();
+        let mut tex_data = vec![];
+        tex.write_to(&mut tex_data).unwrap();
+        let bpe_data = bpe::compress(&tex_data).unwrap();
+        let mut pach = PachArchive::new();
+        pach.entries = vec![(0, bpe_data), (1, vec![3; 12])];
+        let mut pach_data = vec![];
+        pach.write_to(&mut pach_data).unwrap();
+        let mut epac = EpacArchive::new();
+        epac.sections.push(EpacSection {
+            divider: None,
+            entries: vec![EpacEntry::new("PACH", pach_data).unwrap()],
+        });
+        let mut original = vec![];
+        epac.write_to(&mut original).unwrap();
+
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-tree", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let src_path = dir.join("nest.epac");
+        let unpacked_path = dir.join("nest");
+        let dst_path = dir.join("repacked.epac");
+        write_file(&src_path, &original).unwrap();
+
+        let registry = Registry::default();
+        unpack_recursive(
+            &registry,
+            &src_path,
+            &unpacked_path,
+            &UnpackOptions::default(),
+        )
+        .unwrap();
+        let manifest = manifest::read(unpacked_path.join(TREE_MANIFEST)).unwrap();
+        let formats: Vec<_> = manifest
+            .tables("node")
+            .iter()
+            .map(|node| node.str("format").unwrap().to_string())
+            .collect();
+        assert_eq!(formats, ["pach", "bpe", "tex"]);
+
+        pack_recursive(
+            &registry,
+            &unpacked_path,
+            &dst_path,
+            &PackOptions::default(),
+        )
+        .unwrap();
+        assert!(read_file(&dst_path).unwrap() == original);
+
+        // an edited leaf reaches the output while the packed files in the tree stay as
+        // they were unpacked
+        let tex_path = manifest.tables("node")[2].str("unpacked").unwrap();
+        let leaf_path = join_relative_path(&unpacked_path, tex_path)
+            .unwrap()
+            .join("a.dds");
+        write_file(&leaf_path, &[4; 40]).unwrap();
+        let mut before = BTreeMap::new();
+        snapshot(&unpacked_path, &mut before);
+        pack_recursive(
+            &registry,
+            &unpacked_path,
+            &dst_path,
+            &PackOptions::default(),
+        )
+        .unwrap();
+        let mut after = BTreeMap::new();
+        snapshot(&unpacked_path, &mut after);
+        let edited = read_file(&dst_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(before == after);
+        assert_eq!(edited.len(), original.len());
+        assert!(edited != original);
+    }
+}
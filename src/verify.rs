@@ -0,0 +1,106 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::format::Registry;
+use crate::{read_file, Error, PackOptions, Result, TempDir, UnpackOptions};
+
+/// The first difference between a file and the result of unpacking and packing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub offset: u64,
+    /// The byte in the original file, `None` past its end.
+    pub expected: Option<u8>,
+    /// The byte in the repacked file, `None` past its end.
+    pub found: Option<u8>,
+    /// The field of the original file at `offset`, if the format can tell.
+    pub field: Option<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "first difference at offset {:#x}", self.offset)?;
+        if let Some(field) = &self.field {
+            write!(f, " ({})", field)?;
+        }
+        match (self.expected, self.found) {
+            (Some(expected), Some(found)) => {
+                write!(f, ": expected {:#04x}, found {:#04x}", expected, found)
+            }
+            (Some(_), None) => write!(f, ": repacked file ends here"),
+            (None, _) => write!(f, ": repacked file is longer"),
+        }
+    }
+}
+
+/// Unpacks `src_path` into a temporary directory, packs it again and compares the
+/// result with the original, returning the first difference if there is one.
+pub fn verify_roundtrip(registry: &Registry, src_path: &Path) -> Result<Option<Mismatch>> {
+    let original = read_file(src_path)?;
+    let format = registry.detect(&original).ok_or(Error::UnknownFormat)?;
+
+    let temp_dir = TempDir::new()?;
+    let unpacked_path = temp_dir.path.join("unpacked");
+    let repacked_path = temp_dir.path.join("repacked");
+    format.unpack(src_path, &unpacked_path, &UnpackOptions::default())?;
+    let options = PackOptions {
+        endian: format.detect_endian(&original),
+        ..PackOptions::default()
+    };
+    format.pack(&unpacked_path, &repacked_path, &options)?;
+    let repacked = read_file(&repacked_path)?;
+
+    let offset = original
+        .iter()
+        .zip(&repacked)
+        .position(|(a, b)| a != b)
+        .unwrap_or_else(|| original.len().min(repacked.len()));
+    if offset == original.len() && offset == repacked.len() {
+        return Ok(None);
+    }
+    Ok(Some(Mismatch {
+        offset: offset as _,
+        expected: original.get(offset).copied(),
+        found: repacked.get(offset).copied(),
+        field: format.describe_offset(&original, offset as _),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bpe, write_file};
+
+    #[test]
+    fn reports_the_first_difference() {
+        let dir =
+            std::env::temp_dir().join(format!("rr-mod-tool-test-{}-verify", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let registry = Registry::default();
+        let data = b"abababababababab".repeat(4);
+
+        let good_path = dir.join("good.bpe");
+        write_file(&good_path, &bpe::compress(&data).unwrap()).unwrap();
+        let good = verify_roundtrip(&registry, &good_path);
+
+        // a valid stream substituting nothing, which the encoder would compress
+        let mut raw = bpe::compress(&data).unwrap()[..16].to_vec();
+        for half in [0u8, 0x80] {
+            raw.push(0x7f);
+            raw.extend(half..=half + 0x7f);
+        }
+        raw.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        raw.extend_from_slice(&data);
+        let compressed_len = (raw.len() - 16) as u32;
+        raw[8..12].copy_from_slice(&compressed_len.to_le_bytes());
+        let raw_path = dir.join("raw.bpe");
+        write_file(&raw_path, &raw).unwrap();
+        let mutated = verify_roundtrip(&registry, &raw_path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(good.unwrap(), None);
+        let mismatch = mutated.unwrap().unwrap();
+        assert_eq!(mismatch.offset, 8);
+        assert_eq!(mismatch.expected, Some(raw[8]));
+        assert_eq!(mismatch.field.as_deref(), Some("compressed length"));
+    }
+}